@@ -1,46 +1,12 @@
-use actix_web::{
-    get, post,
-    web::{self, Bytes, Data, Path, Query},
-    App, HttpResponse, HttpServer, Responder, Result,
-};
+use actix_web::{App, HttpServer};
 use async_trait::async_trait;
 use log::info;
 use wechat4rs::{
     errors::{WechatEncryptError, WechatError},
-    CallbackMessage, EchoStrReq, MessageInfo, ReplyMessage, SaasContext, VerifyInfo, Wechat,
-    WechatCallBackHandler, WechatConfig, WechatSaasResolver,
+    CallbackContext, CallbackMessage, HandlerOutcome, SaasContext, Wechat, WechatCallBackHandler,
+    WechatConfig, WechatSaasResolver,
 };
 
-/// 公众号对接echo验证,
-/// 可以配置多个公众号回调,通过saas_id区分回调的公众号(u64), 下同
-#[get("/wechat-callback/{saas_id}/")]
-async fn echo_str(
-    wechat: Data<Wechat>,
-    saas_id: Path<u64>,
-    verify_info: Query<VerifyInfo>,
-    req: Query<EchoStrReq>,
-) -> Result<String, WechatError> {
-    let context = SaasContext::new(saas_id.into_inner());
-    let result = wechat.handle_echo(&verify_info, &req, &context).await?;
-    Ok(result)
-}
-
-/// 微信回调消息接收入口
-#[post("/wechat-callback/{saas_id}/")]
-async fn wechat_callback(
-    wechat: Data<Wechat>,
-    saas_id: Path<u64>,
-    info: Query<VerifyInfo>,
-    body: Bytes,
-) -> Result<String, WechatError> {
-    let request_body = String::from_utf8(body.to_vec())?;
-    let context = SaasContext::new(saas_id.into_inner());
-    let result = wechat
-        .handle_callback(&info, &request_body, &context) // 调用消息处理入口
-        .await?;
-    Ok(result)
-}
-
 struct EchoText;
 
 /// 处理消息回调[可选]
@@ -51,26 +17,15 @@ impl WechatCallBackHandler for EchoText {
     async fn handler_callback(
         &self,
         _wechat: &Wechat,
-        prev_result: Option<ReplyMessage>,
+        _context: &CallbackContext,
         message: &CallbackMessage,
-    ) -> Result<Option<ReplyMessage>, WechatError> {
-        if let CallbackMessage::Text {
-            info,
-            content,
-            biz_msg_menu_id: _,
-        } = message
-        {
-            let info = info.clone();
-            return Ok(Some(ReplyMessage::Text {
-                info: MessageInfo {
-                    from_user_name: info.to_user_name.clone(),
-                    to_user_name: info.from_user_name.clone(),
-                    ..info
-                },
-                content: format!("hello: {}", content),
-            }));
+    ) -> Result<HandlerOutcome, WechatError> {
+        if let CallbackMessage::Text { content, .. } = message {
+            // reply()自动交换发送方与接收方
+            let reply = message.reply().text(&format!("hello: {}", content))?;
+            return Ok(HandlerOutcome::reply(reply));
         }
-        Ok(prev_result) //默认可以返回None
+        Ok(HandlerOutcome::pass()) //不回复, 继续执行后续处理器
     }
 }
 
@@ -97,11 +52,13 @@ impl WechatSaasResolver for SaasResolve {
         match context.id {
             1 => Ok(WechatConfig {
                 key: None,
+                old_keys: vec![],
                 app_id: "wxc01451f1526a8a14".into(),
                 app_secret: "d4624c36b6795d1d99dcf0547af5443d".into(),
             }),
             2 => Ok(WechatConfig {
                 key: aes_key,
+                old_keys: vec![],
                 app_id: "wx11853b05910e1b6b".into(),
                 app_secret: "wx11853b05910e1b6b".into(),
             }),
@@ -129,22 +86,27 @@ async fn init() -> anyhow::Result<()> {
     // let config_env: WechatConfig = envy::prefixed("WECHAT_").from_env()?;
     let manager = RedisConnectionManager::new(dotenv::var("REDIS_URL")?)?;
     let pool = RedisPool::new(bb8::Pool::builder().build(manager).await?);
-    let token_p = RedisTokenProvider::new(pool);
+    let token_p = RedisTokenProvider::new(pool.clone());
 
     // 2. 指定配置解析器
-    let mut wechat = wechat4rs::Wechat::new(Box::new(SaasResolve), Box::new(token_p));
-    // 3. [可选] 注册消息回调处理器, 用于处理微信回调的消息
-    wechat.registry_callback(Box::new(EchoText));
-
-    let wechat = web::Data::new(wechat);
+    let wechat = wechat4rs::Wechat::builder(Box::new(SaasResolve), Box::new(token_p))
+        // 3. [可选] 注册消息回调处理器, 用于处理微信回调的消息
+        .callback(Box::new(EchoText))
+        // 4. [可选] 应用数据, 处理器中通过wechat.extensions().get::<T>()获取
+        .extension(pool.clone())
+        // 被动回复超时及客服消息需要在后台执行, 通过build_shared共享
+        .build_shared();
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .wrap(Logger::new("%a %{User-Agent}i"))
-            .app_data(wechat.clone())
-            .service(echo_str) // 微信注册echo str
-            .service(wechat_callback) // 回调入口
+            // 微信接入验证(GET)及回调入口(POST): /wechat-callback/{saas_id}/,
+            // 可以配置多个公众号回调, 通过saas_id区分回调的公众号(u64)
+            .service(wechat4rs::actix::callback_scope(
+                "/wechat-callback",
+                wechat.clone(),
+            ))
     })
     .bind("0.0.0.0:3000")?
     .run()
@@ -154,7 +116,7 @@ async fn init() -> anyhow::Result<()> {
 }
 
 #[actix_rt::main]
-async fn main() -> Result<(), std::io::Error> {
+async fn main() -> std::io::Result<()> {
     if let Err(err) = init().await {
         eprintln!("ERROR: {:#}", err);
         err.chain()
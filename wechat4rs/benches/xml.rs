@@ -0,0 +1,132 @@
+//! 回调消息解析的性能对比: 单次扫描解析 vs DOM + xpath, 以及解析回调消息的各个阶段
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use sxd_document::parser;
+use sxd_xpath::evaluate_xpath;
+use wechat4rs::xml;
+use wechat4rs::CallbackMessage;
+
+const TEXT: &str = r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[fromUser]]></FromUserName>
+  <CreateTime>1348831860</CreateTime>
+  <MsgType><![CDATA[text]]></MsgType>
+  <Content><![CDATA[this is a test]]></Content>
+  <MsgId>1234567890123456</MsgId>
+</xml>"#;
+
+const PIC_SYSPHOTO: &str = r#"<xml>
+<ToUserName><![CDATA[gh_e136c6e50636]]></ToUserName>
+<FromUserName><![CDATA[oMgHVjngRipVsoxg6TuX3vz6glDg]]></FromUserName>
+<CreateTime>1408090651</CreateTime>
+<MsgType><![CDATA[event]]></MsgType>
+<Event><![CDATA[pic_sysphoto]]></Event>
+<EventKey><![CDATA[6]]></EventKey>
+<SendPicsInfo><Count>2</Count>
+<PicList>
+<item><PicMd5Sum><![CDATA[1b5f7c23b5bf75682a53e7b6d163e185]]></PicMd5Sum></item>
+<item><PicMd5Sum><![CDATA[1b5f7c23b5bf75682a53e7b6d163e186]]></PicMd5Sum></item>
+</PicList>
+</SendPicsInfo>
+</xml>"#;
+
+const ENVELOPE: &str = r#"<xml>
+<ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>
+<Encrypt><![CDATA[YsZjOA0RLvxvjZ8Xq38yC2YZgxw20MS/UCS13eiWaznQawh8JHGyonUKFLKC9cSgpxDpP9IHQ5+Vl9exTBSMgCzI19P1z0YpByB5rLfHMQWsyvm/H5uwH16lf2BgooZZRoEyzTDLXQFqjwiUSP7Iw8IzdtMp1Ux3f9glW5D/I5H3sGmxbmxf0N/2I5DKKWAlQZSfEnzouKcpyD9DJeY8FfKcQAlJFs/FKGs7g6UdXlxHwmgK3+ZOf7+FL8nFVOQzVpCLuOfRJnMQ//+Bp8aXoTbLiaW6haYuKf7CpPihQJ9/XFTgirBRB2V3jNFisVzwL9XeJ6r/H8Pt8GyGeQ6Hdpl4RVJY4gOTYvZpNvcz0WsKtJkh04tC7zj6tO8/cR4wsJxzTvDpMtBSpukNcFuR7BQtKKTlAkYulnoj8dAfHFc=]]></Encrypt>
+</xml>"#;
+
+/// 原有的解析方式: 构建DOM后每个字段执行一次xpath
+fn xpath(xml: &str, paths: &[&str]) -> Vec<String> {
+    let package = parser::parse(xml).unwrap();
+    let doc = package.as_document();
+    paths
+        .iter()
+        .map(|path| {
+            evaluate_xpath(&doc, &format!("/xml/{}", path))
+                .unwrap()
+                .string()
+        })
+        .collect()
+}
+
+fn bench_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("text");
+    group.bench_function("xpath", |b| {
+        b.iter(|| {
+            xpath(
+                black_box(TEXT),
+                &[
+                    "MsgType",
+                    "ToUserName",
+                    "FromUserName",
+                    "CreateTime",
+                    "MsgId",
+                    "Content",
+                    "bizmsgmenuid",
+                ],
+            )
+        })
+    });
+    group.bench_function("single_pass", |b| {
+        b.iter(|| wechat4rs::from_xml(black_box(TEXT)).unwrap())
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("pic_sysphoto");
+    group.bench_function("xpath", |b| {
+        b.iter(|| {
+            xpath(
+                black_box(PIC_SYSPHOTO),
+                &[
+                    "MsgType",
+                    "ToUserName",
+                    "FromUserName",
+                    "CreateTime",
+                    "MsgId",
+                    "Event",
+                    "EventKey",
+                    "SendPicsInfo/Count",
+                    "SendPicsInfo/PicList/*/PicMd5Sum",
+                ],
+            )
+        })
+    });
+    group.bench_function("single_pass", |b| {
+        b.iter(|| wechat4rs::from_xml(black_box(PIC_SYSPHOTO)).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_envelope(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    group.bench_function("xpath", |b| {
+        b.iter(|| xpath(black_box(ENVELOPE), &["Encrypt"]))
+    });
+    group.bench_function("single_pass", |b| {
+        b.iter(|| {
+            xml::parse(black_box(ENVELOPE))
+                .unwrap()
+                .child("Encrypt")
+                .map(xml::XmlNode::text)
+        })
+    });
+    group.finish();
+}
+
+/// 完整的回调消息解析: 解析XML(parse), 转换为消息(from_node)及两者合计(from_xml)
+fn bench_callback(c: &mut Criterion) {
+    for (name, msg) in &[("text", TEXT), ("pic_sysphoto", PIC_SYSPHOTO)] {
+        let mut group = c.benchmark_group(format!("callback_{}", name));
+        group.bench_function("parse", |b| b.iter(|| xml::parse(black_box(msg)).unwrap()));
+        let node = xml::parse(msg).unwrap();
+        group.bench_function("from_node", |b| {
+            b.iter(|| CallbackMessage::from_node(black_box(&node)).unwrap())
+        });
+        group.bench_function("from_xml", |b| {
+            b.iter(|| wechat4rs::from_xml(black_box(msg)).unwrap())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_message, bench_envelope, bench_callback);
+criterion_main!(benches);
@@ -0,0 +1,89 @@
+//! actix-web接入
+//!
+//! ```ignore
+//! let wechat = Wechat::builder(resolver, token_provider).build_shared();
+//! HttpServer::new(move || {
+//!     App::new().service(wechat4rs::actix::callback_scope("/wechat-callback", wechat.clone()))
+//! })
+//! ```
+use crate::service::{handle_request, text_response};
+use crate::{SaasContext, Wechat};
+use actix_web::web::{self, BytesMut, Data, Path};
+use actix_web::{HttpRequest, HttpResponse, Scope};
+use bytes::Bytes;
+use http::StatusCode;
+use log::warn;
+use std::sync::Arc;
+use tokio::stream::StreamExt;
+
+/// 接入验证(GET)及消息回调(POST)的路由: `{path}/{saas_id}/`
+///
+/// wechat需通过`into_shared`或`build_shared`创建, 被动回复超时及客服消息才能在后台执行;
+/// 消息体超过`Wechat::max_body_size`时返回413
+pub fn callback_scope(path: &str, wechat: Arc<Wechat>) -> Scope {
+    web::scope(path).data(wechat).service(
+        web::resource("/{saas_id}/")
+            .route(web::get().to(echo))
+            .route(web::post().to(callback)),
+    )
+}
+
+async fn echo(wechat: Data<Arc<Wechat>>, saas_id: Path<u64>, request: HttpRequest) -> HttpResponse {
+    dispatch(&wechat, saas_id.into_inner(), &request, Bytes::new()).await
+}
+
+async fn callback(
+    wechat: Data<Arc<Wechat>>,
+    saas_id: Path<u64>,
+    request: HttpRequest,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("读取回调消息失败: {:?}", e);
+                return into_response(text_response(StatusCode::BAD_REQUEST, "Bad Request"));
+            }
+        };
+        if body.len() + chunk.len() > wechat.max_body_size {
+            let status = StatusCode::PAYLOAD_TOO_LARGE;
+            return into_response(text_response(status, "Payload Too Large"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    dispatch(&wechat, saas_id.into_inner(), &request, body.freeze()).await
+}
+
+async fn dispatch(
+    wechat: &Wechat,
+    saas_id: u64,
+    request: &HttpRequest,
+    body: Bytes,
+) -> HttpResponse {
+    let mut builder = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone());
+    for (name, value) in request.headers() {
+        builder = builder.header(name.clone(), value.clone());
+    }
+    let request = match builder.body(body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("转换回调请求失败: {:?}", e);
+            return into_response(text_response(StatusCode::BAD_REQUEST, "Bad Request"));
+        }
+    };
+    let context = SaasContext::new(saas_id);
+    into_response(handle_request(wechat, request, &context).await)
+}
+
+fn into_response(response: http::Response<Bytes>) -> HttpResponse {
+    let (parts, body) = response.into_parts();
+    let mut builder = HttpResponse::build(parts.status);
+    for (name, value) in parts.headers.iter() {
+        builder.header(name.clone(), value.clone());
+    }
+    builder.body(body)
+}
@@ -0,0 +1,249 @@
+//! 同步(阻塞)版本的微信SDK
+//!
+//! 适用于无法运行异步运行时的批处理工具等场景, 内部持有独立的tokio运行时
+//!
+//! # Panics
+//!
+//! 不能在tokio运行时中(如`#[tokio::main]`、actix处理器内)调用阻塞方法,
+//! tokio不允许在运行时中阻塞等待另一个运行时, 会直接panic; 异步代码中请使用`wechat()`
+use crate::customservice::{Customservice, KfAccount, KfMessage};
+use crate::menu::{ApiMenu, ConditionalMenu, ConditionalMenuList, SelfMenuInfo, WechatMenu};
+use crate::{SaasContext, Wechat, WechatResult, WechatToken};
+use std::future::Future;
+use tokio::runtime::{Builder, Runtime};
+
+/// 同步版微信公众平台SDK
+pub struct BlockingWechat {
+    wechat: Wechat,
+    runtime: Runtime,
+}
+
+impl BlockingWechat {
+    pub fn new(wechat: Wechat) -> std::io::Result<Self> {
+        let runtime = Builder::new().threaded_scheduler().enable_all().build()?;
+        Ok(BlockingWechat { wechat, runtime })
+    }
+
+    /// 异步版本的SDK
+    pub fn wechat(&self) -> &Wechat {
+        &self.wechat
+    }
+
+    /// 在内部运行时中执行异步任务, 阻塞直到完成
+    ///
+    /// # Panics
+    ///
+    /// 在tokio运行时中调用时panic, 所有同步方法均通过此方法执行
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.handle().block_on(future)
+    }
+
+    /// 获取token
+    pub fn get_access_token(&self, context: &SaasContext) -> WechatResult<WechatToken> {
+        self.block_on(self.wechat.get_access_token(context))
+    }
+}
+
+/// 客服消息
+impl BlockingWechat {
+    /// 添加客服帐号
+    pub fn add_account(
+        &self,
+        context: &SaasContext,
+        account: &String,
+        nickname: &String,
+        password: Option<String>,
+    ) -> WechatResult<()> {
+        self.block_on(
+            self.wechat
+                .add_account(context, account, nickname, password),
+        )
+    }
+
+    /// 修改客服帐号
+    pub fn update_account(
+        &self,
+        context: &SaasContext,
+        account: &String,
+        nickname: &String,
+        password: Option<String>,
+    ) -> WechatResult<()> {
+        self.block_on(
+            self.wechat
+                .update_account(context, account, nickname, password),
+        )
+    }
+
+    /// 删除客服帐号
+    pub fn del_account(
+        &self,
+        context: &SaasContext,
+        account: &String,
+        nickname: &String,
+        password: Option<String>,
+    ) -> WechatResult<()> {
+        self.block_on(
+            self.wechat
+                .del_account(context, account, nickname, password),
+        )
+    }
+
+    /// 获取所有客服账号
+    pub fn get_list(&self, context: &SaasContext) -> WechatResult<Vec<KfAccount>> {
+        self.block_on(self.wechat.get_list(context))
+    }
+
+    /// 客服接口-发消息
+    pub fn send_msg(
+        &self,
+        context: &SaasContext,
+        touser: String,
+        msg: KfMessage,
+        kf_account: Option<String>,
+    ) -> WechatResult<()> {
+        self.block_on(self.wechat.send_msg(context, touser, msg, kf_account))
+    }
+
+    /// 客服输入状态
+    pub fn typing(&self, context: &SaasContext, touser: String, typing: bool) -> WechatResult<()> {
+        self.block_on(self.wechat.typing(context, touser, typing))
+    }
+}
+
+/// 自定义菜单
+impl BlockingWechat {
+    /// 创建菜单
+    pub fn create_menu(&self, context: &SaasContext, menu: &ApiMenu) -> WechatResult<()> {
+        self.block_on(self.wechat.create_menu(context, menu))
+    }
+
+    /// 查询当前菜单
+    pub fn get_menu(&self, context: &SaasContext) -> WechatResult<SelfMenuInfo> {
+        self.block_on(self.wechat.get_menu(context))
+    }
+
+    /// 删除当前使用的自定义菜单
+    pub fn delete_all_menu(&self, context: &SaasContext) -> WechatResult<()> {
+        self.block_on(self.wechat.delete_all_menu(context))
+    }
+
+    /// 创建个性化菜单, 返回菜单id
+    pub fn create_conditional_menu(
+        &self,
+        context: &SaasContext,
+        menu: &ConditionalMenu,
+    ) -> WechatResult<i64> {
+        self.block_on(self.wechat.create_conditional_menu(context, menu))
+    }
+
+    /// 删除个性化菜单
+    pub fn delete_conditional_menu(&self, context: &SaasContext, menu_id: i64) -> WechatResult<()> {
+        self.block_on(self.wechat.delete_conditional_menu(context, menu_id))
+    }
+
+    /// 测试个性化菜单匹配结果
+    pub fn get_conditional_menu_of_user(
+        &self,
+        context: &SaasContext,
+        openid: &String,
+    ) -> WechatResult<ApiMenu> {
+        self.block_on(self.wechat.get_conditional_menu_of_user(context, openid))
+    }
+
+    /// 查询全部自定义菜单
+    pub fn get_conditional_menu_list(
+        &self,
+        context: &SaasContext,
+    ) -> WechatResult<ConditionalMenuList> {
+        self.block_on(self.wechat.get_conditional_menu_list(context))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::token_provider::memory::MemoryTokenProvider;
+    use crate::{ConstSaasResolver, WechatConfig};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    #[test]
+    fn test_get_access_token() -> WechatResult<()> {
+        let wechat = Wechat::new(
+            Box::new(ConstSaasResolver::new(WechatConfig::default())),
+            Box::new(MemoryTokenProvider::new()),
+        );
+        let wechat = BlockingWechat::new(wechat).unwrap();
+        let context = SaasContext::new(1);
+        let token = WechatToken::new_relative("ACCESS_TOKEN".into(), 7200);
+        let inner = wechat.wechat();
+        wechat.block_on(inner.token_provider.set_token(inner, &context, Some(token)))?;
+        assert_eq!("ACCESS_TOKEN", wechat.get_access_token(&context)?.token);
+        Ok(())
+    }
+
+    /// 按顺序返回响应的HTTP服务, 返回收到的请求行
+    fn mock_server(responses: Vec<&'static str>) -> (reqwest::Url, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for body in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                sender.send(request_line.trim_end().to_string()).unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (reqwest::Url::parse(&url).unwrap(), receiver)
+    }
+
+    #[test]
+    fn test_api_call() -> WechatResult<()> {
+        let (api_base, requests) = mock_server(vec![
+            r#"{"access_token":"MOCK_TOKEN","expires_in":7200}"#,
+            r#"[{"kf_account":"test1@test","kf_nick":"ntest1","kf_id":"1001","kf_headimgurl":""}]"#,
+        ]);
+        let wechat = Wechat::builder(
+            Box::new(ConstSaasResolver::new(WechatConfig::default())),
+            Box::new(MemoryTokenProvider::new()),
+        )
+        .api_base(api_base)
+        .build();
+        let wechat = BlockingWechat::new(wechat).unwrap();
+        let list = wechat.get_list(&SaasContext::new(1))?;
+        assert_eq!(1, list.len());
+        assert_eq!("test1@test", list[0].account);
+
+        assert!(requests.recv().unwrap().starts_with("GET /cgi-bin/token?"));
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /cgi-bin/customservice/getkflist?"));
+        assert!(request.contains("access_token=MOCK_TOKEN"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic]
+    async fn test_panic_in_runtime() {
+        let wechat = Wechat::new(
+            Box::new(ConstSaasResolver::new(WechatConfig::default())),
+            Box::new(MemoryTokenProvider::new()),
+        );
+        let wechat = BlockingWechat::new(wechat).unwrap();
+        let _ = wechat.get_access_token(&SaasContext::new(1));
+    }
+}
@@ -21,7 +21,11 @@ impl WechatToken {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WechatConfig {
+    /// 当前使用的EncodingAESKey, 回复消息始终使用该key加密
     pub key: Option<Vec<u8>>,
+    /// 更换EncodingAESKey后仍可用于解密的旧key, 按顺序尝试
+    #[serde(default)]
+    pub old_keys: Vec<Vec<u8>>,
     pub app_id: String,
     pub app_secret: String,
 }
@@ -33,16 +37,29 @@ impl WechatConfig {
     pub fn new(key: Option<Vec<u8>>, app_id: String, app_secret: String) -> Self {
         WechatConfig {
             key,
+            old_keys: vec![],
             app_id,
             app_secret,
         }
     }
+
+    /// 设置更换EncodingAESKey过渡期内仍有效的旧key
+    pub fn with_old_keys(mut self, old_keys: Vec<Vec<u8>>) -> Self {
+        self.old_keys = old_keys;
+        self
+    }
+
+    /// 解密时依次尝试的key, 当前key在前
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.key.iter().chain(self.old_keys.iter())
+    }
 }
 
 impl Default for WechatConfig {
     fn default() -> Self {
         Self {
             key: None,
+            old_keys: vec![],
             app_id: "".into(),
             app_secret: "".into(),
         }
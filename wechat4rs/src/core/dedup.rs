@@ -0,0 +1,292 @@
+//! 回调消息去重
+//!
+//! 微信5秒内未收到回复时会重试, 最多3次. 同一条消息只执行一次处理器,
+//! 重试请求返回首次处理的回复, 首次处理未完成时与无回复相同, 返回"success"或空字符串.
+use crate::message::CallbackMessage;
+use crate::{SaasContext, WechatEncryptError, WechatError};
+use async_trait::async_trait;
+use std::marker::{Send, Sync};
+use std::time::Duration;
+
+/// 去重记录的状态
+#[derive(Debug, Clone, PartialEq)]
+pub enum DedupState {
+    /// 首次收到, 已标记为处理中
+    New,
+    /// 处理中
+    Processing,
+    /// 已处理, 回复消息的明文XML, 无回复时为空
+    Done(String),
+}
+
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    /// 首次收到时标记为处理中并返回New, 否则返回已有的状态
+    async fn begin(
+        &self,
+        context: &SaasContext,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<DedupState, WechatError>;
+
+    /// 记录处理结果
+    async fn finish(
+        &self,
+        context: &SaasContext,
+        key: &str,
+        reply: &str,
+        ttl: Duration,
+    ) -> Result<(), WechatError>;
+
+    /// 处理失败时删除记录, 微信重试时重新处理
+    async fn abort(&self, context: &SaasContext, key: &str) -> Result<(), WechatError>;
+}
+
+/// 回调去重配置
+pub struct CallbackDedup {
+    pub store: Box<dyn DedupStore>,
+    /// 记录保存时间, 需覆盖微信的重试周期
+    pub ttl: Duration,
+}
+
+impl CallbackDedup {
+    /// 默认保存60秒
+    pub fn new(store: Box<dyn DedupStore>) -> Self {
+        CallbackDedup {
+            store,
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+/// 去重key: 普通消息使用MsgId, 事件使用FromUserName + CreateTime + Event
+pub fn dedup_key(message: &CallbackMessage) -> String {
+    let info = message.info();
+    match info.msg_id {
+        Some(msg_id) => format!("msg:{}", msg_id),
+        None => format!(
+            "event:{}:{}:{}",
+            info.from_user_name,
+            info.create_time,
+            message.event().unwrap_or_default()
+        ),
+    }
+}
+
+pub mod memory {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Mutex, MutexGuard};
+    use std::time::Instant;
+
+    /// (公众号, key) -> (过期时间, 回复), 回复为None表示处理中
+    type Entries = HashMap<(u64, String), (Instant, Option<String>)>;
+
+    /// 基于内存的去重记录, 仅适用于单机部署
+    #[derive(Default)]
+    pub struct MemoryDedupStore {
+        entries: Mutex<Entries>,
+    }
+
+    impl MemoryDedupStore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        fn lock(&self) -> Result<MutexGuard<'_, Entries>, WechatError> {
+            Ok(self.entries.lock()?)
+        }
+
+        pub fn len(&self) -> usize {
+            self.lock().map(|entries| entries.len()).unwrap_or(0)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// 清理过期的记录
+        pub fn remove_expired(&self) {
+            if let Ok(mut entries) = self.lock() {
+                let now = Instant::now();
+                entries.retain(|_, (expire_at, _)| *expire_at > now);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl DedupStore for MemoryDedupStore {
+        async fn begin(
+            &self,
+            context: &SaasContext,
+            key: &str,
+            ttl: Duration,
+        ) -> Result<DedupState, WechatError> {
+            let mut entries = self.lock()?;
+            let now = Instant::now();
+            let key = (context.id, key.to_string());
+            match entries.get(&key) {
+                Some((expire_at, Some(reply))) if *expire_at > now => {
+                    Ok(DedupState::Done(reply.clone()))
+                }
+                Some((expire_at, None)) if *expire_at > now => Ok(DedupState::Processing),
+                _ => {
+                    entries.insert(key, (now + ttl, None));
+                    Ok(DedupState::New)
+                }
+            }
+        }
+
+        async fn finish(
+            &self,
+            context: &SaasContext,
+            key: &str,
+            reply: &str,
+            ttl: Duration,
+        ) -> Result<(), WechatError> {
+            self.lock()?.insert(
+                (context.id, key.to_string()),
+                (Instant::now() + ttl, Some(reply.to_string())),
+            );
+            Ok(())
+        }
+
+        async fn abort(&self, context: &SaasContext, key: &str) -> Result<(), WechatError> {
+            self.lock()?.remove(&(context.id, key.to_string()));
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_dedup() -> Result<(), WechatError> {
+            let store = MemoryDedupStore::new();
+            let context = SaasContext::new(1);
+            let ttl = Duration::from_secs(60);
+            assert_eq!(DedupState::New, store.begin(&context, "1", ttl).await?);
+            assert_eq!(
+                DedupState::Processing,
+                store.begin(&context, "1", ttl).await?
+            );
+            // 不同公众号互不影响
+            let other = SaasContext::new(2);
+            assert_eq!(DedupState::New, store.begin(&other, "1", ttl).await?);
+
+            store.finish(&context, "1", "<xml></xml>", ttl).await?;
+            assert_eq!(
+                DedupState::Done("<xml></xml>".into()),
+                store.begin(&context, "1", ttl).await?
+            );
+            // 失败后可重新处理
+            store.abort(&other, "1").await?;
+            assert_eq!(DedupState::New, store.begin(&other, "1", ttl).await?);
+            // 过期后重新处理
+            store
+                .finish(&context, "2", "", Duration::from_millis(0))
+                .await?;
+            assert_eq!(DedupState::New, store.begin(&context, "2", ttl).await?);
+            Ok(())
+        }
+    }
+}
+
+pub mod redis {
+    use super::*;
+    use bb8_redis::{redis::cmd, RedisPool};
+
+    /// 处理中的标记, 已处理的记录以DONE_PREFIX开头
+    const PROCESSING: &str = "processing";
+    const DONE_PREFIX: &str = "done:";
+
+    fn get_dedup_key(context: &SaasContext, key: &str) -> String {
+        format!("wechat::{}::dedup::{}", context.id, key)
+    }
+
+    /// 基于redis的去重记录, 集群部署时共享
+    pub struct RedisDedupStore {
+        redis_pool: RedisPool,
+    }
+
+    impl RedisDedupStore {
+        pub fn new(pool: RedisPool) -> Self {
+            RedisDedupStore { redis_pool: pool }
+        }
+    }
+
+    #[async_trait]
+    impl DedupStore for RedisDedupStore {
+        async fn begin(
+            &self,
+            context: &SaasContext,
+            key: &str,
+            ttl: Duration,
+        ) -> Result<DedupState, WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            let key = get_dedup_key(context, key);
+            // SET NX: 已存在时返回nil
+            let result: Option<String> = cmd("SET")
+                .arg(&key)
+                .arg(PROCESSING)
+                .arg("EX")
+                .arg(ttl.as_secs().max(1))
+                .arg("NX")
+                .query_async(conn)
+                .await?;
+            if result.is_some() {
+                return Ok(DedupState::New);
+            }
+            let value: Option<String> = cmd("GET").arg(&key).query_async(conn).await?;
+            Ok(match value {
+                Some(value) if value.starts_with(DONE_PREFIX) => {
+                    DedupState::Done(value[DONE_PREFIX.len()..].to_string())
+                }
+                // 在SET与GET之间过期时按处理中处理, 微信会再次重试
+                _ => DedupState::Processing,
+            })
+        }
+
+        async fn finish(
+            &self,
+            context: &SaasContext,
+            key: &str,
+            reply: &str,
+            ttl: Duration,
+        ) -> Result<(), WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            cmd("SET")
+                .arg(get_dedup_key(context, key))
+                .arg(format!("{}{}", DONE_PREFIX, reply))
+                .arg("EX")
+                .arg(ttl.as_secs().max(1))
+                .query_async::<_, ()>(conn)
+                .await?;
+            Ok(())
+        }
+
+        async fn abort(&self, context: &SaasContext, key: &str) -> Result<(), WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            cmd("DEL")
+                .arg(get_dedup_key(context, key))
+                .query_async::<_, ()>(conn)
+                .await?;
+            Ok(())
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use actix_web::error::ResponseError;
+#[cfg(feature = "actix")]
+use actix_web::{error::ResponseError, HttpResponse};
 use http::StatusCode;
 use std::fmt::Display;
 use thiserror::Error;
@@ -19,11 +20,76 @@ pub enum WechatEncryptError {
     InvalidAppId,
     #[error("配置信息无效")]
     InvalidConfig,
+    #[error("消息无效, {0}")]
+    InvalidMessage(String),
+    /// 构建的被动回复不符合微信的限制, 属于服务端错误
+    #[error("回复消息无效, {0}")]
+    InvalidReply(String),
     #[error("API请求错误:{msg:?}")]
     ApiRequestError {
         msg: String,
         source: Box<dyn std::error::Error + Send + Sync>,
     },
+    /// 无法获取access_token
+    #[error("token不可用, {0}")]
+    TokenError(String),
+    /// 序列化、文件读写失败等服务端内部错误
+    #[error("内部错误, {0}")]
+    InternalError(String),
+    /// redis等存储不可用
+    #[error("存储错误:{msg:?}")]
+    StorageError {
+        msg: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl WechatEncryptError {
+    /// 存储错误, 如redis不可用、锁中毒、文件写入失败
+    pub fn storage<T: Into<String>>(msg: T) -> Self {
+        WechatEncryptError::StorageError {
+            msg: msg.into(),
+            source: Box::new(NoError),
+        }
+    }
+
+    /// 内部错误, 不是请求本身的问题
+    pub fn internal<T: Into<String>>(msg: T) -> Self {
+        WechatEncryptError::InternalError(msg.into())
+    }
+
+    /// 回调接口返回的HTTP状态码
+    ///
+    /// 签名或AppID无效为403, 消息无效为400, 微信API错误为502, token不可用为503,
+    /// 回复无效、配置、内部及存储错误为500
+    pub fn http_status(&self) -> StatusCode {
+        match self {
+            WechatEncryptError::InvalidSignature(_) | WechatEncryptError::InvalidAppId => {
+                StatusCode::FORBIDDEN
+            }
+            WechatEncryptError::InvalidMessage(_) => StatusCode::BAD_REQUEST,
+            WechatEncryptError::ApiRequestError { .. } => StatusCode::BAD_GATEWAY,
+            WechatEncryptError::TokenError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            WechatEncryptError::InvalidReply(_)
+            | WechatEncryptError::InvalidConfig
+            | WechatEncryptError::InternalError(_)
+            | WechatEncryptError::StorageError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// 用户会话错误
+#[derive(Error, Debug)]
+pub enum SessionError {
+    /// 未启用用户会话
+    #[error("用户会话未启用")]
+    Disabled,
+    /// 并发修改超过最大重试次数
+    #[error("会话并发修改冲突: {0}")]
+    Conflict(String),
+    /// 保存的会话无法序列化或反序列化
+    #[error("会话数据无效, {0}")]
+    InvalidData(String),
 }
 
 #[allow(dead_code)]
@@ -33,44 +99,41 @@ pub enum WechatError {
     ParseError(String),
     #[error("error on parse")]
     EncryptError { source: WechatEncryptError },
+    #[error("error on session")]
+    SessionError { source: SessionError },
 }
 
-impl From<bb8::RunError<redis::RedisError>> for WechatError {
-    fn from(e: bb8::RunError<redis::RedisError>) -> Self {
-        WechatError::EncryptError {
-            source: WechatEncryptError::ApiRequestError {
-                msg: format!("{:?}", e),
-                source: Box::new(NoError),
-            },
-        }
+impl serde::de::Error for WechatError {
+    fn custom<T: Display>(msg: T) -> Self {
+        WechatError::ParseError(msg.to_string())
     }
 }
 
-impl From<redis::RedisError> for WechatError {
-    fn from(e: redis::RedisError) -> Self {
-        WechatError::EncryptError {
-            source: WechatEncryptError::ApiRequestError {
-                msg: format!("{:?}", e),
-                source: Box::new(NoError),
-            },
-        }
+impl serde::ser::Error for WechatError {
+    fn custom<T: Display>(msg: T) -> Self {
+        WechatEncryptError::internal(msg.to_string()).into()
     }
 }
 
-impl From<serde_json::error::Error> for WechatError {
-    fn from(e: serde_json::error::Error) -> Self {
-        WechatError::EncryptError {
-            source: WechatEncryptError::ApiRequestError {
-                msg: format!("{:?}", e),
-                source: Box::new(NoError),
-            },
-        }
+impl From<bb8::RunError<redis::RedisError>> for WechatError {
+    fn from(e: bb8::RunError<redis::RedisError>) -> Self {
+        WechatEncryptError::storage(format!("{:?}", e)).into()
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for WechatError {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        WechatEncryptError::storage(e.to_string()).into()
     }
 }
 
-impl From<sxd_xpath::Error> for WechatError {
-    fn from(_: sxd_xpath::Error) -> Self {
-        todo!()
+impl From<redis::RedisError> for WechatError {
+    fn from(e: redis::RedisError) -> Self {
+        WechatEncryptError::StorageError {
+            msg: format!("{:?}", e),
+            source: Box::new(e),
+        }
+        .into()
     }
 }
 
@@ -81,14 +144,14 @@ impl From<reqwest::Error> for WechatError {
 }
 
 impl From<std::io::Error> for WechatError {
-    fn from(_: std::io::Error) -> Self {
-        todo!()
+    fn from(e: std::io::Error) -> Self {
+        WechatEncryptError::internal(e.to_string()).into()
     }
 }
 
 impl From<std::string::FromUtf8Error> for WechatError {
-    fn from(_: std::string::FromUtf8Error) -> Self {
-        todo!()
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        WechatError::ParseError(e.to_string())
     }
 }
 
@@ -98,39 +161,61 @@ impl From<WechatEncryptError> for WechatError {
     }
 }
 
-impl ResponseError for WechatError {
-    fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+impl From<SessionError> for WechatError {
+    fn from(e: SessionError) -> Self {
+        WechatError::SessionError { source: e }
     }
 }
 
-impl From<openssl::error::ErrorStack> for WechatEncryptError {
-    fn from(e: openssl::error::ErrorStack) -> Self {
-        WechatEncryptError::InvalidSignature(format!("{:?}", e))
+impl WechatError {
+    /// 回调接口返回的HTTP状态码, 请求无法解析为400, 会话错误为500,
+    /// 其它见`WechatEncryptError::http_status`
+    ///
+    /// `ParseError`只用于请求参数或消息体格式错误, 服务端的错误使用`WechatEncryptError::InternalError`
+    pub fn http_status(&self) -> StatusCode {
+        match self {
+            WechatError::ParseError(_) => StatusCode::BAD_REQUEST,
+            WechatError::EncryptError { source } => source.http_status(),
+            WechatError::SessionError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
-impl From<base64::DecodeError> for WechatEncryptError {
-    fn from(e: base64::DecodeError) -> Self {
-        WechatEncryptError::InvalidSignature(e.to_string())
+/// 响应内容只包含状态码的说明, 不包含错误详情
+#[cfg(feature = "actix")]
+fn safe_response(status: StatusCode) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("text/plain; charset=utf-8")
+        .body(status.canonical_reason().unwrap_or_default())
+}
+
+#[cfg(feature = "actix")]
+impl ResponseError for WechatError {
+    fn status_code(&self) -> StatusCode {
+        self.http_status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        safe_response(self.status_code())
     }
 }
 
-impl From<std::io::Error> for WechatEncryptError {
-    fn from(_: std::io::Error) -> Self {
-        todo!()
+/// 加密失败属于内部错误, 解密失败由调用方转换为`InvalidMessage`
+impl From<openssl::error::ErrorStack> for WechatEncryptError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        WechatEncryptError::internal(format!("{:?}", e))
     }
 }
 
-impl From<std::string::FromUtf8Error> for WechatEncryptError {
-    fn from(_: std::string::FromUtf8Error) -> Self {
-        todo!()
+impl From<std::io::Error> for WechatEncryptError {
+    fn from(e: std::io::Error) -> Self {
+        WechatEncryptError::InvalidMessage(e.to_string())
     }
 }
 
-impl From<sxd_xpath::Error> for WechatEncryptError {
-    fn from(_: sxd_xpath::Error) -> Self {
-        todo!()
+impl From<std::string::FromUtf8Error> for WechatEncryptError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        WechatEncryptError::InvalidMessage(e.to_string())
     }
 }
 
@@ -152,15 +237,114 @@ impl From<WechatEncryptError> for std::io::Error {
                 std::io::Error::new(ErrorKind::InvalidData, e)
             }
             WechatEncryptError::InvalidConfig => std::io::Error::new(ErrorKind::InvalidData, e),
+            WechatEncryptError::InvalidMessage(_) => std::io::Error::new(ErrorKind::InvalidData, e),
+            WechatEncryptError::InvalidReply(_) => std::io::Error::new(ErrorKind::InvalidData, e),
             WechatEncryptError::ApiRequestError { msg: _, source: _ } => {
                 std::io::Error::new(ErrorKind::InvalidData, e)
             }
+            WechatEncryptError::TokenError(_) => std::io::Error::new(ErrorKind::InvalidData, e),
+            WechatEncryptError::InternalError(_) => std::io::Error::other(e),
+            WechatEncryptError::StorageError { msg: _, source: _ } => {
+                std::io::Error::new(ErrorKind::InvalidData, e)
+            }
         }
     }
 }
 
+#[cfg(feature = "actix")]
 impl ResponseError for WechatEncryptError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+        self.http_status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        safe_response(self.status_code())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_http_status() {
+        let status = |e: WechatEncryptError| WechatError::from(e).http_status();
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(WechatEncryptError::InvalidSignature("".into()))
+        );
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            WechatError::ParseError("".into()).http_status()
+        );
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            status(WechatEncryptError::InvalidMessage("".into()))
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            status(WechatEncryptError::InvalidReply("".into()))
+        );
+        let api = WechatEncryptError::ApiRequestError {
+            msg: "code: 40001".into(),
+            source: Box::new(NoError),
+        };
+        assert_eq!(StatusCode::BAD_GATEWAY, status(api));
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            status(WechatEncryptError::TokenError("".into()))
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            status(WechatEncryptError::storage("redis"))
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            WechatError::from(SessionError::Conflict("openid".into())).http_status()
+        );
+        let redis = redis::RedisError::from((redis::ErrorKind::IoError, "refused"));
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            WechatError::from(redis).http_status()
+        );
+        let lock = std::sync::Mutex::new(());
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = lock.lock().unwrap();
+            panic!("poison");
+        });
+        let poisoned = lock.lock().map(|_| ()).map_err(WechatError::from);
+        match poisoned {
+            Err(WechatError::EncryptError {
+                source: e @ WechatEncryptError::StorageError { .. },
+            }) => assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, e.http_status()),
+            _ => panic!("should be storage error"),
+        }
+    }
+
+    #[test]
+    fn test_internal_error() {
+        let io = std::io::Error::other("disk full");
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            WechatError::from(io).http_status()
+        );
+        let ssl = WechatEncryptError::from(openssl::error::ErrorStack::get());
+        assert!(matches!(ssl, WechatEncryptError::InternalError(_)));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, ssl.http_status());
+        let ser = <WechatError as serde::ser::Error>::custom("unsupported");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, ser.http_status());
+    }
+
+    #[cfg(feature = "actix")]
+    #[test]
+    fn test_error_response() {
+        use actix_web::dev::Body;
+        let error = WechatEncryptError::storage("redis://:secret@127.0.0.1");
+        let response = error.error_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        match response.body().as_ref() {
+            Some(Body::Bytes(body)) => assert_eq!("Internal Server Error", body),
+            _ => panic!("unexpected body"),
+        }
     }
 }
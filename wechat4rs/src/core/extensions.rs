@@ -0,0 +1,98 @@
+//! 按类型存取的扩展数据
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type AnyMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// 按类型保存的扩展数据, 每种类型保存一个值
+///
+/// 用于`Wechat`的应用数据, 以及单次回调请求内在handler之间传递数据;
+/// 读多写少, 并发读取时不互相阻塞
+#[derive(Default)]
+pub struct Extensions {
+    map: RwLock<AnyMap>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // handler panic后仍可继续使用
+    fn read(&self) -> RwLockReadGuard<'_, AnyMap> {
+        self.map.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, AnyMap> {
+        self.map.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 保存value, 返回同类型的旧值
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<T> {
+        self.write()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    /// 获取T类型的值的拷贝, 较大的值可以用Arc包装
+    pub fn get<T: Any + Send + Sync + Clone>(&self) -> Option<T> {
+        self.read()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&self) -> Option<T> {
+        self.write()
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.read().contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct UserId(u64);
+
+    #[test]
+    fn test_extensions() {
+        let extensions = Extensions::new();
+        assert!(extensions.is_empty());
+        assert_eq!(None, extensions.insert(UserId(1)));
+        assert_eq!(None, extensions.insert("text".to_string()));
+        assert_eq!(Some(UserId(1)), extensions.insert(UserId(2)));
+        assert_eq!(Some(UserId(2)), extensions.get::<UserId>());
+        assert_eq!(Some("text".to_string()), extensions.get::<String>());
+        assert!(!extensions.contains::<u64>());
+        assert_eq!(Some(UserId(2)), extensions.remove::<UserId>());
+        assert_eq!(None, extensions.get::<UserId>());
+        assert_eq!(1, extensions.len());
+        // 读取时不互斥
+        let _guard = extensions.read();
+        assert_eq!(Some("text".to_string()), extensions.get::<String>());
+    }
+}
@@ -1,7 +1,13 @@
 mod config;
+pub mod dedup;
 pub mod errors;
+pub mod extensions;
+pub mod nonce_cache;
+pub mod recorder;
+pub mod session;
 pub mod token_provider;
 pub mod utils;
 
 pub use config::*;
 pub use errors::*;
+pub use extensions::Extensions;
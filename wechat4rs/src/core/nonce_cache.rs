@@ -0,0 +1,211 @@
+//! 回调消息nonce防重放缓存
+use crate::{SaasContext, WechatEncryptError, WechatError};
+use async_trait::async_trait;
+use std::marker::{Send, Sync};
+use std::time::Duration;
+
+/// nonce的最长保留时间, 时间窗口过大时按此保留
+const MAX_TTL: Duration = Duration::from_secs(10 * 365 * 24 * 3600);
+
+#[async_trait]
+pub trait NonceCache: Send + Sync {
+    /// 记录nonce, ttl内已记录过时返回false
+    async fn insert(
+        &self,
+        context: &SaasContext,
+        nonce: &str,
+        ttl: Duration,
+    ) -> Result<bool, WechatError>;
+}
+
+pub mod memory {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    pub struct MemoryNonceCache {
+        /// (公众号, nonce) -> 过期时间
+        entries: Mutex<HashMap<(u64, String), Instant>>,
+        /// 最多缓存的nonce数量, None为不限制
+        capacity: Option<usize>,
+    }
+
+    impl MemoryNonceCache {
+        pub fn new() -> Self {
+            MemoryNonceCache {
+                entries: Mutex::new(HashMap::new()),
+                capacity: None,
+            }
+        }
+
+        /// 最多缓存capacity个nonce, 超出时淘汰最早过期的
+        pub fn with_capacity(capacity: usize) -> Self {
+            MemoryNonceCache {
+                entries: Mutex::new(HashMap::new()),
+                capacity: Some(capacity),
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            self.entries
+                .lock()
+                .map(|entries| entries.len())
+                .unwrap_or(0)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// 清理过期的nonce
+        pub fn remove_expired(&self) {
+            if let Ok(mut entries) = self.entries.lock() {
+                let now = Instant::now();
+                entries.retain(|_, expire_at| *expire_at > now);
+            }
+        }
+    }
+
+    impl Default for MemoryNonceCache {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[async_trait]
+    impl NonceCache for MemoryNonceCache {
+        async fn insert(
+            &self,
+            context: &SaasContext,
+            nonce: &str,
+            ttl: Duration,
+        ) -> Result<bool, WechatError> {
+            let mut entries = self.entries.lock()?;
+            let now = Instant::now();
+            let key = (context.id, nonce.to_string());
+            if let Some(expire_at) = entries.get(&key) {
+                if *expire_at > now {
+                    return Ok(false);
+                }
+            }
+            if let Some(capacity) = self.capacity {
+                if entries.len() >= capacity {
+                    entries.retain(|_, expire_at| *expire_at > now);
+                }
+                while !entries.is_empty() && entries.len() >= capacity {
+                    let oldest = entries
+                        .iter()
+                        .min_by_key(|(_, expire_at)| **expire_at)
+                        .map(|(key, _)| key.clone());
+                    match oldest {
+                        Some(oldest) => entries.remove(&oldest),
+                        None => break,
+                    };
+                }
+            }
+            entries.insert(key, now + ttl.min(MAX_TTL));
+            Ok(true)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_replay() -> Result<(), WechatError> {
+            let cache = MemoryNonceCache::new();
+            let ttl = Duration::from_secs(60);
+            assert!(cache.insert(&SaasContext::new(1), "nonce", ttl).await?);
+            assert!(!cache.insert(&SaasContext::new(1), "nonce", ttl).await?);
+            // 不同公众号互不影响
+            assert!(cache.insert(&SaasContext::new(2), "nonce", ttl).await?);
+            assert_eq!(2, cache.len());
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_expired() -> Result<(), WechatError> {
+            let cache = MemoryNonceCache::new();
+            let context = SaasContext::new(1);
+            assert!(
+                cache
+                    .insert(&context, "nonce", Duration::from_millis(0))
+                    .await?
+            );
+            assert!(
+                cache
+                    .insert(&context, "nonce", Duration::from_secs(60))
+                    .await?
+            );
+            assert!(
+                cache
+                    .insert(&context, "other", Duration::from_millis(0))
+                    .await?
+            );
+            cache.remove_expired();
+            assert_eq!(1, cache.len());
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_capacity() -> Result<(), WechatError> {
+            let cache = MemoryNonceCache::with_capacity(2);
+            let context = SaasContext::new(1);
+            cache.insert(&context, "1", Duration::from_secs(10)).await?;
+            cache.insert(&context, "2", Duration::from_secs(20)).await?;
+            cache.insert(&context, "3", Duration::from_secs(30)).await?;
+            assert_eq!(2, cache.len());
+            // 最早过期的被淘汰
+            assert!(cache.insert(&context, "1", Duration::from_secs(10)).await?);
+            assert!(!cache.insert(&context, "3", Duration::from_secs(30)).await?);
+            Ok(())
+        }
+    }
+}
+
+pub mod redis {
+    use super::*;
+    use bb8_redis::{redis::cmd, RedisPool};
+
+    fn get_nonce_key(context: &SaasContext, nonce: &str) -> String {
+        format!("wechat::{}::nonce::{}", context.id, nonce)
+    }
+
+    /// 基于redis的nonce缓存, 集群部署时共享
+    pub struct RedisNonceCache {
+        redis_pool: RedisPool,
+    }
+
+    impl RedisNonceCache {
+        pub fn new(pool: RedisPool) -> Self {
+            RedisNonceCache { redis_pool: pool }
+        }
+    }
+
+    #[async_trait]
+    impl NonceCache for RedisNonceCache {
+        async fn insert(
+            &self,
+            context: &SaasContext,
+            nonce: &str,
+            ttl: Duration,
+        ) -> Result<bool, WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            // SET NX: 已存在时返回nil
+            let result: Option<String> = cmd("SET")
+                .arg(get_nonce_key(context, nonce))
+                .arg(1)
+                .arg("EX")
+                .arg(ttl.min(MAX_TTL).as_secs().max(1))
+                .arg("NX")
+                .query_async(conn)
+                .await?;
+            Ok(result.is_some())
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! 回调审计记录
+//!
+//! 每次回调处理完成后(包括失败), 将原始消息、解密后的消息、回复及耗时交给`CallbackRecorder`保存
+//!
+//! 被动回复超时的消息, 处理器在后台完成后再保存一条`deferred`为true的补充记录
+use crate::xml::XmlNode;
+use crate::{
+    CallbackContext, CallbackMessage, ReplyMessage, SaasContext, VerifyInfo, WechatEncryptError,
+    WechatError,
+};
+use async_trait::async_trait;
+use log::warn;
+use serde::Serialize;
+use std::marker::{Send, Sync};
+use std::time::Duration;
+
+/// 一次回调的记录
+#[derive(Debug, Clone, Serialize)]
+pub struct CallbackRecord {
+    /// 收到回调的时间, unix时间戳(毫秒)
+    pub received_at: i64,
+    pub verify_info: VerifyInfo,
+    /// 原始消息体, 安全模式下为密文
+    pub raw_body: String,
+    /// 解密后的消息XML, 校验或解密失败时为None
+    pub decrypted_xml: Option<String>,
+    /// 解析后的消息
+    pub message: Option<CallbackMessage>,
+    /// 被动回复的XML(加密前), 无回复或回复超时时为None
+    pub reply_xml: Option<String>,
+    /// 返回给微信的响应内容, 处理失败时为None
+    pub response: Option<String>,
+    /// 处理耗时(毫秒)
+    pub latency_ms: u64,
+    /// 处理失败时的错误
+    pub error: Option<String>,
+    /// 回复超时后的补充记录, reply_xml为通过客服消息发送的回复
+    pub deferred: bool,
+}
+
+impl CallbackRecord {
+    pub(crate) fn new(verify_info: &VerifyInfo, raw_body: &str) -> Self {
+        CallbackRecord {
+            received_at: chrono::Utc::now().timestamp_millis(),
+            verify_info: verify_info.clone(),
+            raw_body: raw_body.to_string(),
+            decrypted_xml: None,
+            message: None,
+            reply_xml: None,
+            response: None,
+            latency_ms: 0,
+            error: None,
+            deferred: false,
+        }
+    }
+
+    /// 回复超时后处理器在后台完成时的补充记录, latency为后台处理的耗时
+    pub(crate) fn deferred(
+        context: &CallbackContext,
+        message: &CallbackMessage,
+        result: Result<Option<&ReplyMessage>, &WechatError>,
+        latency: Duration,
+    ) -> Self {
+        let mut record = Self::new(&context.verify_info, &context.raw_xml);
+        record.decrypted_xml = Some(context.decrypted_xml.clone());
+        record.message = Some(message.clone());
+        record.latency_ms = latency.as_millis() as u64;
+        record.deferred = true;
+        match result {
+            Ok(reply) => record.reply_xml = reply.and_then(|reply| reply.to_xml().ok()),
+            Err(e) => record.error = Some(format!("{:?}", e)),
+        }
+        record
+    }
+
+    pub(crate) fn finish(&mut self, result: &Result<String, WechatError>, latency: Duration) {
+        self.latency_ms = latency.as_millis() as u64;
+        match result {
+            Ok(response) => self.response = Some(response.clone()),
+            Err(e) => self.error = Some(format!("{:?}", e)),
+        }
+    }
+}
+
+/// 回调审计记录的保存方式
+///
+/// 在返回响应前调用, 应尽快返回, 耗时的写入放到后台执行; 返回的错误只记录日志, 不影响回调的响应
+#[async_trait]
+pub trait CallbackRecorder: Send + Sync {
+    async fn record(
+        &self,
+        context: &SaasContext,
+        record: &CallbackRecord,
+    ) -> Result<(), WechatError>;
+}
+
+pub mod file {
+    use super::*;
+    use std::path::Path;
+    use tokio::fs::{File, OpenOptions};
+    use tokio::io::{AsyncWriteExt, BufWriter};
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    /// 等待写入的记录数上限, 超出时丢弃新的记录
+    const QUEUE_SIZE: usize = 1024;
+
+    /// 一行JSON
+    #[derive(Serialize)]
+    struct Line<'a> {
+        saas_id: u64,
+        #[serde(flatten)]
+        record: &'a CallbackRecord,
+    }
+
+    /// 以JSON Lines格式追加写入文件, 每次回调一行
+    ///
+    /// 记录通过队列交给后台任务写入, 队列中的记录写完后再flush
+    pub struct JsonLinesRecorder {
+        sender: mpsc::Sender<String>,
+        writer: JoinHandle<()>,
+    }
+
+    impl JsonLinesRecorder {
+        /// 打开文件, 不存在时创建, 并启动后台写入任务
+        pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, WechatError> {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|e| WechatEncryptError::storage(e.to_string()))?;
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+            Ok(JsonLinesRecorder {
+                sender,
+                writer: tokio::spawn(write_lines(file, receiver)),
+            })
+        }
+
+        /// 停止接收记录, 等待已提交的记录写入文件
+        pub async fn close(self) {
+            drop(self.sender);
+            if let Err(e) = self.writer.await {
+                warn!("回调记录写入任务异常退出: {:?}", e);
+            }
+        }
+    }
+
+    async fn write_lines(file: File, mut receiver: mpsc::Receiver<String>) {
+        let mut file = BufWriter::new(file);
+        while let Some(line) = receiver.recv().await {
+            let mut result = file.write_all(line.as_bytes()).await;
+            while result.is_ok() {
+                match receiver.try_recv() {
+                    Ok(line) => result = file.write_all(line.as_bytes()).await,
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = result.and(file.flush().await) {
+                warn!("写入回调记录失败: {:?}", e);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl CallbackRecorder for JsonLinesRecorder {
+        async fn record(
+            &self,
+            context: &SaasContext,
+            record: &CallbackRecord,
+        ) -> Result<(), WechatError> {
+            let mut line = serde_json::to_string(&Line {
+                saas_id: context.id,
+                record,
+            })
+            .map_err(|e| WechatEncryptError::internal(e.to_string()))?;
+            line.push('\n');
+            self.sender
+                .clone()
+                .try_send(line)
+                .map_err(|e| WechatEncryptError::storage(format!("回调记录未写入: {}", e)).into())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::wechat::test::get_plaintext_verify_info;
+
+        #[tokio::test]
+        async fn test_json_lines() -> Result<(), WechatError> {
+            let path = std::env::temp_dir().join(format!(
+                "wechat4rs-callback-{}.jsonl",
+                chrono::Utc::now().timestamp_nanos()
+            ));
+            let recorder = JsonLinesRecorder::open(&path).await?;
+            let mut record = CallbackRecord::new(&get_plaintext_verify_info(), "<xml></xml>");
+            record.finish(&Ok("success".into()), Duration::from_millis(12));
+            recorder.record(&SaasContext::new(1), &record).await?;
+            record.finish(
+                &Err(WechatError::ParseError("bad".into())),
+                Duration::from_millis(3),
+            );
+            recorder.record(&SaasContext::new(2), &record).await?;
+            recorder.close().await;
+
+            let content = std::fs::read_to_string(&path)?;
+            std::fs::remove_file(&path)?;
+            let lines: Vec<serde_json::Value> = content
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(2, lines.len());
+            assert_eq!(1, lines[0]["saas_id"]);
+            assert_eq!("<xml></xml>", lines[0]["raw_body"]);
+            assert_eq!("success", lines[0]["response"]);
+            assert_eq!(12, lines[0]["latency_ms"]);
+            assert!(lines[0]["error"].is_null());
+            assert_eq!(2, lines[1]["saas_id"]);
+            assert_eq!(1592558813, lines[1]["verify_info"]["timestamp"]);
+            assert!(lines[1]["error"].as_str().unwrap().contains("bad"));
+            Ok(())
+        }
+    }
+}
+
+pub mod redact {
+    use super::*;
+    use regex::{Captures, Regex};
+
+    /// 跳过指定字段后交给另一个recorder保存
+    ///
+    /// 原始消息、解密后的消息、回复及响应中的同名XML元素内容置为空,
+    /// 解析后的消息中对应字段同样置为空(数字为默认值), 置空后无法解析时只保存XML
+    pub struct RedactingRecorder {
+        inner: Box<dyn CallbackRecorder>,
+        fields: Vec<String>,
+        pattern: Regex,
+    }
+
+    impl RedactingRecorder {
+        /// 跳过媒体id: MediaId, ThumbMediaId
+        pub fn new(inner: Box<dyn CallbackRecorder>) -> Self {
+            Self::with_fields(inner, &["MediaId", "ThumbMediaId"])
+        }
+
+        /// 跳过指定的字段, 使用微信XML中的元素名, 如`Content`, `Recognition`
+        pub fn with_fields(inner: Box<dyn CallbackRecorder>, fields: &[&str]) -> Self {
+            let names = fields
+                .iter()
+                .map(|field| regex::escape(field))
+                .collect::<Vec<_>>()
+                .join("|");
+            let pattern = Regex::new(&format!("(?s)<({0})>.*?</({0})>", names)).unwrap();
+            RedactingRecorder {
+                inner,
+                fields: fields.iter().map(|field| field.to_string()).collect(),
+                pattern,
+            }
+        }
+
+        fn redact_xml(&self, xml: &str) -> String {
+            if self.fields.is_empty() {
+                return xml.to_string();
+            }
+            self.pattern
+                .replace_all(xml, |caps: &Captures| {
+                    if caps[1] == caps[2] {
+                        format!("<{0}></{0}>", &caps[1])
+                    } else {
+                        caps[0].to_string()
+                    }
+                })
+                .into_owned()
+        }
+
+        fn redact_node(&self, node: &mut XmlNode) {
+            if let XmlNode::Element(children) = node {
+                for (name, child) in children.iter_mut() {
+                    if self.fields.contains(name) {
+                        *child = XmlNode::Text(String::new());
+                    } else {
+                        self.redact_node(child);
+                    }
+                }
+            }
+        }
+
+        fn redact_message(&self, message: &CallbackMessage) -> Option<CallbackMessage> {
+            let result = message.to_xml().and_then(|xml| {
+                let mut node = crate::xml::parse(&xml)?;
+                self.redact_node(&mut node);
+                CallbackMessage::from_node(&node)
+            });
+            match result {
+                Ok(message) => Some(message),
+                Err(e) => {
+                    warn!("回调记录中的消息置空字段后无法解析, 只保存XML: {:?}", e);
+                    None
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl CallbackRecorder for RedactingRecorder {
+        async fn record(
+            &self,
+            context: &SaasContext,
+            record: &CallbackRecord,
+        ) -> Result<(), WechatError> {
+            let redact = |xml: &Option<String>| xml.as_ref().map(|xml| self.redact_xml(xml));
+            let record = CallbackRecord {
+                raw_body: self.redact_xml(&record.raw_body),
+                decrypted_xml: redact(&record.decrypted_xml),
+                message: record
+                    .message
+                    .as_ref()
+                    .and_then(|message| self.redact_message(message)),
+                reply_xml: redact(&record.reply_xml),
+                response: redact(&record.response),
+                ..record.clone()
+            };
+            self.inner.record(context, &record).await
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::wechat::test::get_plaintext_verify_info;
+        use std::sync::{Arc, Mutex};
+
+        const IMAGE: &str = "<xml><ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>\
+<FromUserName><![CDATA[openid]]></FromUserName><CreateTime>1592558813</CreateTime>\
+<MsgType><![CDATA[image]]></MsgType><PicUrl><![CDATA[http://pic]]></PicUrl>\
+<MediaId><![CDATA[media_id]]></MediaId><MsgId>1</MsgId></xml>";
+
+        struct Collect(Arc<Mutex<Vec<CallbackRecord>>>);
+
+        #[async_trait]
+        impl CallbackRecorder for Collect {
+            async fn record(
+                &self,
+                _context: &SaasContext,
+                record: &CallbackRecord,
+            ) -> Result<(), WechatError> {
+                self.0.lock().unwrap().push(record.clone());
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn test_redacting() -> Result<(), WechatError> {
+            let records = Arc::new(Mutex::new(vec![]));
+            let recorder = RedactingRecorder::new(Box::new(Collect(records.clone())));
+            let mut record = CallbackRecord::new(&get_plaintext_verify_info(), IMAGE);
+            record.decrypted_xml = Some(IMAGE.to_string());
+            record.message = Some(crate::from_xml(IMAGE)?);
+            recorder.record(&SaasContext::new(1), &record).await?;
+
+            let record = records.lock().unwrap().pop().unwrap();
+            assert!(!record.raw_body.contains("media_id"));
+            assert!(record.raw_body.contains("<MediaId></MediaId>"));
+            assert!(record.raw_body.contains("http://pic"));
+            assert_eq!(Some(record.raw_body.clone()), record.decrypted_xml);
+            match &record.message {
+                Some(CallbackMessage::Image {
+                    media_id, pic_url, ..
+                }) => {
+                    assert_eq!("", media_id);
+                    assert_eq!("http://pic", pic_url);
+                }
+                _ => panic!("should be image message"),
+            }
+
+            // 自定义字段
+            let recorder = RedactingRecorder::with_fields(
+                Box::new(Collect(records.clone())),
+                &["PicUrl", "FromUserName"],
+            );
+            recorder.record(&SaasContext::new(1), &record).await?;
+            let record = records.lock().unwrap().pop().unwrap();
+            assert!(!record.raw_body.contains("http://pic"));
+            assert!(!record.raw_body.contains("openid"));
+            assert_eq!(
+                Some("".to_string()),
+                record.message.map(|m| m.info().from_user_name.clone())
+            );
+
+            // 数字字段置空后解析为默认值
+            let recorder =
+                RedactingRecorder::with_fields(Box::new(Collect(records.clone())), &["MsgId"]);
+            let mut record = CallbackRecord::new(&get_plaintext_verify_info(), IMAGE);
+            record.decrypted_xml = Some(IMAGE.to_string());
+            record.message = Some(crate::from_xml(IMAGE)?);
+            recorder.record(&SaasContext::new(1), &record).await?;
+            let record = records.lock().unwrap().pop().unwrap();
+            assert_eq!(None, record.message.unwrap().info().msg_id);
+            assert!(record.raw_body.contains("<MsgId></MsgId>"));
+            assert!(record.decrypted_xml.unwrap().contains("<MsgId></MsgId>"));
+            Ok(())
+        }
+    }
+}
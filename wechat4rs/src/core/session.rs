@@ -0,0 +1,507 @@
+//! 用户会话
+//!
+//! 按(公众号, FromUserName)保存多轮对话的状态, 值以JSON保存, 超过ttl未更新时过期.
+//! 每次保存都会校验版本号, 同一用户的回调并发执行(如微信重试)时不会互相覆盖.
+//! 通过`Wechat::session`获取的会话记录最后执行update的消息, 微信重试时不会重复执行update.
+use crate::{CallbackContext, SaasContext, SessionError, WechatEncryptError, WechatError};
+use async_trait::async_trait;
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::marker::{Send, Sync};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 并发修改时的最大重试次数
+const MAX_RETRIES: usize = 8;
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// 读取会话, 返回(版本号, JSON)
+    async fn load(
+        &self,
+        context: &SaasContext,
+        open_id: &str,
+    ) -> Result<Option<(u64, String)>, WechatError>;
+
+    /// 当前版本号与expected一致时保存为新的version并返回true, 否则返回false
+    ///
+    /// expected为0表示会话不存在, value为None时删除会话;
+    /// version为随机生成的非0值, 删除或过期后重新创建的会话不会与旧版本号相同
+    async fn store(
+        &self,
+        context: &SaasContext,
+        open_id: &str,
+        expected: u64,
+        version: u64,
+        value: Option<&str>,
+        ttl: Duration,
+    ) -> Result<bool, WechatError>;
+}
+
+/// 会话配置
+pub struct SessionManager {
+    pub store: Box<dyn SessionStore>,
+    /// 会话的有效期, 每次保存时重新计算
+    pub ttl: Duration,
+}
+
+impl SessionManager {
+    /// 默认有效期10分钟
+    pub fn new(store: Box<dyn SessionStore>) -> Self {
+        SessionManager {
+            store,
+            ttl: Duration::from_secs(600),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// 获取用户的会话
+    pub fn session<'a>(&'a self, context: &SaasContext, open_id: &str) -> UserSession<'a> {
+        UserSession {
+            manager: self,
+            context: *context,
+            open_id: open_id.to_string(),
+            applied: None,
+        }
+    }
+
+    /// 回调消息发送方的会话, update时记录消息的去重key
+    pub(crate) fn session_for<'a>(&'a self, context: &CallbackContext) -> UserSession<'a> {
+        let counter = match context.extensions.get::<UpdateCounter>() {
+            Some(counter) => counter,
+            None => {
+                let counter = UpdateCounter::default();
+                context.extensions.insert(counter.clone());
+                counter
+            }
+        };
+        UserSession {
+            applied: Some((context.msg_key.clone(), counter.0)),
+            ..self.session(&context.saas_context, &context.open_id)
+        }
+    }
+}
+
+/// 单次回调请求内已执行的update次数
+#[derive(Clone, Default)]
+struct UpdateCounter(Arc<AtomicUsize>);
+
+/// 保存的会话
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    /// 最后执行update的消息, 及该update在消息处理中的序号
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    applied: Option<(String, usize)>,
+    /// 为None时表示update已删除会话, 保留applied用于重试
+    value: Option<T>,
+}
+
+/// 单个用户的会话
+pub struct UserSession<'a> {
+    manager: &'a SessionManager,
+    context: SaasContext,
+    open_id: String,
+    /// 所属消息的去重key及update计数
+    applied: Option<(String, Arc<AtomicUsize>)>,
+}
+
+impl<'a> UserSession<'a> {
+    async fn load<T: DeserializeOwned>(&self) -> Result<(u64, Option<Stored<T>>), WechatError> {
+        match self
+            .manager
+            .store
+            .load(&self.context, &self.open_id)
+            .await?
+        {
+            Some((version, json)) => {
+                let stored = serde_json::from_str(&json).map_err(invalid_stored)?;
+                Ok((version, Some(stored)))
+            }
+            None => Ok((0, None)),
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self) -> Result<Option<T>, WechatError> {
+        Ok(self.load().await?.1.and_then(|stored| stored.value))
+    }
+
+    /// 覆盖保存
+    pub async fn set<T: Serialize>(&self, value: &T) -> Result<(), WechatError> {
+        let stored = Stored {
+            applied: None,
+            value: Some(value),
+        };
+        let json = serde_json::to_string(&stored).map_err(invalid_stored)?;
+        self.replace(Some(json)).await
+    }
+
+    pub async fn remove(&self) -> Result<(), WechatError> {
+        self.replace(None).await
+    }
+
+    async fn replace(&self, json: Option<String>) -> Result<(), WechatError> {
+        for _ in 0..MAX_RETRIES {
+            let version = match self
+                .manager
+                .store
+                .load(&self.context, &self.open_id)
+                .await?
+            {
+                Some((version, _)) => version,
+                None => 0,
+            };
+            if self.save(version, json.as_deref()).await? {
+                return Ok(());
+            }
+        }
+        Err(self.conflict())
+    }
+
+    /// 读取-修改-保存, 返回保存的值
+    ///
+    /// f返回None时删除会话. 保存前会话已被其它请求修改时, 重新读取并再次调用f;
+    /// 同一消息的update已保存过时(微信重试), 不再调用f, 返回当前的值
+    pub async fn update<T, F>(&self, mut f: F) -> Result<Option<T>, WechatError>
+    where
+        T: Serialize + DeserializeOwned + Send,
+        F: FnMut(Option<T>) -> Option<T> + Send,
+    {
+        let applied = self
+            .applied
+            .as_ref()
+            .map(|(key, counter)| (key.clone(), counter.fetch_add(1, Ordering::SeqCst) + 1));
+        for _ in 0..MAX_RETRIES {
+            let (version, stored) = self.load::<T>().await?;
+            let (last, value) = match stored {
+                Some(stored) => (stored.applied, stored.value),
+                None => (None, None),
+            };
+            if let (Some((key, seq)), Some((last_key, last_seq))) = (&applied, &last) {
+                if key == last_key && seq <= last_seq {
+                    info!("会话已按此消息更新, 跳过: {} {}", self.open_id, key);
+                    return Ok(value);
+                }
+            }
+            let value = f(value);
+            let json = match (&value, &applied) {
+                (None, None) => None,
+                _ => Some(
+                    serde_json::to_string(&Stored {
+                        applied: applied.clone(),
+                        value: value.as_ref(),
+                    })
+                    .map_err(invalid_stored)?,
+                ),
+            };
+            if self.save(version, json.as_deref()).await? {
+                return Ok(value);
+            }
+        }
+        Err(self.conflict())
+    }
+
+    async fn save(&self, expected: u64, json: Option<&str>) -> Result<bool, WechatError> {
+        if expected == 0 && json.is_none() {
+            return Ok(true);
+        }
+        let version = rand::random::<u64>().max(1);
+        let store = &self.manager.store;
+        let ttl = self.manager.ttl;
+        store
+            .store(&self.context, &self.open_id, expected, version, json, ttl)
+            .await
+    }
+
+    fn conflict(&self) -> WechatError {
+        SessionError::Conflict(self.open_id.clone()).into()
+    }
+}
+
+/// 会话数据无法序列化或反序列化
+fn invalid_stored(e: serde_json::Error) -> WechatError {
+    SessionError::InvalidData(e.to_string()).into()
+}
+
+pub mod memory {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Mutex, MutexGuard};
+    use std::time::Instant;
+
+    /// (公众号, openid) -> (过期时间, 版本号, JSON)
+    type Entries = HashMap<(u64, String), (Instant, u64, String)>;
+
+    /// 基于内存的会话, 仅适用于单机部署
+    #[derive(Default)]
+    pub struct MemorySessionStore {
+        entries: Mutex<Entries>,
+    }
+
+    impl MemorySessionStore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        fn lock(&self) -> Result<MutexGuard<'_, Entries>, WechatError> {
+            Ok(self.entries.lock()?)
+        }
+
+        /// 清理过期的会话
+        pub fn remove_expired(&self) {
+            if let Ok(mut entries) = self.lock() {
+                let now = Instant::now();
+                entries.retain(|_, (expire_at, _, _)| *expire_at > now);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SessionStore for MemorySessionStore {
+        async fn load(
+            &self,
+            context: &SaasContext,
+            open_id: &str,
+        ) -> Result<Option<(u64, String)>, WechatError> {
+            let entries = self.lock()?;
+            Ok(match entries.get(&(context.id, open_id.to_string())) {
+                Some((expire_at, version, json)) if *expire_at > Instant::now() => {
+                    Some((*version, json.clone()))
+                }
+                _ => None,
+            })
+        }
+
+        async fn store(
+            &self,
+            context: &SaasContext,
+            open_id: &str,
+            expected: u64,
+            version: u64,
+            value: Option<&str>,
+            ttl: Duration,
+        ) -> Result<bool, WechatError> {
+            let mut entries = self.lock()?;
+            let now = Instant::now();
+            let key = (context.id, open_id.to_string());
+            let current = match entries.get(&key) {
+                Some((expire_at, version, _)) if *expire_at > now => *version,
+                _ => 0,
+            };
+            if current != expected {
+                return Ok(false);
+            }
+            match value {
+                Some(json) => entries.insert(key, (now + ttl, version, json.to_string())),
+                None => entries.remove(&key),
+            };
+            Ok(true)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use serde::Deserialize;
+
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        enum OrderFlow {
+            WaitOrderNo,
+            Confirm { order_no: String },
+        }
+
+        #[tokio::test]
+        async fn test_session() -> Result<(), WechatError> {
+            let sessions = SessionManager::new(Box::new(MemorySessionStore::new()));
+            let context = SaasContext::new(1);
+            let session = sessions.session(&context, "openid");
+            assert_eq!(None, session.get::<OrderFlow>().await?);
+            session.set(&OrderFlow::WaitOrderNo).await?;
+            assert_eq!(Some(OrderFlow::WaitOrderNo), session.get().await?);
+            // 不同公众号、不同用户互不影响
+            let other = SaasContext::new(2);
+            assert_eq!(
+                None,
+                sessions
+                    .session(&other, "openid")
+                    .get::<OrderFlow>()
+                    .await?
+            );
+            assert_eq!(
+                None,
+                sessions
+                    .session(&context, "other")
+                    .get::<OrderFlow>()
+                    .await?
+            );
+
+            let next = session
+                .update(|flow| match flow {
+                    Some(OrderFlow::WaitOrderNo) => Some(OrderFlow::Confirm {
+                        order_no: "123".into(),
+                    }),
+                    flow => flow,
+                })
+                .await?;
+            assert_eq!(
+                Some(OrderFlow::Confirm {
+                    order_no: "123".into()
+                }),
+                next
+            );
+            session.remove().await?;
+            assert_eq!(None, session.get::<OrderFlow>().await?);
+
+            // 过期
+            let sessions = sessions.with_ttl(Duration::from_millis(0));
+            let session = sessions.session(&context, "openid");
+            session.set(&OrderFlow::WaitOrderNo).await?;
+            assert_eq!(None, session.get::<OrderFlow>().await?);
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_concurrent_update() -> Result<(), WechatError> {
+            let store = MemorySessionStore::new();
+            let context = SaasContext::new(1);
+            let ttl = Duration::from_secs(60);
+            // 版本号不一致时不保存
+            assert!(
+                store
+                    .store(&context, "openid", 0, 7, Some(r#"{"value":1}"#), ttl)
+                    .await?
+            );
+            assert!(
+                !store
+                    .store(&context, "openid", 0, 8, Some(r#"{"value":2}"#), ttl)
+                    .await?
+            );
+            assert_eq!(
+                Some((7, r#"{"value":1}"#.to_string())),
+                store.load(&context, "openid").await?
+            );
+
+            let sessions = SessionManager::new(Box::new(store));
+            let session = sessions.session(&context, "openid");
+            let add = || session.update(|count: Option<u32>| Some(count.unwrap_or(0) + 1));
+            let (a, b, c) = tokio::join!(add(), add(), add());
+            a?;
+            b?;
+            c?;
+            assert_eq!(Some(4), session.get::<u32>().await?);
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_recreated_version() -> Result<(), WechatError> {
+            let sessions = SessionManager::new(Box::new(MemorySessionStore::new()));
+            let context = SaasContext::new(1);
+            let session = sessions.session(&context, "openid");
+            session.set(&1).await?;
+            let (version, _) = sessions.store.load(&context, "openid").await?.unwrap();
+            // 删除后重新创建, 旧版本号不能再保存
+            session.remove().await?;
+            session.set(&2).await?;
+            assert!(
+                !sessions
+                    .store
+                    .store(&context, "openid", version, 9, Some("3"), sessions.ttl)
+                    .await?
+            );
+            assert_eq!(Some(2), session.get::<u32>().await?);
+            Ok(())
+        }
+    }
+}
+
+pub mod redis {
+    use super::*;
+    use bb8_redis::{
+        redis::{cmd, Script},
+        RedisPool,
+    };
+
+    /// 版本号为ARGV[1]时保存为新版本ARGV[2], ARGV[3]为空时删除
+    const STORE_SCRIPT: &str = r#"
+local current = redis.call('HGET', KEYS[1], 'v')
+if (current or '0') ~= ARGV[1] then
+    return 0
+end
+if ARGV[3] == '' then
+    redis.call('DEL', KEYS[1])
+else
+    redis.call('HMSET', KEYS[1], 'v', ARGV[2], 'd', ARGV[3])
+    redis.call('EXPIRE', KEYS[1], ARGV[4])
+end
+return 1
+"#;
+
+    fn get_session_key(context: &SaasContext, open_id: &str) -> String {
+        format!("wechat::{}::session::{}", context.id, open_id)
+    }
+
+    /// 基于redis的会话, 集群部署时共享
+    pub struct RedisSessionStore {
+        redis_pool: RedisPool,
+        script: Script,
+    }
+
+    impl RedisSessionStore {
+        pub fn new(pool: RedisPool) -> Self {
+            RedisSessionStore {
+                redis_pool: pool,
+                script: Script::new(STORE_SCRIPT),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SessionStore for RedisSessionStore {
+        async fn load(
+            &self,
+            context: &SaasContext,
+            open_id: &str,
+        ) -> Result<Option<(u64, String)>, WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            let (version, json): (Option<u64>, Option<String>) = cmd("HMGET")
+                .arg(get_session_key(context, open_id))
+                .arg("v")
+                .arg("d")
+                .query_async(conn)
+                .await?;
+            Ok(version.zip(json))
+        }
+
+        async fn store(
+            &self,
+            context: &SaasContext,
+            open_id: &str,
+            expected: u64,
+            version: u64,
+            value: Option<&str>,
+            ttl: Duration,
+        ) -> Result<bool, WechatError> {
+            let mut conn = self.redis_pool.get().await?;
+            let conn = conn
+                .as_mut()
+                .ok_or_else(|| WechatEncryptError::storage("redis连接不可用"))?;
+            let saved: i32 = self
+                .script
+                .key(get_session_key(context, open_id))
+                .arg(expected)
+                .arg(version)
+                .arg(value.unwrap_or_default())
+                .arg(ttl.as_secs().max(1))
+                .invoke_async(conn)
+                .await?;
+            Ok(saved == 1)
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{NoError, SaasContext, Wechat, WechatEncryptError, WechatError, WechatToken};
+use crate::{SaasContext, Wechat, WechatEncryptError, WechatError, WechatToken};
 use async_trait::async_trait;
 use log::{debug, info};
 use smol::{Task, Timer};
@@ -8,11 +8,24 @@ use std::sync::Arc;
 
 pub struct TokenResolveGard {
     finish_flag: Arc<AtomicBool>,
+    /// 随gard一起释放的锁
+    _guard: Option<Box<dyn Send + Sync>>,
 }
 
 impl TokenResolveGard {
     pub fn new(finish_flag: Arc<AtomicBool>) -> Self {
-        TokenResolveGard { finish_flag }
+        TokenResolveGard {
+            finish_flag,
+            _guard: None,
+        }
+    }
+
+    /// gard释放时同时释放guard
+    pub fn with_guard<G: Send + Sync + 'static>(finish_flag: Arc<AtomicBool>, guard: G) -> Self {
+        TokenResolveGard {
+            finish_flag,
+            _guard: Some(Box::new(guard)),
+        }
     }
 }
 
@@ -56,26 +69,115 @@ pub trait TokenProvider: Send + Sync {
 pub mod memory {
     use super::*;
     use async_mutex::Mutex;
+    use chrono::Utc;
     use std::collections::HashMap;
     use std::sync::RwLock;
 
     pub struct MemoryTokenProvider {
         token_list: RwLock<HashMap<u64, WechatToken>>,
-        rw_mutex: Arc<Mutex<()>>,
+        /// 每个公众号独立的token获取锁, 避免一个公众号获取token时阻塞其他公众号
+        locks: RwLock<HashMap<u64, Arc<Mutex<()>>>>,
+        /// 最多缓存的token数量, None为不限制
+        capacity: Option<usize>,
     }
 
     impl MemoryTokenProvider {
         pub fn new() -> Self {
             MemoryTokenProvider {
                 token_list: RwLock::new(HashMap::new()),
-                rw_mutex: Arc::new(Mutex::new(())),
+                locks: RwLock::new(HashMap::new()),
+                capacity: None,
+            }
+        }
+
+        /// 限制缓存的token数量, 超出时优先清除已过期的token, 其次清除最早过期的token
+        ///
+        /// capacity为0时不缓存token
+        pub fn with_capacity(capacity: usize) -> Self {
+            MemoryTokenProvider {
+                token_list: RwLock::new(HashMap::new()),
+                locks: RwLock::new(HashMap::new()),
+                capacity: Some(capacity),
+            }
+        }
+
+        /// 当前缓存的token数量(含已过期未清除的)
+        pub fn len(&self) -> usize {
+            self.token_list.read().unwrap().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// 清除所有已过期的token, 以及不再使用的公众号锁
+        pub fn remove_expired(&self) {
+            let now = Utc::now();
+            let mut list = self.token_list.write().unwrap();
+            list.retain(|id, token| {
+                let keep = token.expire_at >= now;
+                if !keep {
+                    debug!("remove expired token:{}", id);
+                }
+                keep
+            });
+            let mut locks = self.locks.write().unwrap();
+            locks.retain(|id, lock| list.contains_key(id) || Arc::strong_count(lock) > 1);
+        }
+
+        /// token清除后, 同时清除未被占用的公众号锁
+        fn remove_lock(&self, id: u64) {
+            let mut locks = self.locks.write().unwrap();
+            if matches!(locks.get(&id), Some(lock) if Arc::strong_count(lock) == 1) {
+                locks.remove(&id);
+            }
+        }
+
+        fn get_lock(&self, context: &SaasContext) -> Arc<Mutex<()>> {
+            if let Some(lock) = self.locks.read().unwrap().get(&context.id) {
+                return lock.clone();
+            }
+            let mut locks = self.locks.write().unwrap();
+            locks
+                .entry(context.id)
+                .or_insert_with(|| Arc::new(Mutex::new(())))
+                .clone()
+        }
+
+        /// 容量已满时清理出一个位置
+        fn evict_for(&self, list: &mut HashMap<u64, WechatToken>, id: u64) {
+            let capacity = match self.capacity {
+                Some(capacity) => capacity,
+                None => return,
+            };
+            if list.contains_key(&id) || list.len() < capacity {
+                return;
+            }
+            let now = Utc::now();
+            list.retain(|id, token| {
+                let keep = token.expire_at >= now;
+                if !keep {
+                    self.remove_lock(*id);
+                }
+                keep
+            });
+            while !list.is_empty() && list.len() >= capacity {
+                let oldest = list
+                    .iter()
+                    .min_by_key(|(_, token)| token.expire_at)
+                    .map(|(id, _)| *id);
+                if let Some(oldest) = oldest {
+                    debug!("token capacity exceeded, evict token:{}", oldest);
+                    list.remove(&oldest);
+                    self.remove_lock(oldest);
+                }
             }
         }
     }
 
-    impl Drop for MemoryTokenProvider {
-        fn drop(&mut self) {
-            todo!()
+    impl Default for MemoryTokenProvider {
+        fn default() -> Self {
+            Self::new()
         }
     }
 
@@ -87,19 +189,24 @@ pub mod memory {
             wechat: &Wechat,
             context: &SaasContext,
         ) -> Result<Option<WechatToken>, WechatError> {
-            use chrono::Utc;
-            let list = self.token_list.read().unwrap();
-            let token = (*list).get(&context.id);
-            Ok(match token {
-                None => None,
-                Some(token) => {
-                    if token.expire_at < Utc::now() {
-                        None
-                    } else {
-                        Some(token.clone())
-                    }
+            {
+                let list = self.token_list.read().unwrap();
+                match list.get(&context.id) {
+                    None => return Ok(None),
+                    Some(token) if token.expire_at >= Utc::now() => return Ok(Some(token.clone())),
+                    Some(_) => {}
                 }
-            })
+            }
+            // 已过期, 清除
+            debug!("token expired:{:?}", context);
+            let mut list = self.token_list.write().unwrap();
+            if let Some(token) = list.get(&context.id) {
+                if token.expire_at < Utc::now() {
+                    list.remove(&context.id);
+                    self.remove_lock(context.id);
+                }
+            }
+            Ok(None)
         }
         async fn set_token(
             &self,
@@ -108,12 +215,20 @@ pub mod memory {
             token: Option<WechatToken>,
         ) -> Result<(), WechatError> {
             let mut list = self.token_list.write().unwrap();
-            if let Some(token) = token {
-                info!("set token:{:?}, token:{:?}", context, token);
-                (*list).insert(context.id, token);
-            } else {
-                info!("remove token:{:?}", context);
-                (*list).remove(&context.id);
+            match token {
+                Some(_) if self.capacity == Some(0) => {
+                    debug!("token cache disabled:{:?}", context);
+                }
+                Some(token) => {
+                    info!("set token:{:?}, token:{:?}", context, token);
+                    self.evict_for(&mut list, context.id);
+                    list.insert(context.id, token);
+                }
+                None => {
+                    info!("remove token:{:?}", context);
+                    list.remove(&context.id);
+                    self.remove_lock(context.id);
+                }
             }
             Ok(())
         }
@@ -122,24 +237,10 @@ pub mod memory {
             wechat: &Wechat,
             context: &SaasContext,
         ) -> Result<TokenResolveGard, WechatError> {
-            use std::sync::Arc;
-
-            let context = context.clone();
-
-            let rw_mutex = self.rw_mutex.clone();
-            let lock = *rw_mutex.lock().await;
+            let lock = self.get_lock(context).lock_arc().await;
+            debug!("token lock acquired, {:?}", context);
             let flag = Arc::new(AtomicBool::new(false));
-            let gard = TokenResolveGard::new(flag.clone());
-            Task::spawn(async move {
-                use std::time::Duration;
-                while !flag.load(Ordering::SeqCst) {
-                    Timer::after(Duration::from_secs(10)).await;
-                }
-                drop(lock);
-                debug!("token lock released, {:?}", context);
-            })
-            .detach();
-            return Ok(gard);
+            Ok(TokenResolveGard::with_guard(flag, lock))
         }
 
         async fn unlock_token_resolver(
@@ -150,11 +251,131 @@ pub mod memory {
         ) -> Result<(), WechatError> {
             debug!("begin release token lock:{:?}", context);
             drop(gard);
+            // 获取token失败时不会再使用该锁
+            if !self.token_list.read().unwrap().contains_key(&context.id) {
+                self.remove_lock(context.id);
+            }
             Ok(())
         }
 
         //
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::{ConstSaasResolver, WechatConfig};
+        use std::time::Duration;
+
+        fn get_wechat() -> Wechat {
+            Wechat::new(
+                Box::new(ConstSaasResolver::new(WechatConfig::default())),
+                Box::new(MemoryTokenProvider::new()),
+            )
+        }
+
+        #[tokio::test]
+        async fn test_remove_expired_token() -> Result<(), WechatError> {
+            let wechat = get_wechat();
+            let provider = MemoryTokenProvider::new();
+            let context = SaasContext::new(1);
+            let token = WechatToken::new_relative("expired".into(), -10);
+            provider.set_token(&wechat, &context, Some(token)).await?;
+            assert_eq!(1, provider.len());
+            assert!(provider.get_token(&wechat, &context).await?.is_none());
+            assert!(provider.is_empty());
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_capacity() -> Result<(), WechatError> {
+            let wechat = get_wechat();
+            let provider = MemoryTokenProvider::with_capacity(2);
+            for (id, expire_in) in [(1u64, 100), (2, 50), (3, 200)].iter() {
+                let token = WechatToken::new_relative(format!("token{}", id), *expire_in);
+                provider
+                    .set_token(&wechat, &SaasContext::new(*id), Some(token))
+                    .await?;
+            }
+            assert_eq!(2, provider.len());
+            for (id, exists) in [(1u64, true), (2, false), (3, true)].iter() {
+                let token = provider.get_token(&wechat, &SaasContext::new(*id)).await?;
+                assert_eq!(*exists, token.is_some());
+            }
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_lock_per_context() -> Result<(), WechatError> {
+            let wechat = get_wechat();
+            let provider = MemoryTokenProvider::new();
+            let a = SaasContext::new(1);
+            let b = SaasContext::new(2);
+
+            let gard_a = provider.lock_token_resolver(&wechat, &a).await?;
+            // 其他公众号不受影响
+            let gard_b = tokio::time::timeout(
+                Duration::from_secs(1),
+                provider.lock_token_resolver(&wechat, &b),
+            )
+            .await
+            .expect("lock of other context should not be blocked")?;
+            // 同一个公众号需要等待锁释放
+            assert!(tokio::time::timeout(
+                Duration::from_millis(200),
+                provider.lock_token_resolver(&wechat, &a),
+            )
+            .await
+            .is_err());
+
+            provider.unlock_token_resolver(&wechat, &a, gard_a).await?;
+            let gard_a = tokio::time::timeout(
+                Duration::from_secs(1),
+                provider.lock_token_resolver(&wechat, &a),
+            )
+            .await
+            .expect("lock should be released")?;
+            provider.unlock_token_resolver(&wechat, &a, gard_a).await?;
+            provider.unlock_token_resolver(&wechat, &b, gard_b).await?;
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_zero_capacity() -> Result<(), WechatError> {
+            let wechat = get_wechat();
+            let provider = MemoryTokenProvider::with_capacity(0);
+            let context = SaasContext::new(1);
+            let token = WechatToken::new_relative("token".into(), 100);
+            provider.set_token(&wechat, &context, Some(token)).await?;
+            assert!(provider.is_empty());
+            assert!(provider.get_token(&wechat, &context).await?.is_none());
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_lock_evicted_with_token() -> Result<(), WechatError> {
+            let wechat = get_wechat();
+            let provider = MemoryTokenProvider::with_capacity(1);
+            for id in 1..=3u64 {
+                let context = SaasContext::new(id);
+                let gard = provider.lock_token_resolver(&wechat, &context).await?;
+                let token = WechatToken::new_relative(format!("token{}", id), 100);
+                provider.set_token(&wechat, &context, Some(token)).await?;
+                provider.unlock_token_resolver(&wechat, &context, gard).await?;
+            }
+            assert_eq!(1, provider.locks.read().unwrap().len());
+
+            // 获取失败未写入token
+            let context = SaasContext::new(4);
+            let gard = provider.lock_token_resolver(&wechat, &context).await?;
+            provider.unlock_token_resolver(&wechat, &context, gard).await?;
+            assert_eq!(1, provider.locks.read().unwrap().len());
+
+            provider.set_token(&wechat, &SaasContext::new(3), None).await?;
+            assert!(provider.locks.read().unwrap().is_empty());
+            Ok(())
+        }
+    }
 }
 
 pub mod reids {
@@ -210,7 +431,8 @@ pub mod reids {
                 return Ok(None);
             }
 
-            let token: WechatToken = serde_json::from_str(&reply)?;
+            let token: WechatToken = serde_json::from_str(&reply)
+                .map_err(|e| WechatEncryptError::storage(format!("token数据无效: {}", e)))?;
 
             Ok(Some(token))
         }
@@ -225,10 +447,15 @@ pub mod reids {
             let key = get_token_key(context);
 
             if let Some(token) = token {
-                let value = serde_json::to_string(&token)?;
-                cmd("SET").arg(key).arg(value).query_async(conn).await?;
+                let value = serde_json::to_string(&token)
+                    .map_err(|e| WechatEncryptError::internal(e.to_string()))?;
+                cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .query_async::<_, ()>(conn)
+                    .await?;
             } else {
-                cmd("DEL").arg(key).query_async(conn).await?;
+                cmd("DEL").arg(key).query_async::<_, ()>(conn).await?;
             }
             Ok(())
         }
@@ -260,12 +487,11 @@ pub mod reids {
                 }
                 Timer::after(Duration::from_secs(1)).await;
                 if i > 99 {
-                    return Err(WechatError::EncryptError {
-                        source: WechatEncryptError::ApiRequestError {
-                            msg: format!("无法获得token, 超时. {:?}", context),
-                            source: Box::new(NoError),
-                        },
-                    });
+                    return Err(WechatEncryptError::TokenError(format!(
+                        "无法获得token, 超时. {:?}",
+                        context
+                    ))
+                    .into());
                 }
             }
 
@@ -280,14 +506,14 @@ pub mod reids {
                         cmd("EXPIRE")
                             .arg(key.clone())
                             .arg(10i32)
-                            .query_async(conn)
+                            .query_async::<_, ()>(conn)
                             .await?;
                     }
                     // del key
                     debug!("token lock released, {:?}", context);
                     let mut conn = redis_pool.get().await?;
                     let conn = conn.as_mut().unwrap();
-                    cmd("DEL").arg(key).query_async(conn).await?;
+                    cmd("DEL").arg(key).query_async::<_, ()>(conn).await?;
                     Ok::<(), WechatError>(())
                 })
                 .await;
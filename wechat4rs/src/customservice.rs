@@ -1,5 +1,5 @@
 use crate::req_utils::*;
-use crate::{SaasContext, Wechat, WechatError, WechatResult};
+use crate::{SaasContext, Wechat, WechatEncryptError, WechatError, WechatResult};
 use async_trait::async_trait;
 use maplit::hashmap;
 use serde::{Deserialize, Serialize};
@@ -121,6 +121,40 @@ impl KfMessage {
         }
     }
 }
+/// 客服接口-发消息的请求体
+fn send_msg_body(
+    touser: String,
+    msg: &KfMessage,
+    kf_account: Option<String>,
+) -> WechatResult<serde_json::Value> {
+    let msgtype = msg.get_msgtype();
+    // 枚举序列化为{"Text": {...}}, 只取内容
+    let msg_value = serde_json::to_value(msg)
+        .map_err(|e| WechatEncryptError::internal(e.to_string()))?;
+    let mut msg_value = match msg_value {
+        serde_json::Value::Object(map) if map.len() == 1 => {
+            map.into_iter().next().map(|(_, v)| v).unwrap_or_default()
+        }
+        value => value,
+    };
+    if let KfMessage::News { .. } = msg {
+        msg_value = json! ({
+             "articles": [ msg_value ],
+        });
+    };
+    let mut msg_value = json!({
+        "touser": touser,
+        "msgtype": msgtype,
+        msgtype: msg_value,
+    });
+    if let Some(kf_account) = kf_account {
+        msg_value["customservice"] = json!({
+            "kf_account": kf_account,
+        });
+    }
+    Ok(msg_value)
+}
+
 /// 客服消息
 ///
 #[async_trait]
@@ -249,29 +283,7 @@ impl Customservice for Wechat {
         msg: KfMessage,
         kf_account: Option<String>,
     ) -> WechatResult<()> {
-        let msgtype = msg.get_msgtype();
-        let mut msg_value = serde_json::to_value(msg.clone())?;
-        if let KfMessage::News {
-            title: _,
-            description: _,
-            url: _,
-            picurl: _,
-        } = &msg
-        {
-            msg_value = json! ({
-                 "articles": [ msg_value ],
-            });
-        };
-        let mut msg_value = json!({
-            "touser": touser,
-            "msgtype": msgtype.clone(),
-            msgtype: msg_value,
-        });
-        if let Some(kf_account) = kf_account {
-            msg_value["customservice"] = json!({
-                "kf_account": kf_account,
-            });
-        }
+        let msg_value = send_msg_body(touser, &msg, kf_account)?;
         self.api_post(context, "cgi-bin/message/custom/send", None, &msg_value)
             .await
     }
@@ -290,3 +302,36 @@ impl Customservice for Wechat {
             .await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_send_msg_body() -> WechatResult<()> {
+        let body = send_msg_body(
+            "openid".into(),
+            &KfMessage::Text {
+                content: "hello".into(),
+            },
+            None,
+        )?;
+        assert_eq!(
+            json!({"touser": "openid", "msgtype": "text", "text": {"content": "hello"}}),
+            body
+        );
+        let body = send_msg_body(
+            "openid".into(),
+            &KfMessage::News {
+                title: Some("title".into()),
+                description: None,
+                url: None,
+                picurl: None,
+            },
+            Some("test@kf".into()),
+        )?;
+        assert_eq!("title", body["news"]["articles"][0]["title"]);
+        assert_eq!("test@kf", body["customservice"]["kf_account"]);
+        Ok(())
+    }
+}
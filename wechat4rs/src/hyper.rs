@@ -0,0 +1,99 @@
+//! hyper接入
+//!
+//! ```ignore
+//! let service = wechat4rs::hyper::CallbackService::new(wechat.into_shared(), "/wechat-callback");
+//! let make_service = make_service_fn(move |_| {
+//!     let service = service.clone();
+//!     async move { Ok::<_, Infallible>(service) }
+//! });
+//! Server::bind(&addr).serve(make_service).await?;
+//! ```
+//!
+//! 自行路由时可通过`VerifyInfo::try_from(&parts)`提取签名信息,
+//! 错误响应见`service::error_response`, 通过`Response::map(Body::from)`转换为hyper的响应
+use crate::service::{handle_request, text_response};
+use crate::{SaasContext, Wechat};
+use ::hyper::body::HttpBody;
+use ::hyper::service::Service;
+use ::hyper::{Body, Request, Response};
+use bytes::{Bytes, BytesMut};
+use http::StatusCode;
+use log::warn;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// 接入验证(GET)及消息回调(POST)的服务: `{path}/{saas_id}/`
+///
+/// 其它路径返回404, 消息体超过`Wechat::max_body_size`时返回413
+#[derive(Clone)]
+pub struct CallbackService {
+    wechat: Arc<Wechat>,
+    path: String,
+}
+
+impl CallbackService {
+    pub fn new(wechat: Arc<Wechat>, path: &str) -> Self {
+        CallbackService {
+            wechat,
+            path: path.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn wechat(&self) -> &Arc<Wechat> {
+        &self.wechat
+    }
+
+    pub async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let saas_id = match self.saas_id(request.uri().path()) {
+            Some(saas_id) => saas_id,
+            None => return into_response(text_response(StatusCode::NOT_FOUND, "Not Found")),
+        };
+        let (parts, mut body) = request.into_parts();
+        let mut bytes = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("读取回调消息失败: {:?}", e);
+                    return into_response(text_response(StatusCode::BAD_REQUEST, "Bad Request"));
+                }
+            };
+            if bytes.len() + chunk.len() > self.wechat.max_body_size {
+                let status = StatusCode::PAYLOAD_TOO_LARGE;
+                return into_response(text_response(status, "Payload Too Large"));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let request = Request::from_parts(parts, bytes.freeze());
+        let context = SaasContext::new(saas_id);
+        into_response(handle_request(&self.wechat, request, &context).await)
+    }
+
+    /// `{path}/{saas_id}/`中的saas_id
+    fn saas_id(&self, path: &str) -> Option<u64> {
+        let rest = path.strip_prefix(self.path.as_str())?.strip_prefix('/')?;
+        rest.strip_suffix('/').unwrap_or(rest).parse().ok()
+    }
+}
+
+impl Service<Request<Body>> for CallbackService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.handle(request).await) })
+    }
+}
+
+fn into_response(response: Response<Bytes>) -> Response<Body> {
+    response.map(Body::from)
+}
@@ -1,11 +1,21 @@
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod core;
 pub mod customservice;
+#[cfg(feature = "hyper")]
+pub mod hyper;
 pub mod menu;
 mod message;
 mod req_utils;
+pub mod router;
+pub mod service;
 mod wechat;
+pub mod xml;
 
 pub use crate::core::*;
-pub use message::crypt::VerifyInfo;
+pub use message::crypt::{EncryptMode, StrictVerify, VerifyInfo};
 pub use message::*;
+pub use service::WechatService;
 pub use wechat::*;
@@ -113,7 +113,7 @@ pub struct SelfMenuInfo {
 
 impl SelfMenuInfo {
     fn from_json(json: &String) -> Result<Self, WechatError> {
-        let json = serde_json::from_str(json)?;
+        let json = serde_json::from_str(json).map_err(invalid_response)?;
         let json = normalize_json(json);
         if let Value::Object(mut obj) = json {
             let open = obj.get("is_menu_open")
@@ -129,7 +129,7 @@ impl SelfMenuInfo {
                 });
             }
 
-            let menu = serde_json::from_value::<CustomMenu> (selfmenu_info)?; 
+            let menu = serde_json::from_value::<CustomMenu> (selfmenu_info).map_err(invalid_response)?; 
             return Ok(SelfMenuInfo{
                 open,
                 menu: SelfMenuInfoMenu::CustomMenu(menu),
@@ -290,7 +290,7 @@ fn normalize_json(v:Value) -> Value {
 
 /// 微信菜单服务
 #[async_trait]
-trait WechatMenu {
+pub trait WechatMenu {
     /// 创建菜单
     async fn create_menu(&self, context: &SaasContext, menu: &ApiMenu) -> WechatResult<()>;
 
@@ -1,5 +1,11 @@
 use crate::core::{WechatEncryptError, WechatError};
-use log::info;
+use crate::customservice::KfMessage;
+use crate::xml::{self, XmlNode};
+use log::{info, warn};
+use serde::de::{self, DeserializeOwned};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
 
 /// 普通回调事件消息
 #[derive(Debug, PartialEq, Clone)]
@@ -43,14 +49,14 @@ pub enum MenuEventMessage {
     Click {
         /// 事件KEY值，与自定义菜单接口中KEY值对应
         event_key: String,
-    },  
+    },
     /// 点击菜单跳转链接时的事件推送
-    View { 
+    View {
         /// 事件KEY值，设置的跳转URL
         event_key: String,
         /// 指菜单ID，如果是个性化菜单，则可以通过这个字段，知道是哪个规则的菜单被点击了。
         menu_id: Option<String>,
-     },
+    },
     /// 扫码推事件的事件推送
     ScanCodePush {
         /// 事件KEY值，由开发者在创建菜单时设定
@@ -86,7 +92,7 @@ pub enum MenuEventMessage {
         count: i32,
         /// 图片的MD5值，开发者若需要，可用于验证接收到图片
         pic_md5_sum: Vec<String>,
-    }, 
+    },
     /// 弹出微信相册发图器的事件推送
     PicWeixin {
         /// 事件KEY值，由开发者在创建菜单时设定
@@ -95,7 +101,7 @@ pub enum MenuEventMessage {
         count: i32,
         /// 图片的MD5值，开发者若需要，可用于验证接收到图片
         pic_md5_sum: Vec<String>,
-    },     
+    },
     /// 弹出地理位置选择器的事件推送
     LocationSelect {
         /// 事件KEY值，由开发者在创建菜单时设定
@@ -114,25 +120,40 @@ pub enum MenuEventMessage {
     /// 点击菜单跳转小程序的事件推送
     ViewMiniProgram {
         /// 事件KEY值，跳转的小程序路径
-        event_key:String,
+        event_key: String,
         /// 菜单ID，如果是个性化菜单，则可以通过这个字段，知道是哪个规则的菜单被点击了
         menu_id: String,
-    }
+    },
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
 pub struct MessageInfo {
     /// 开发者微信号
+    #[serde(rename = "ToUserName", default)]
     pub to_user_name: String,
     /// 发送方帐号（一个OpenID）
+    #[serde(rename = "FromUserName", default)]
     pub from_user_name: String,
     /// 消息创建时间 （整型）
+    #[serde(rename = "CreateTime", default, deserialize_with = "xml::number")]
     pub create_time: u64,
     /// 消息id，64位整型
+    #[serde(
+        rename = "MsgId",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub msg_id: Option<u64>,
+    /// 回调消息中未解析的其他字段(顶层元素名 -> 文本内容), 回复消息时忽略
+    #[serde(skip)]
+    pub extra: BTreeMap<String, String>,
 }
 
 /// 微信回调消息
+///
+/// 按MsgType区分消息类型, 事件消息再按Event区分; 未支持的消息或事件解析为`Unknown`,
+/// 未解析的顶层元素保存在`MessageInfo::extra`中, 序列化时原样输出
 #[derive(Debug, PartialEq, Clone)]
 pub enum CallbackMessage {
     /// 文本消息
@@ -141,7 +162,7 @@ pub enum CallbackMessage {
         /// 文本消息内容
         content: String,
         /// 菜单消息
-        biz_msg_menu_id: Option<String>, // todo
+        biz_msg_menu_id: Option<String>,
     },
     /// 图片消息
     Image {
@@ -205,239 +226,685 @@ pub enum CallbackMessage {
         event: NormalEventMessage,
     },
     /// 菜单事件
-    MenuMessage  {
+    MenuMessage {
         info: MessageInfo,
         event: MenuEventMessage,
+    },
+    /// 未支持的消息或事件
+    Unknown {
+        info: MessageInfo,
+        /// 消息类型
+        msg_type: String,
+        /// 事件类型, 仅事件消息有值
+        event: Option<String>,
+        /// 所有顶层元素(元素名 -> 文本内容)
+        fields: BTreeMap<String, String>,
+    },
+}
+
+impl CallbackMessage {
+    /// 构建被动回复消息
+    pub fn reply(&self) -> ReplyBuilder {
+        let info = self.info();
+        ReplyBuilder {
+            info: MessageInfo {
+                to_user_name: info.from_user_name.clone(),
+                from_user_name: info.to_user_name.clone(),
+                create_time: chrono::Utc::now().timestamp() as u64,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// 消息头信息
+    pub fn info(&self) -> &MessageInfo {
+        match self {
+            CallbackMessage::Text { info, .. }
+            | CallbackMessage::Image { info, .. }
+            | CallbackMessage::Voice { info, .. }
+            | CallbackMessage::Video { info, .. }
+            | CallbackMessage::ShortVideo { info, .. }
+            | CallbackMessage::Location { info, .. }
+            | CallbackMessage::Link { info, .. }
+            | CallbackMessage::Event { info, .. }
+            | CallbackMessage::MenuMessage { info, .. }
+            | CallbackMessage::Unknown { info, .. } => info,
+        }
+    }
+
+    /// 未解析的其他字段
+    pub fn extra(&self) -> &BTreeMap<String, String> {
+        &self.info().extra
+    }
+
+    /// 消息类型(MsgType)
+    pub fn msg_type(&self) -> &str {
+        match self {
+            CallbackMessage::Text { .. } => "text",
+            CallbackMessage::Image { .. } => "image",
+            CallbackMessage::Voice { .. } => "voice",
+            CallbackMessage::Video { .. } => "video",
+            CallbackMessage::ShortVideo { .. } => "shortvideo",
+            CallbackMessage::Location { .. } => "location",
+            CallbackMessage::Link { .. } => "link",
+            CallbackMessage::Event { .. } | CallbackMessage::MenuMessage { .. } => "event",
+            CallbackMessage::Unknown { msg_type, .. } => msg_type,
+        }
+    }
+
+    /// 事件类型(Event), 非事件消息为None
+    pub fn event(&self) -> Option<&str> {
+        let event = match self {
+            CallbackMessage::Event { event, .. } => match event {
+                NormalEventMessage::Subscribe {} | NormalEventMessage::QrSubscribe { .. } => {
+                    "subscribe"
+                }
+                NormalEventMessage::Unsubscribe {} => "unsubscribe",
+                NormalEventMessage::Scan { .. } => "SCAN",
+                NormalEventMessage::Location { .. } => "LOCATION",
+            },
+            CallbackMessage::MenuMessage { event, .. } => match event {
+                MenuEventMessage::Click { .. } => "CLICK",
+                MenuEventMessage::View { .. } => "VIEW",
+                MenuEventMessage::ScanCodePush { .. } => "scancode_push",
+                MenuEventMessage::ScanCodeWaitMsg { .. } => "scancode_waitmsg",
+                MenuEventMessage::PicSysPhoto { .. } => "pic_sysphoto",
+                MenuEventMessage::PicPhotoOrAlbum { .. } => "pic_photo_or_album",
+                MenuEventMessage::PicWeixin { .. } => "pic_weixin",
+                MenuEventMessage::LocationSelect { .. } => "location_select",
+                MenuEventMessage::ViewMiniProgram { .. } => "view_miniprogram",
+            },
+            CallbackMessage::Unknown { event, .. } => return event.as_deref(),
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    /// 事件KEY值(EventKey), 如菜单KEY、二维码场景值
+    pub fn event_key(&self) -> Option<&str> {
+        match self {
+            CallbackMessage::Event {
+                event:
+                    NormalEventMessage::QrSubscribe { event_key, .. }
+                    | NormalEventMessage::Scan { event_key, .. },
+                ..
+            }
+            | CallbackMessage::MenuMessage {
+                event:
+                    MenuEventMessage::Click { event_key }
+                    | MenuEventMessage::View { event_key, .. }
+                    | MenuEventMessage::ScanCodePush { event_key, .. }
+                    | MenuEventMessage::ScanCodeWaitMsg { event_key, .. }
+                    | MenuEventMessage::PicSysPhoto { event_key, .. }
+                    | MenuEventMessage::PicPhotoOrAlbum { event_key, .. }
+                    | MenuEventMessage::PicWeixin { event_key, .. }
+                    | MenuEventMessage::LocationSelect { event_key, .. }
+                    | MenuEventMessage::ViewMiniProgram { event_key, .. },
+                ..
+            } => Some(event_key),
+            CallbackMessage::Unknown { fields, .. } => fields.get("EventKey").map(String::as_str),
+            _ => None,
+        }
     }
 }
 
+/// 消息头中的字段, 不计入未解析字段
+const INFO_FIELDS: [&str; 7] = [
+    "ToUserName",
+    "FromUserName",
+    "CreateTime",
+    "MsgId",
+    "MsgType",
+    "Event",
+    "EventKey",
+];
+
+/// 已支持的消息类型(MsgType), 其它类型解析为`Unknown`
+const MSG_TYPES: [&str; 8] = [
+    "text",
+    "image",
+    "voice",
+    "video",
+    "shortvideo",
+    "location",
+    "link",
+    "event",
+];
+
+/// 已支持的事件类型(Event), 其它事件解析为`Unknown`
+const EVENTS: [&str; 13] = [
+    "subscribe",
+    "unsubscribe",
+    "SCAN",
+    "LOCATION",
+    "CLICK",
+    "VIEW",
+    "scancode_push",
+    "scancode_waitmsg",
+    "pic_sysphoto",
+    "pic_photo_or_album",
+    "pic_weixin",
+    "location_select",
+    "view_miniprogram",
+];
+
 pub fn from_xml(xml: &str) -> Result<CallbackMessage, WechatError> {
-    use sxd_document::parser;
-    use sxd_xpath::evaluate_xpath;
-
-    let package = parser::parse(xml).unwrap();
-    let doc = package.as_document();
-
-    let get_string = |path: &str| -> Result<String, WechatError> {
-        let path = format!("/xml/{}", path);
-        Ok(evaluate_xpath(&doc, &path)?.string().trim().into())
-    };
-
-    let get_string_vec = |path: &str| -> Result<Vec<String>, WechatError> {
-        use sxd_xpath::Value;
-        let path = format!("/xml/{}", path);
-        let value = evaluate_xpath(&doc, &path)?;
-        let mut result = Vec::new();
-        if let Value::Nodeset(nodes) =  value {
-            for node in nodes.document_order() {
-                result.push(node.string_value().trim().into());
-            }
-        } else {
-            result.push(value.string().trim().into());
+    xml::from_str(xml)
+}
+
+/// 扫码信息<ScanCodeInfo>
+#[derive(Serialize, Deserialize, Default)]
+struct ScanCodeInfo {
+    #[serde(rename = "ScanType", default)]
+    scan_type: String,
+    #[serde(rename = "ScanResult", default)]
+    scan_result: String,
+}
+
+/// 发送的图片信息<SendPicsInfo>
+#[derive(Serialize, Deserialize, Default)]
+struct SendPicsInfo {
+    #[serde(rename = "Count", default, deserialize_with = "xml::number")]
+    count: i32,
+    #[serde(rename = "PicList", default, deserialize_with = "xml::items")]
+    pic_list: Vec<PicItem>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PicItem {
+    #[serde(rename = "PicMd5Sum", default)]
+    pic_md5_sum: String,
+}
+
+impl SendPicsInfo {
+    fn new(count: i32, pic_md5_sum: &[String]) -> Self {
+        let pic_list = pic_md5_sum
+            .iter()
+            .map(|md5| PicItem {
+                pic_md5_sum: md5.clone(),
+            })
+            .collect();
+        SendPicsInfo { count, pic_list }
+    }
+
+    fn md5_sums(self) -> Vec<String> {
+        self.pic_list
+            .into_iter()
+            .map(|pic| pic.pic_md5_sum)
+            .collect()
+    }
+}
+
+/// 发送的位置信息<SendLocationInfo>
+#[derive(Serialize, Deserialize, Default)]
+struct SendLocationInfo {
+    #[serde(rename = "Location_X", default, deserialize_with = "xml::number")]
+    x: f64,
+    #[serde(rename = "Location_Y", default, deserialize_with = "xml::number")]
+    y: f64,
+    /// 可能带小数, 按浮点数解析
+    #[serde(rename = "Scale", default, deserialize_with = "xml::number")]
+    scale: f64,
+    #[serde(rename = "Label", default)]
+    label: String,
+    #[serde(
+        rename = "Poiname",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    poin_name: Option<String>,
+}
+
+/// 读取顶层元素, 不存在或为空时返回默认值
+fn field<T: DeserializeOwned + Default>(node: &XmlNode, name: &str) -> Result<T, WechatError> {
+    match node.child(name) {
+        Some(child) if !child.is_empty() => T::deserialize(child),
+        _ => Ok(T::default()),
+    }
+}
+
+impl Serialize for CallbackMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let info = self.info();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("ToUserName", &info.to_user_name)?;
+        map.serialize_entry("FromUserName", &info.from_user_name)?;
+        map.serialize_entry("CreateTime", &info.create_time)?;
+        if let Some(msg_id) = info.msg_id {
+            map.serialize_entry("MsgId", &msg_id)?;
         }
-        Ok(result)
-    };
-
-
-    let get_f64_option = |path: &str| -> Result<Option<f64>, WechatError> {
-        let path = format!("/xml/{}", path);
-        let path = evaluate_xpath(&doc, &path)?;
-        let n = path.number();
-        if n.is_nan() {
-            Ok(None)
-        } else {
-            Ok(Some(n))
+        map.serialize_entry("MsgType", self.msg_type())?;
+        if let Some(event) = self.event() {
+            map.serialize_entry("Event", event)?;
         }
-    };
-    let get_f64 = |path: &str| -> Result<f64, WechatError> {
-        let n = get_f64_option(path)?.unwrap_or(0f64);
-        Ok(n)
-    };
-
-    let get_u64 = |path: &str| -> Result<u64, WechatError> { Ok(get_f64(path)? as u64) };
-
-    let get_u64_option = |path: &str| -> Result<Option<u64>, WechatError> {
-        let n = get_f64_option(path)?;
-        let n = match n {
-            Some(n) => Some(n as u64),
-            None => None,
-        };
-        Ok(n)
-    };
-
-    let get_string_optional = |path: &str| -> Result<Option<String>, WechatError> {
-        get_string(path)
-            .map(|result| -> Option<String> {
-                if result.is_empty() {
-                    None
-                } else {
-                    Some(result)
+        match self {
+            CallbackMessage::Text {
+                content,
+                biz_msg_menu_id,
+                ..
+            } => {
+                map.serialize_entry("Content", content)?;
+                map.serialize_entry("bizmsgmenuid", biz_msg_menu_id)?;
+            }
+            CallbackMessage::Image {
+                pic_url, media_id, ..
+            } => {
+                map.serialize_entry("PicUrl", pic_url)?;
+                map.serialize_entry("MediaId", media_id)?;
+            }
+            CallbackMessage::Voice {
+                media_id,
+                format,
+                recognition,
+                ..
+            } => {
+                map.serialize_entry("MediaId", media_id)?;
+                map.serialize_entry("Format", format)?;
+                map.serialize_entry("Recognition", recognition)?;
+            }
+            CallbackMessage::Video {
+                media_id,
+                thumb_media_id,
+                ..
+            }
+            | CallbackMessage::ShortVideo {
+                media_id,
+                thumb_media_id,
+                ..
+            } => {
+                map.serialize_entry("MediaId", media_id)?;
+                map.serialize_entry("ThumbMediaId", thumb_media_id)?;
+            }
+            CallbackMessage::Location {
+                x, y, scale, label, ..
+            } => {
+                map.serialize_entry("Location_X", x)?;
+                map.serialize_entry("Location_Y", y)?;
+                map.serialize_entry("Scale", scale)?;
+                map.serialize_entry("Label", label)?;
+            }
+            CallbackMessage::Link {
+                title,
+                description,
+                url,
+                ..
+            } => {
+                map.serialize_entry("Title", title)?;
+                map.serialize_entry("Description", description)?;
+                map.serialize_entry("Url", url)?;
+            }
+            CallbackMessage::Event { event, .. } => match event {
+                NormalEventMessage::Subscribe {} | NormalEventMessage::Unsubscribe {} => {}
+                NormalEventMessage::QrSubscribe { event_key, ticket }
+                | NormalEventMessage::Scan { event_key, ticket } => {
+                    map.serialize_entry("EventKey", event_key)?;
+                    map.serialize_entry("Ticket", ticket)?;
                 }
-            })
-            .map(|r| Ok(r))?
-    };
-    let msg_type = get_string("MsgType")?;
-
-    let info = MessageInfo {
-        to_user_name: get_string("ToUserName")?,
-        from_user_name: get_string("FromUserName")?,
-        create_time: get_u64("CreateTime")?,
-        msg_id: get_u64_option("MsgId")?,
-    };
-
-    let msg = match msg_type.as_str() {
-        "text" => CallbackMessage::Text {
-            info,
-            content: get_string("Content")?,
-            biz_msg_menu_id: get_string_optional("bizmsgmenuid")?,
-        },
-        "image" => CallbackMessage::Image {
-            info,
-            pic_url: get_string("PicUrl")?,
-            media_id: get_string("MediaId")?,
-        },
-        "voice" => CallbackMessage::Voice {
-            info,
-            media_id: get_string("MediaId")?,
-            format: get_string("Format")?,
-            recognition: get_string_optional("Recognition")?,
-        },
-        "video" => CallbackMessage::Video {
-            info,
-            media_id: get_string("MediaId")?,
-            thumb_media_id: get_string("ThumbMediaId")?,
-        },
-        "shortvideo" => CallbackMessage::ShortVideo {
-            info,
-            media_id: get_string("MediaId")?,
-            thumb_media_id: get_string("ThumbMediaId")?,
-        },
-        "location" => CallbackMessage::Location {
-            info,
-            x: get_f64("Location_X")?,
-            y: get_f64("Location_Y")?,
-            scale: get_u64("Scale")?,
-            label: get_string("Label")?,
-        },
-        "link" => CallbackMessage::Link {
-            info,
-            title: get_string("Title")?,
-            description: get_string("Description")?,
-            url: get_string("Url")?,
-        },
-        "event" => {
-            let event = get_string("Event")?;
-            let event_key = get_string("EventKey")?;
-            match event.as_str() {
-                // 普通事件
-                "subscribe" => {
-                    if event_key.is_empty() {
-                        CallbackMessage::Event{info,event: NormalEventMessage::Subscribe {}}
-                    } else {
-                        CallbackMessage::Event{info, event: NormalEventMessage::QrSubscribe {
-                            event_key: event_key.clone(),
-                            ticket: get_string("Ticket")?,
-                        }}
-                    }
+                NormalEventMessage::Location {
+                    lat,
+                    lng,
+                    precesion,
+                } => {
+                    map.serialize_entry("Latitude", lat)?;
+                    map.serialize_entry("Longitude", lng)?;
+                    map.serialize_entry("Precision", precesion)?;
                 }
-                "SCAN" => CallbackMessage::Event{info, event: NormalEventMessage::Scan {
-                    event_key: event_key.clone(),
-                    ticket: get_string("Ticket")?,
-                }},
-                "LOCATION" =>CallbackMessage::Event{info, event: NormalEventMessage::Location {
-                    lat: get_f64("Latitude")?,
-                    lng: get_f64("Longitude")?,
-                    precesion: get_f64("Precision")?,
-                }},
-                // 菜单事件
-                "CLICK" => CallbackMessage::MenuMessage{info, event: MenuEventMessage::Click { event_key }},
-                "VIEW" => CallbackMessage::MenuMessage{info, event: MenuEventMessage::View { 
-                    event_key,
-                    menu_id: get_string_optional("MenuID")?,
-                }},
-                "scancode_push" => CallbackMessage::MenuMessage{
-                    info, event: MenuEventMessage::ScanCodePush {
-                        event_key,
-                        scan_type: get_string("ScanCodeInfo/ScanType")?,
-                        scan_result: get_string("ScanCodeInfo/ScanResult")?,
+            },
+            CallbackMessage::MenuMessage { event, .. } => {
+                map.serialize_entry("EventKey", self.event_key().unwrap_or_default())?;
+                match event {
+                    MenuEventMessage::Click { .. } => {}
+                    MenuEventMessage::View { menu_id, .. } => {
+                        map.serialize_entry("MenuID", menu_id)?;
                     }
-                }, 
-                "scancode_waitmsg" => CallbackMessage::MenuMessage{
-                    info, event: MenuEventMessage::ScanCodeWaitMsg {
-                        event_key,
-                        scan_type: get_string("ScanCodeInfo/ScanType")?,
-                        scan_result: get_string("ScanCodeInfo/ScanResult")?,
+                    MenuEventMessage::ScanCodePush {
+                        scan_type,
+                        scan_result,
+                        ..
                     }
-                },
-                "pic_sysphoto" => {
-                    CallbackMessage::MenuMessage {
-                        info, event: MenuEventMessage::PicSysPhoto {
-                            event_key,
-                            count: get_u64("SendPicsInfo/Count")? as i32,
-                            pic_md5_sum: get_string_vec("SendPicsInfo/PicList/*/PicMd5Sum")?,
-                        }
+                    | MenuEventMessage::ScanCodeWaitMsg {
+                        scan_type,
+                        scan_result,
+                        ..
+                    } => {
+                        let info = ScanCodeInfo {
+                            scan_type: scan_type.clone(),
+                            scan_result: scan_result.clone(),
+                        };
+                        map.serialize_entry("ScanCodeInfo", &info)?;
                     }
-                },
-                "pic_photo_or_album" => {
-                    CallbackMessage::MenuMessage {
-                        info, event: MenuEventMessage::PicPhotoOrAlbum {
-                            event_key,
-                            count: get_u64("SendPicsInfo/Count")? as i32,
-                            pic_md5_sum: get_string_vec("SendPicsInfo/PicList/*/PicMd5Sum")?,
-                        }
+                    MenuEventMessage::PicSysPhoto {
+                        count, pic_md5_sum, ..
                     }
-                },
-                "pic_weixin" => {
-                    CallbackMessage::MenuMessage {
-                        info, event: MenuEventMessage::PicWeixin {
-                            event_key,
-                            count: get_u64("SendPicsInfo/Count")? as i32,
-                            pic_md5_sum: get_string_vec("SendPicsInfo/PicList/*/PicMd5Sum")?,
-                        }
+                    | MenuEventMessage::PicPhotoOrAlbum {
+                        count, pic_md5_sum, ..
                     }
-                },
-                "location_select" => CallbackMessage::MenuMessage{
-                    info, event: MenuEventMessage::LocationSelect {
-                        event_key,
-                        x: get_f64("SendLocationInfo/Location_X")?,
-                        y: get_f64("SendLocationInfo/Location_Y")?,
-                        scale: get_f64("SendLocationInfo/Scale")? as i32,
-                        label: get_string("SendLocationInfo/Label")?,
-                        poin_name: get_string_optional("SendLocationInfo/Poiname")?,
+                    | MenuEventMessage::PicWeixin {
+                        count, pic_md5_sum, ..
+                    } => {
+                        let info = SendPicsInfo::new(*count, pic_md5_sum);
+                        map.serialize_entry("SendPicsInfo", &info)?;
                     }
-                },
-                "view_miniprogram" => CallbackMessage::MenuMessage {
-                    info, event: MenuEventMessage::ViewMiniProgram {
-                        event_key,
-                        menu_id: get_string("MenuId")?,
+                    MenuEventMessage::LocationSelect {
+                        x,
+                        y,
+                        scale,
+                        label,
+                        poin_name,
+                        ..
+                    } => {
+                        let info = SendLocationInfo {
+                            x: *x,
+                            y: *y,
+                            scale: f64::from(*scale),
+                            label: label.clone(),
+                            poin_name: poin_name.clone(),
+                        };
+                        map.serialize_entry("SendLocationInfo", &info)?;
+                    }
+                    MenuEventMessage::ViewMiniProgram { menu_id, .. } => {
+                        map.serialize_entry("MenuId", menu_id)?;
+                    }
+                }
+            }
+            // 消息头之外的字段原样输出
+            CallbackMessage::Unknown { fields, .. } => {
+                for (name, value) in fields {
+                    if !INFO_FIELDS.contains(&name.as_str()) || name == "EventKey" {
+                        map.serialize_entry(name, value)?;
                     }
-                },
-                e @ _ => {
-                    print!("{}", e);
-                    todo!()
                 }
             }
         }
-        _ => {
-            todo!();
+        // 未解析的字段原样输出
+        if !matches!(self, CallbackMessage::Unknown { .. }) {
+            for (name, value) in &info.extra {
+                map.serialize_entry(name, value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CallbackMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let node = XmlNode::deserialize(deserializer)?;
+        CallbackMessage::from_node(&node).map_err(de::Error::custom)
+    }
+}
+
+impl CallbackMessage {
+    /// 从已解析的XML转换
+    ///
+    /// 先按`MSG_TYPES`及`EVENTS`判断是否支持, 缺少MsgType时返回`ParseError`
+    pub fn from_node(node: &XmlNode) -> Result<CallbackMessage, WechatError> {
+        let text = |name: &str| node.child(name).map(|node| node.text().trim().to_string());
+        let msg_type = text("MsgType")
+            .filter(|msg_type| !msg_type.is_empty())
+            .ok_or_else(|| WechatError::ParseError("缺少MsgType".into()))?;
+        let event = text("Event");
+        let supported = match (msg_type.as_str(), &event) {
+            ("event", Some(event)) => EVENTS.contains(&event.as_str()),
+            ("event", None) => false,
+            (msg_type, _) => MSG_TYPES.contains(&msg_type),
+        };
+        let info = MessageInfo::deserialize(node)?;
+        if !supported {
+            warn!("unknown message: {}, event: {:?}", msg_type, event);
+            let fields: BTreeMap<String, String> = node
+                .children()
+                .iter()
+                .map(|(name, node)| (name.clone(), node.text().trim().to_string()))
+                .collect();
+            let info = MessageInfo {
+                extra: fields
+                    .iter()
+                    .filter(|(name, _)| !INFO_FIELDS.contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                ..info
+            };
+            return Ok(CallbackMessage::Unknown {
+                info,
+                msg_type,
+                event,
+                fields,
+            });
+        }
+        let mut msg = match msg_type.as_str() {
+            "text" => CallbackMessage::Text {
+                info,
+                content: field(node, "Content")?,
+                biz_msg_menu_id: field(node, "bizmsgmenuid")?,
+            },
+            "image" => CallbackMessage::Image {
+                info,
+                pic_url: field(node, "PicUrl")?,
+                media_id: field(node, "MediaId")?,
+            },
+            "voice" => CallbackMessage::Voice {
+                info,
+                media_id: field(node, "MediaId")?,
+                format: field(node, "Format")?,
+                recognition: field(node, "Recognition")?,
+            },
+            "video" => CallbackMessage::Video {
+                info,
+                media_id: field(node, "MediaId")?,
+                thumb_media_id: field(node, "ThumbMediaId")?,
+            },
+            "shortvideo" => CallbackMessage::ShortVideo {
+                info,
+                media_id: field(node, "MediaId")?,
+                thumb_media_id: field(node, "ThumbMediaId")?,
+            },
+            "location" => CallbackMessage::Location {
+                info,
+                x: field(node, "Location_X")?,
+                y: field(node, "Location_Y")?,
+                scale: field(node, "Scale")?,
+                label: field(node, "Label")?,
+            },
+            "link" => CallbackMessage::Link {
+                info,
+                title: field(node, "Title")?,
+                description: field(node, "Description")?,
+                url: field(node, "Url")?,
+            },
+            _ => Self::event_from_node(node, info, event.unwrap_or_default())?,
+        };
+        // 消息头及当前消息类型之外的顶层元素即为未解析的字段
+        let known = msg.fields();
+        msg.info_mut().extra = node
+            .children()
+            .iter()
+            .filter(|(name, _)| {
+                !INFO_FIELDS.contains(&name.as_str()) && !known.contains(&name.as_str())
+            })
+            .map(|(name, node)| (name.clone(), node.text().trim().to_string()))
+            .collect();
+        Ok(msg)
+    }
+
+    /// 普通事件及菜单事件
+    fn event_from_node(
+        node: &XmlNode,
+        info: MessageInfo,
+        event: String,
+    ) -> Result<CallbackMessage, WechatError> {
+        let event_key: String = field(node, "EventKey")?;
+        let normal = |event| CallbackMessage::Event {
+            info: info.clone(),
+            event,
+        };
+        let menu = |event| CallbackMessage::MenuMessage {
+            info: info.clone(),
+            event,
+        };
+        let msg = match event.as_str() {
+            // 普通事件
+            "subscribe" if event_key.is_empty() => normal(NormalEventMessage::Subscribe {}),
+            "subscribe" => normal(NormalEventMessage::QrSubscribe {
+                event_key,
+                ticket: field(node, "Ticket")?,
+            }),
+            "unsubscribe" => normal(NormalEventMessage::Unsubscribe {}),
+            "SCAN" => normal(NormalEventMessage::Scan {
+                event_key,
+                ticket: field(node, "Ticket")?,
+            }),
+            "LOCATION" => normal(NormalEventMessage::Location {
+                lat: field(node, "Latitude")?,
+                lng: field(node, "Longitude")?,
+                precesion: field(node, "Precision")?,
+            }),
+            // 菜单事件
+            "CLICK" => menu(MenuEventMessage::Click { event_key }),
+            "VIEW" => menu(MenuEventMessage::View {
+                event_key,
+                menu_id: field(node, "MenuID")?,
+            }),
+            "scancode_push" | "scancode_waitmsg" => {
+                let info: ScanCodeInfo = field(node, "ScanCodeInfo")?;
+                menu(if event == "scancode_push" {
+                    MenuEventMessage::ScanCodePush {
+                        event_key,
+                        scan_type: info.scan_type,
+                        scan_result: info.scan_result,
+                    }
+                } else {
+                    MenuEventMessage::ScanCodeWaitMsg {
+                        event_key,
+                        scan_type: info.scan_type,
+                        scan_result: info.scan_result,
+                    }
+                })
+            }
+            "pic_sysphoto" | "pic_photo_or_album" | "pic_weixin" => {
+                let info: SendPicsInfo = field(node, "SendPicsInfo")?;
+                let count = info.count;
+                let pic_md5_sum = info.md5_sums();
+                menu(match event.as_str() {
+                    "pic_sysphoto" => MenuEventMessage::PicSysPhoto {
+                        event_key,
+                        count,
+                        pic_md5_sum,
+                    },
+                    "pic_photo_or_album" => MenuEventMessage::PicPhotoOrAlbum {
+                        event_key,
+                        count,
+                        pic_md5_sum,
+                    },
+                    _ => MenuEventMessage::PicWeixin {
+                        event_key,
+                        count,
+                        pic_md5_sum,
+                    },
+                })
+            }
+            "location_select" => {
+                let info: SendLocationInfo = field(node, "SendLocationInfo")?;
+                menu(MenuEventMessage::LocationSelect {
+                    event_key,
+                    x: info.x,
+                    y: info.y,
+                    scale: info.scale as i32,
+                    label: info.label,
+                    poin_name: info.poin_name,
+                })
+            }
+            "view_miniprogram" => menu(MenuEventMessage::ViewMiniProgram {
+                event_key,
+                menu_id: field(node, "MenuId")?,
+            }),
+            _ => return Err(WechatError::ParseError(format!("不支持的事件: {}", event))),
+        };
+        Ok(msg)
+    }
+
+    /// 当前消息类型的字段(顶层元素名), 不含消息头
+    fn fields(&self) -> &'static [&'static str] {
+        match self {
+            CallbackMessage::Text { .. } => &["Content", "bizmsgmenuid"],
+            CallbackMessage::Image { .. } => &["PicUrl", "MediaId"],
+            CallbackMessage::Voice { .. } => &["MediaId", "Format", "Recognition"],
+            CallbackMessage::Video { .. } | CallbackMessage::ShortVideo { .. } => {
+                &["MediaId", "ThumbMediaId"]
+            }
+            CallbackMessage::Location { .. } => &["Location_X", "Location_Y", "Scale", "Label"],
+            CallbackMessage::Link { .. } => &["Title", "Description", "Url"],
+            CallbackMessage::Event { event, .. } => match event {
+                NormalEventMessage::Subscribe {} | NormalEventMessage::Unsubscribe {} => &[],
+                NormalEventMessage::QrSubscribe { .. } | NormalEventMessage::Scan { .. } => {
+                    &["Ticket"]
+                }
+                NormalEventMessage::Location { .. } => &["Latitude", "Longitude", "Precision"],
+            },
+            CallbackMessage::MenuMessage { event, .. } => match event {
+                MenuEventMessage::Click { .. } => &[],
+                MenuEventMessage::View { .. } => &["MenuID"],
+                MenuEventMessage::ScanCodePush { .. }
+                | MenuEventMessage::ScanCodeWaitMsg { .. } => &["ScanCodeInfo"],
+                MenuEventMessage::PicSysPhoto { .. }
+                | MenuEventMessage::PicPhotoOrAlbum { .. }
+                | MenuEventMessage::PicWeixin { .. } => &["SendPicsInfo"],
+                MenuEventMessage::LocationSelect { .. } => &["SendLocationInfo"],
+                MenuEventMessage::ViewMiniProgram { .. } => &["MenuId"],
+            },
+            CallbackMessage::Unknown { .. } => &[],
         }
-    };
+    }
+
+    /// 转换为微信XML
+    pub fn to_xml(&self) -> Result<String, WechatError> {
+        xml::to_string(self)
+    }
 
-    Ok(msg)
+    fn info_mut(&mut self) -> &mut MessageInfo {
+        match self {
+            CallbackMessage::Text { info, .. }
+            | CallbackMessage::Image { info, .. }
+            | CallbackMessage::Voice { info, .. }
+            | CallbackMessage::Video { info, .. }
+            | CallbackMessage::ShortVideo { info, .. }
+            | CallbackMessage::Location { info, .. }
+            | CallbackMessage::Link { info, .. }
+            | CallbackMessage::Event { info, .. }
+            | CallbackMessage::MenuMessage { info, .. }
+            | CallbackMessage::Unknown { info, .. } => info,
+        }
+    }
 }
 
 /// 图文消息信息，注意，如果图文数超过限制，则将只发限制内的条数
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ReplyArticle {
     /// 图文消息标题
-    title: String,
+    #[serde(rename = "Title", default)]
+    pub title: String,
     /// 图文消息描述
-    description: String,
+    #[serde(rename = "Description", default)]
+    pub description: String,
     /// 图片链接，支持JPG、PNG格式，较好的效果为大图360*200，小图200*200
-    pic_url: String,
+    #[serde(rename = "PicUrl", default)]
+    pub pic_url: String,
     /// 点击图文消息跳转链接
-    url: String,
+    #[serde(rename = "Url", default)]
+    pub url: String,
 }
+
+impl ReplyArticle {
+    pub fn new(title: &str, description: &str, pic_url: &str, url: &str) -> Self {
+        ReplyArticle {
+            title: title.into(),
+            description: description.into(),
+            pic_url: pic_url.into(),
+            url: url.into(),
+        }
+    }
+}
+
 /// 被动回复用户消息
 #[derive(Debug, PartialEq, Clone)]
 pub enum ReplyMessage {
@@ -487,166 +954,433 @@ pub enum ReplyMessage {
         info: MessageInfo,
         articles: Vec<ReplyArticle>,
     },
+    /// 将消息转发到客服
+    TransferCustomerService {
+        info: MessageInfo,
+        /// 指定会话接入的客服账号, 为空时转发给所有在线客服
+        kf_account: Option<String>,
+    },
 }
 
-use std::collections::VecDeque;
-struct XmlWriter {
-    w: Vec<u8>,
-    stack: VecDeque<String>,
+/// 被动回复的<Image>、<Voice>
+#[derive(Serialize, Deserialize, Default)]
+struct ReplyMedia {
+    #[serde(rename = "MediaId", default)]
+    media_id: String,
 }
-use std::io::Write;
-impl XmlWriter {
-    pub fn new() -> Self {
-        Self {
-            w: Vec::new(),
-            stack: VecDeque::new(),
-        }
-    }
-    pub fn text(&mut self, txt: &String) -> Result<&mut Self, std::io::Error> {
-        use crate::core::utils::str_ext::{SplitKeepingDelimiterExt, SplitType};
 
-        for item in txt
-            .as_str()
-            .split_keeping_delimiter(|c| c == '<' || c == '>' || c == '&')
-        {
-            match item {
-                SplitType::Match(t) => self.w.write_all(t.as_bytes())?,
-                SplitType::Delimiter("<") => self.w.write_all("&lt;".as_bytes())?,
-                SplitType::Delimiter(">") => self.w.write_all("&gt;".as_bytes())?,
-                SplitType::Delimiter("&") => self.w.write_all("&amp;".as_bytes())?,
-                SplitType::Delimiter(..) => unreachable!(),
+/// 被动回复的<Video>
+#[derive(Serialize, Deserialize, Default)]
+struct ReplyVideo {
+    #[serde(rename = "MediaId", default)]
+    media_id: String,
+    #[serde(
+        rename = "Title",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    title: Option<String>,
+    #[serde(
+        rename = "Description",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    description: Option<String>,
+}
+
+/// 被动回复的<Music>
+#[derive(Serialize, Deserialize, Default)]
+struct ReplyMusic {
+    #[serde(
+        rename = "Title",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    title: Option<String>,
+    #[serde(
+        rename = "Description",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    description: Option<String>,
+    #[serde(
+        rename = "MusicUrl",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    music_url: Option<String>,
+    #[serde(
+        rename = "HQMusicUrl",
+        default,
+        deserialize_with = "xml::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    hq_music_url: Option<String>,
+    #[serde(rename = "ThumbMediaId", default)]
+    thumb_media_id: String,
+}
+
+/// 转发客服的<TransInfo>
+#[derive(Serialize, Deserialize, Default)]
+struct TransInfo {
+    #[serde(rename = "KfAccount", default)]
+    kf_account: String,
+}
+
+impl Serialize for ReplyMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // 被动回复不包含MsgId
+        let info = self.info();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("ToUserName", &info.to_user_name)?;
+        map.serialize_entry("FromUserName", &info.from_user_name)?;
+        map.serialize_entry("CreateTime", &info.create_time)?;
+        map.serialize_entry("MsgType", self.msg_type())?;
+        match self {
+            ReplyMessage::Text { content, .. } => map.serialize_entry("Content", content)?,
+            ReplyMessage::Image { media_id, .. } | ReplyMessage::Voice { media_id, .. } => {
+                let name = if self.msg_type() == "image" {
+                    "Image"
+                } else {
+                    "Voice"
+                };
+                let media = ReplyMedia {
+                    media_id: media_id.clone(),
+                };
+                map.serialize_entry(name, &media)?;
+            }
+            ReplyMessage::Video {
+                media_id,
+                title,
+                description,
+                ..
+            } => {
+                let video = ReplyVideo {
+                    media_id: media_id.clone(),
+                    title: title.clone(),
+                    description: description.clone(),
+                };
+                map.serialize_entry("Video", &video)?;
+            }
+            ReplyMessage::Music {
+                thumb_media_id,
+                title,
+                description,
+                music_url,
+                hq_music_url,
+                ..
+            } => {
+                let music = ReplyMusic {
+                    title: title.clone(),
+                    description: description.clone(),
+                    music_url: music_url.clone(),
+                    hq_music_url: hq_music_url.clone(),
+                    thumb_media_id: thumb_media_id.clone(),
+                };
+                map.serialize_entry("Music", &music)?;
+            }
+            ReplyMessage::News { articles, .. } => {
+                map.serialize_entry("ArticleCount", &articles.len())?;
+                map.serialize_entry("Articles", articles)?;
+            }
+            ReplyMessage::TransferCustomerService { kf_account, .. } => {
+                if let Some(kf_account) = kf_account {
+                    let trans_info = TransInfo {
+                        kf_account: kf_account.clone(),
+                    };
+                    map.serialize_entry("TransInfo", &trans_info)?;
+                }
             }
         }
+        map.end()
+    }
+}
 
-        Ok(self)
+impl<'de> Deserialize<'de> for ReplyMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let node = XmlNode::deserialize(deserializer)?;
+        ReplyMessage::from_node(&node).map_err(de::Error::custom)
     }
-    pub fn begin_tag(&mut self, tag: &str) -> Result<&mut Self, std::io::Error> {
-        self.w.write_all(&['<' as u8])?;
-        self.w.write_all(tag.as_bytes())?;
-        self.w.write_all(&['>' as u8])?;
-        self.stack.push_back(tag.to_string());
-        Ok(self)
+}
+
+impl ReplyMessage {
+    /// 转换为微信XML
+    pub fn to_xml(&self) -> Result<String, WechatError> {
+        xml::to_string(self)
     }
 
-    pub fn end_tag(&mut self) -> Result<&mut Self, std::io::Error> {
-        let tag = self.stack.pop_back().expect("end of stack");
-        self.w.write_all(&['<' as u8, '/' as u8])?;
-        self.w.write_all(tag.as_bytes())?;
-        self.w.write_all(&['>' as u8])?;
-        Ok(self)
+    /// 从微信XML解析
+    pub fn from_xml(xml: &str) -> Result<ReplyMessage, WechatError> {
+        xml::from_str(xml)
     }
 
-    pub fn element(&mut self, tag: &str, text: &String) -> Result<&mut Self, std::io::Error> {
-        self.begin_tag(tag)?.text(text)?.end_tag()
+    /// 从已解析的XML转换
+    fn from_node(node: &XmlNode) -> Result<ReplyMessage, WechatError> {
+        let info = MessageInfo::deserialize(node)?;
+        let msg_type: String = field(node, "MsgType")?;
+        let msg = match msg_type.as_str() {
+            "text" => ReplyMessage::Text {
+                info,
+                content: field(node, "Content")?,
+            },
+            "image" => ReplyMessage::Image {
+                info,
+                media_id: field::<ReplyMedia>(node, "Image")?.media_id,
+            },
+            "voice" => ReplyMessage::Voice {
+                info,
+                media_id: field::<ReplyMedia>(node, "Voice")?.media_id,
+            },
+            "video" => {
+                let video: ReplyVideo = field(node, "Video")?;
+                ReplyMessage::Video {
+                    info,
+                    media_id: video.media_id,
+                    title: video.title,
+                    description: video.description,
+                }
+            }
+            "music" => {
+                let music: ReplyMusic = field(node, "Music")?;
+                ReplyMessage::Music {
+                    info,
+                    thumb_media_id: music.thumb_media_id,
+                    title: music.title,
+                    description: music.description,
+                    music_url: music.music_url,
+                    hq_music_url: music.hq_music_url,
+                }
+            }
+            "news" => ReplyMessage::News {
+                info,
+                articles: field(node, "Articles")?,
+            },
+            "transfer_customer_service" => ReplyMessage::TransferCustomerService {
+                info,
+                kf_account: field::<Option<TransInfo>>(node, "TransInfo")?
+                    .map(|trans_info| trans_info.kf_account),
+            },
+            _ => {
+                return Err(WechatError::ParseError(format!(
+                    "不支持的回复消息: {}",
+                    msg_type
+                )))
+            }
+        };
+        Ok(msg)
+    }
+
+    /// 消息头信息
+    pub fn info(&self) -> &MessageInfo {
+        match self {
+            ReplyMessage::Text { info, .. }
+            | ReplyMessage::Image { info, .. }
+            | ReplyMessage::Voice { info, .. }
+            | ReplyMessage::Video { info, .. }
+            | ReplyMessage::Music { info, .. }
+            | ReplyMessage::News { info, .. }
+            | ReplyMessage::TransferCustomerService { info, .. } => info,
+        }
+    }
+
+    /// 消息类型(MsgType)
+    pub fn msg_type(&self) -> &str {
+        match self {
+            ReplyMessage::Text { .. } => "text",
+            ReplyMessage::Image { .. } => "image",
+            ReplyMessage::Voice { .. } => "voice",
+            ReplyMessage::Video { .. } => "video",
+            ReplyMessage::Music { .. } => "music",
+            ReplyMessage::News { .. } => "news",
+            ReplyMessage::TransferCustomerService { .. } => "transfer_customer_service",
+        }
+    }
+
+    /// 转换为客服消息, 用于超时后通过客服接口补发
+    ///
+    /// 客服图文消息每次只能发送1条, 每篇图文转换为一条消息;
+    /// 被动回复的视频消息没有客服消息必需的缩略图, 返回错误;
+    /// 转发客服没有对应的客服消息, 返回空
+    pub fn to_kf_messages(&self) -> Result<Vec<KfMessage>, WechatError> {
+        let messages = match self.clone() {
+            ReplyMessage::Text { content, .. } => vec![KfMessage::Text { content }],
+            ReplyMessage::Image { media_id, .. } => vec![KfMessage::Image { media_id }],
+            ReplyMessage::Voice { media_id, .. } => vec![KfMessage::Voice { media_id }],
+            ReplyMessage::Video { .. } => {
+                return Err(invalid_reply("视频消息缺少缩略图, 无法转换为客服消息"));
+            }
+            ReplyMessage::Music {
+                thumb_media_id,
+                title,
+                description,
+                music_url,
+                hq_music_url,
+                ..
+            } => vec![KfMessage::Music {
+                title,
+                description,
+                musicurl: music_url.unwrap_or_default(),
+                hqmusicurl: hq_music_url.unwrap_or_default(),
+                thumb_media_id,
+            }],
+            ReplyMessage::News { articles, .. } => articles
+                .into_iter()
+                .map(|article| KfMessage::News {
+                    title: Some(article.title),
+                    description: Some(article.description),
+                    url: Some(article.url),
+                    picurl: Some(article.pic_url),
+                })
+                .collect(),
+            ReplyMessage::TransferCustomerService { .. } => vec![],
+        };
+        Ok(messages)
     }
+}
 
-    pub fn element_optional(
-        &mut self,
-        tag: &str,
-        text: &Option<String>,
-    ) -> Result<&mut Self, std::io::Error> {
-        match text {
-            Some(v) => self.begin_tag(tag)?.text(v)?.end_tag(),
-            None => Ok(self),
+/// 被动回复文本消息的最大字节数
+pub const MAX_TEXT_BYTES: usize = 2048;
+/// 被动回复图文消息的最大条数
+pub const MAX_ARTICLES: usize = 8;
+
+/// 按字节数拆分文本, 不会拆开UTF-8字符, 优先在换行处拆分
+pub fn split_text(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while rest.len() > max_bytes {
+        let mut end = max_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // max_bytes小于一个字符时按字符拆分
+            end = rest.chars().next().map(char::len_utf8).unwrap_or_default();
+        } else if let Some(newline) = rest[..end].rfind('\n') {
+            end = newline + 1;
         }
+        parts.push(&rest[..end]);
+        rest = &rest[end..];
     }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+fn invalid_reply(msg: &str) -> WechatError {
+    WechatEncryptError::InvalidReply(msg.to_string()).into()
+}
 
-    pub fn to_string(&mut self) -> Result<String, std::string::FromUtf8Error> {
-        let v = self.w.clone();
-        let xml = String::from_utf8(v)?;
-        Ok(xml)
+fn require_media_id(media_id: &str) -> Result<String, WechatError> {
+    if media_id.is_empty() {
+        return Err(invalid_reply("media_id不能为空"));
     }
+    Ok(media_id.to_string())
 }
 
-fn format_xml<'d, F>(msg_type: &str, info: &MessageInfo, f: F) -> Result<String, WechatError>
-where
-    F: Fn(&mut XmlWriter) -> Result<(), WechatError>,
-{
-    let mut xml = XmlWriter::new();
+/// 被动回复消息的构建器, 通过`CallbackMessage::reply`创建
+///
+/// 自动交换发送方与接收方, CreateTime为当前时间
+#[derive(Debug, Clone)]
+pub struct ReplyBuilder {
+    info: MessageInfo,
+}
 
-    xml.begin_tag("xml")?
-        .element("ToUserName", &info.to_user_name)?
-        .element("FromUserName", &info.from_user_name)?
-        .element("CreateTime", &info.create_time.to_string())?;
+impl ReplyBuilder {
+    /// 文本消息, 超过MAX_TEXT_BYTES时返回错误
+    pub fn text(self, content: &str) -> Result<ReplyMessage, WechatError> {
+        if content.is_empty() {
+            return Err(invalid_reply("文本消息不能为空"));
+        }
+        if content.len() > MAX_TEXT_BYTES {
+            return Err(invalid_reply("文本消息超过2048字节"));
+        }
+        Ok(ReplyMessage::Text {
+            info: self.info,
+            content: content.to_string(),
+        })
+    }
 
-    if let Some(id) = info.msg_id {
-        xml.element("MsgId", &id.to_string())?;
+    /// 长文本消息: 第一段作为被动回复, 其余部分转换为客服消息
+    pub fn long_text(self, content: &str) -> Result<(ReplyMessage, Vec<KfMessage>), WechatError> {
+        let mut parts = split_text(content, MAX_TEXT_BYTES).into_iter();
+        let reply = self.text(parts.next().unwrap_or_default())?;
+        let rest = parts
+            .map(|part| KfMessage::Text {
+                content: part.to_string(),
+            })
+            .collect();
+        Ok((reply, rest))
     }
-    xml.element("MsgType", &msg_type.into())?;
 
-    f(&mut xml)?;
+    pub fn image(self, media_id: &str) -> Result<ReplyMessage, WechatError> {
+        Ok(ReplyMessage::Image {
+            info: self.info,
+            media_id: require_media_id(media_id)?,
+        })
+    }
 
-    xml.end_tag()?;
+    pub fn voice(self, media_id: &str) -> Result<ReplyMessage, WechatError> {
+        Ok(ReplyMessage::Voice {
+            info: self.info,
+            media_id: require_media_id(media_id)?,
+        })
+    }
 
-    let xml = xml.to_string()?;
-    Ok(xml)
-}
+    pub fn video(
+        self,
+        media_id: &str,
+        title: Option<String>,
+        description: Option<String>,
+    ) -> Result<ReplyMessage, WechatError> {
+        Ok(ReplyMessage::Video {
+            info: self.info,
+            media_id: require_media_id(media_id)?,
+            title,
+            description,
+        })
+    }
 
-impl ReplyMessage {
-    pub(crate) fn to_xml(&self) -> Result<String, WechatError> {
-        let xml = match &self {
-            ReplyMessage::Text { info, content } => format_xml("text", &info, |w| {
-                w.element("Content", &content)?;
-                Ok(())
-            })?,
-            ReplyMessage::Image { info, media_id } => format_xml("image", &info, |w| {
-                w.begin_tag("Image")?
-                    .element("MediaId", media_id)?
-                    .end_tag()?;
-                Ok(())
-            })?,
-            ReplyMessage::Voice { info, media_id } => format_xml("voice", &info, |w| {
-                w.begin_tag("Voice")?
-                    .element("MediaId", media_id)?
-                    .end_tag()?;
-                Ok(())
-            })?,
-            ReplyMessage::Video {
-                info,
-                media_id,
-                title,
-                description,
-            } => format_xml("video", &info, |w| {
-                w.begin_tag("Video")?
-                    .element("MediaId", media_id)?
-                    .element_optional("Title", title)?
-                    .element_optional("Description", description)?
-                    .end_tag()?;
-                Ok(())
-            })?,
-            ReplyMessage::Music {
-                info,
-                thumb_media_id,
-                title,
-                description,
-                music_url,
-                hq_music_url,
-            } => format_xml("music", &info, |w| {
-                w.begin_tag("Music")?
-                    .element_optional("Title", title)?
-                    .element_optional("Description", description)?
-                    .element_optional("MusicUrl", music_url)?
-                    .element_optional("HQMusicUrl", hq_music_url)?
-                    .element("ThumbMediaId", thumb_media_id)?
-                    .end_tag()?;
-                Ok(())
-            })?,
-            ReplyMessage::News { info, articles } => format_xml("news", &info, |w| {
-                w.element("ArticleCount", &articles.len().to_string())?
-                    .begin_tag("Articles")?;
-                for article in articles {
-                    w.begin_tag("Item")?
-                        .element("Title", &article.title)?
-                        .element("Description", &article.description)?
-                        .element("PicUrl", &article.pic_url)?
-                        .element("Url", &article.url)?
-                        .end_tag()?;
-                }
-                w.end_tag()?;
-                Ok(())
-            })?,
-        };
-        Ok(xml)
+    pub fn music(
+        self,
+        thumb_media_id: &str,
+        title: Option<String>,
+        description: Option<String>,
+        music_url: Option<String>,
+        hq_music_url: Option<String>,
+    ) -> Result<ReplyMessage, WechatError> {
+        Ok(ReplyMessage::Music {
+            info: self.info,
+            thumb_media_id: require_media_id(thumb_media_id)?,
+            title,
+            description,
+            music_url,
+            hq_music_url,
+        })
+    }
+
+    /// 图文消息, 1到MAX_ARTICLES条
+    pub fn news(self, articles: Vec<ReplyArticle>) -> Result<ReplyMessage, WechatError> {
+        if articles.is_empty() || articles.len() > MAX_ARTICLES {
+            return Err(invalid_reply("图文消息应为1到8条"));
+        }
+        Ok(ReplyMessage::News {
+            info: self.info,
+            articles,
+        })
+    }
+
+    /// 将消息转发到客服, 可指定客服账号
+    pub fn transfer_customer_service(self, kf_account: Option<&str>) -> ReplyMessage {
+        ReplyMessage::TransferCustomerService {
+            info: self.info,
+            kf_account: kf_account.map(str::to_string),
+        }
     }
 }
 
@@ -674,12 +1408,14 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1348831860,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 content: "this is a test".into(),
                 biz_msg_menu_id: None,
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -703,12 +1439,14 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 1500000000,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 content: "满意".into(),
                 biz_msg_menu_id: Some("101".into()),
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -732,12 +1470,14 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1348831860,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 pic_url: "this is a url".into(),
                 media_id: "media_id".into(),
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -761,6 +1501,7 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1357290913,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 media_id: "media_id".into(),
                 format: "Format".into(),
@@ -768,6 +1509,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -792,6 +1534,7 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1357290913,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 media_id: "media_id".into(),
                 format: "Format".into(),
@@ -799,6 +1542,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -822,12 +1566,14 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1357290913,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 media_id: "media_id".into(),
                 thumb_media_id: "thumb_media_id".into(),
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -851,12 +1597,14 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1357290913,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 media_id: "media_id".into(),
                 thumb_media_id: "thumb_media_id".into(),
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -882,6 +1630,7 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1351776360,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 x: 23.134521,
                 y: 113.358803,
@@ -890,6 +1639,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -914,6 +1664,7 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 1351776360,
                     msg_id: Some(1234567890123456),
+                    ..Default::default()
                 },
                 title: "公众平台官网链接".into(),
                 description: "公众平台官网链接".into(),
@@ -921,6 +1672,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -942,11 +1694,71 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: NormalEventMessage::Subscribe {}
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_unsubscribe() -> Result<(), WechatError> {
+        let msg = from_xml(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[FromUser]]></FromUserName>
+  <CreateTime>123456789</CreateTime>
+  <MsgType><![CDATA[event]]></MsgType>
+  <Event><![CDATA[unsubscribe]]></Event>
+</xml>"#,
+        )?;
+        assert_eq!(
+            CallbackMessage::Event {
+                info: MessageInfo {
+                    to_user_name: "toUser".into(),
+                    from_user_name: "FromUser".into(),
+                    create_time: 123456789,
+                    msg_id: None,
+                    ..Default::default()
+                },
+                event: NormalEventMessage::Unsubscribe {}
+            },
+            msg
+        );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_msg_type_event() -> Result<(), WechatError> {
+        let cases = [
+            ("text", None, None, "<Content><![CDATA[hi]]></Content>"),
+            ("event", Some("subscribe"), None, ""),
+            (
+                "event",
+                Some("subscribe"),
+                Some("qrscene_123"),
+                "<EventKey><![CDATA[qrscene_123]]></EventKey><Ticket>t</Ticket>",
+            ),
+            ("event", Some("CLICK"), Some("menu"), "<EventKey>menu</EventKey>"),
+            ("event", Some("unknown_event"), Some("k"), "<EventKey>k</EventKey>"),
+            ("unknown_type", None, None, ""),
+        ];
+        for (msg_type, event, event_key, body) in cases.iter() {
+            let xml = format!(
+                "<xml><MsgType>{}</MsgType>{}{}</xml>",
+                msg_type,
+                event.map(|e| format!("<Event>{}</Event>", e)).unwrap_or_default(),
+                body
+            );
+            let msg = from_xml(&xml)?;
+            assert_eq!(*msg_type, msg.msg_type(), "{}", xml);
+            assert_eq!(*event, msg.event(), "{}", xml);
+            assert_eq!(*event_key, msg.event_key(), "{}", xml);
+        }
         Ok(())
     }
 
@@ -970,6 +1782,7 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: NormalEventMessage::QrSubscribe {
                     event_key: "qrscene_123123".into(),
@@ -978,6 +1791,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1001,6 +1815,7 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: NormalEventMessage::Scan {
                     event_key: "SCENE_VALUE".into(),
@@ -1009,6 +1824,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1033,6 +1849,7 @@ mod callback_message_tests {
                     from_user_name: "fromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: NormalEventMessage::Location {
                     lat: 23.137466,
@@ -1042,6 +1859,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1064,6 +1882,7 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::Click {
                     event_key: "EVENTKEY".into(),
@@ -1071,6 +1890,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1093,6 +1913,7 @@ mod callback_message_tests {
                     from_user_name: "FromUser".into(),
                     create_time: 123456789,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::View {
                     event_key: "www.qq.com".into(),
@@ -1101,6 +1922,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1126,6 +1948,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408090502,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::ScanCodePush {
                     event_key: "6".into(),
@@ -1135,6 +1958,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1160,6 +1984,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408090606,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::ScanCodeWaitMsg {
                     event_key: "6".into(),
@@ -1169,6 +1994,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1197,6 +2023,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408090651,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::PicSysPhoto {
                     event_key: "6".into(),
@@ -1206,6 +2033,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1233,6 +2061,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408090816,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::PicPhotoOrAlbum {
                     event_key: "6".into(),
@@ -1242,6 +2071,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1269,6 +2099,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408090816,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::PicWeixin {
                     event_key: "6".into(),
@@ -1278,6 +2109,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1306,6 +2138,7 @@ mod callback_message_tests {
                     from_user_name: "oMgHVjngRipVsoxg6TuX3vz6glDg".into(),
                     create_time: 1408091189,
                     msg_id: None,
+                    ..Default::default()
                 },
                 event: MenuEventMessage::LocationSelect {
                     event_key: "6".into(),
@@ -1318,6 +2151,7 @@ mod callback_message_tests {
             },
             msg
         );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1334,25 +2168,152 @@ mod callback_message_tests {
 <MenuId>MENUID</MenuId>
 </xml>"#,
         )?;
-        assert_eq!(
-            CallbackMessage::MenuMessage {
-                info: MessageInfo {
-                    to_user_name: "toUser".into(),
-                    from_user_name: "FromUser".into(),
-                    create_time: 123456789,
-                    msg_id: None,
-                },
-                event: MenuEventMessage::ViewMiniProgram {
-                    event_key: "pages/index/index".into(),
-                    menu_id: "MENUID".into(),
-                }
-            },
-            msg
-        );
+        assert_eq!(
+            CallbackMessage::MenuMessage {
+                info: MessageInfo {
+                    to_user_name: "toUser".into(),
+                    from_user_name: "FromUser".into(),
+                    create_time: 123456789,
+                    msg_id: None,
+                    ..Default::default()
+                },
+                event: MenuEventMessage::ViewMiniProgram {
+                    event_key: "pages/index/index".into(),
+                    menu_id: "MENUID".into(),
+                }
+            },
+            msg
+        );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        Ok(())
+    }
+
+
+
+    #[test]
+    fn test_unknown_msg_type() -> Result<(), WechatError> {
+        let msg = from_xml(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[fromUser]]></FromUserName>
+  <CreateTime>1348831860</CreateTime>
+  <MsgType><![CDATA[miniprogrampage]]></MsgType>
+  <Title><![CDATA[title]]></Title>
+  <MsgId>1234567890123456</MsgId>
+</xml>"#,
+        )?;
+        let mut fields = BTreeMap::new();
+        fields.insert("ToUserName".to_string(), "toUser".to_string());
+        fields.insert("FromUserName".to_string(), "fromUser".to_string());
+        fields.insert("CreateTime".to_string(), "1348831860".to_string());
+        fields.insert("MsgType".to_string(), "miniprogrampage".to_string());
+        fields.insert("Title".to_string(), "title".to_string());
+        fields.insert("MsgId".to_string(), "1234567890123456".to_string());
+        let mut extra = BTreeMap::new();
+        extra.insert("Title".to_string(), "title".to_string());
+        assert_eq!(
+            CallbackMessage::Unknown {
+                info: MessageInfo {
+                    to_user_name: "toUser".into(),
+                    from_user_name: "fromUser".into(),
+                    create_time: 1348831860,
+                    msg_id: Some(1234567890123456),
+                    extra,
+                },
+                msg_type: "miniprogrampage".into(),
+                event: None,
+                fields,
+            },
+            msg
+        );
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_event() -> Result<(), WechatError> {
+        let msg = from_xml(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[FromUser]]></FromUserName>
+  <CreateTime>123456789</CreateTime>
+  <MsgType><![CDATA[event]]></MsgType>
+  <Event><![CDATA[TEMPLATESENDJOBFINISH]]></Event>
+  <MsgID>200163836</MsgID>
+  <Status><![CDATA[success]]></Status>
+</xml>"#,
+        )?;
+        match &msg {
+            CallbackMessage::Unknown {
+                info,
+                msg_type,
+                event,
+                fields,
+            } => {
+                assert_eq!("event", msg_type);
+                assert_eq!(&Some("TEMPLATESENDJOBFINISH".to_string()), event);
+                assert_eq!(7, fields.len());
+                assert_eq!(Some(&"success".to_string()), fields.get("Status"));
+                assert_eq!(Some(&"200163836".to_string()), info.extra.get("MsgID"));
+                assert_eq!(2, info.extra.len());
+            }
+            _ => panic!("should be unknown message"),
+        }
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_fields() -> Result<(), WechatError> {
+        let msg = from_xml(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[fromUser]]></FromUserName>
+  <CreateTime>1348831860</CreateTime>
+  <MsgType><![CDATA[text]]></MsgType>
+  <Content><![CDATA[this is a test]]></Content>
+  <MsgId>1234567890123456</MsgId>
+  <MsgDataId>xxxx</MsgDataId>
+  <Idx>1</Idx>
+</xml>"#,
+        )?;
+        let mut extra = BTreeMap::new();
+        extra.insert("Idx".to_string(), "1".to_string());
+        extra.insert("MsgDataId".to_string(), "xxxx".to_string());
+        assert_eq!(&extra, msg.extra());
+        assert_eq!(msg, from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
+    #[test]
+    fn test_missing_msg_type() {
+        let xml = "<xml><ToUserName><![CDATA[toUser]]></ToUserName><MsgType></MsgType></xml>";
+        for xml in &["<xml><ToUserName><![CDATA[toUser]]></ToUserName></xml>", xml] {
+            assert!(matches!(from_xml(xml), Err(WechatError::ParseError(_))));
+            match CallbackMessage::from_node(&xml::parse(xml).unwrap()) {
+                Err(WechatError::ParseError(msg)) => assert!(msg.contains("MsgType")),
+                other => panic!("should be parse error: {:?}", other),
+            }
+        }
+    }
 
+    #[test]
+    fn test_supported_types() -> Result<(), WechatError> {
+        let msg_types = MSG_TYPES.iter().filter(|msg_type| **msg_type != "event");
+        let events = EVENTS.iter().map(|event| ("event", Some(*event)));
+        for (msg_type, event) in msg_types.map(|msg_type| (*msg_type, None)).chain(events) {
+            let event = event.map(|event| format!("<Event>{}</Event>", event));
+            let xml = format!(
+                "<xml><MsgType>{}</MsgType>{}</xml>",
+                msg_type,
+                event.unwrap_or_default()
+            );
+            let msg = from_xml(&xml)?;
+            assert!(!matches!(msg, CallbackMessage::Unknown { .. }), "{}", xml);
+            assert_eq!(msg, from_xml(&msg.to_xml()?)?);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1365,6 +2326,7 @@ mod reply_message_testes {
             from_user_name: "my_id".into(),
             create_time: 123456789876,
             msg_id: None,
+            ..Default::default()
         }
     }
 
@@ -1375,9 +2337,10 @@ mod reply_message_testes {
             content: "reply message".into(),
         };
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>text</MsgType><Content>reply message</Content></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[text]]></MsgType><Content><![CDATA[reply message]]></Content></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1388,9 +2351,10 @@ mod reply_message_testes {
             media_id: "media_id_test".into(),
         };
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>image</MsgType><Image><MediaId>media_id_test</MediaId></Image></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[image]]></MsgType><Image><MediaId><![CDATA[media_id_test]]></MediaId></Image></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1401,9 +2365,10 @@ mod reply_message_testes {
             media_id: "media_id_test".into(),
         };
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>voice</MsgType><Voice><MediaId>media_id_test</MediaId></Voice></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[voice]]></MsgType><Voice><MediaId><![CDATA[media_id_test]]></MediaId></Voice></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1416,9 +2381,12 @@ mod reply_message_testes {
             description: Some("测试描述".into()),
         };
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>video</MsgType><Video><MediaId>media_id_test</MediaId><Title>test vedio</Title><Description>测试描述</Description></Video></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[video]]></MsgType><Video><MediaId><![CDATA[media_id_test]]></MediaId><Title><![CDATA[test vedio]]></Title><Description><![CDATA[测试描述]]></Description></Video></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
+        // 客服视频消息需要缩略图
+        assert!(msg.to_kf_messages().is_err());
         Ok(())
     }
 
@@ -1433,9 +2401,10 @@ mod reply_message_testes {
             thumb_media_id: "thumb media id".into(),
         };
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>music</MsgType><Music><Title>test vedio</Title><Description>测试描述 1&gt;2 &amp;&amp; 2&lt;3</Description><MusicUrl>music url..</MusicUrl><ThumbMediaId>thumb media id</ThumbMediaId></Music></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[music]]></MsgType><Music><Title><![CDATA[test vedio]]></Title><Description><![CDATA[测试描述 1>2 && 2<3]]></Description><MusicUrl><![CDATA[music url..]]></MusicUrl><ThumbMediaId><![CDATA[thumb media id]]></ThumbMediaId></Music></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
         Ok(())
     }
 
@@ -1461,9 +2430,117 @@ mod reply_message_testes {
         };
 
         assert_eq!(
-            r#"<xml><ToUserName>to user</ToUserName><FromUserName>my_id</FromUserName><CreateTime>123456789876</CreateTime><MsgType>news</MsgType><ArticleCount>2</ArticleCount><Articles><Item><Title>article title 1</Title><Description>description 1</Description><PicUrl>pic url 1</PicUrl><Url>url 1</Url></Item><Item><Title>article title 2</Title><Description>description 2</Description><PicUrl>pic url 2</PicUrl><Url>url 2</Url></Item></Articles></xml>"#,
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[news]]></MsgType><ArticleCount>2</ArticleCount><Articles><item><Title><![CDATA[article title 1]]></Title><Description><![CDATA[description 1]]></Description><PicUrl><![CDATA[pic url 1]]></PicUrl><Url><![CDATA[url 1]]></Url></item><item><Title><![CDATA[article title 2]]></Title><Description><![CDATA[description 2]]></Description><PicUrl><![CDATA[pic url 2]]></PicUrl><Url><![CDATA[url 2]]></Url></item></Articles></xml>"#,
+            msg.to_xml()?
+        );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
+        // 客服消息每篇图文一条
+        let kf_messages = msg.to_kf_messages()?;
+        assert_eq!(2, kf_messages.len());
+        match &kf_messages[1] {
+            KfMessage::News { title, url, .. } => {
+                assert_eq!(Some("article title 2"), title.as_deref());
+                assert_eq!(Some("url 2"), url.as_deref());
+            }
+            _ => panic!("should be news"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_customer_service() -> Result<(), WechatError> {
+        let msg = ReplyMessage::TransferCustomerService {
+            info: get_info(),
+            kf_account: None,
+        };
+        assert_eq!(
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[transfer_customer_service]]></MsgType></xml>"#,
+            msg.to_xml()?
+        );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
+
+        // 指定客服账号
+        let msg = ReplyMessage::TransferCustomerService {
+            info: get_info(),
+            kf_account: Some("test1@test".into()),
+        };
+        assert_eq!(
+            r#"<xml><ToUserName><![CDATA[to user]]></ToUserName><FromUserName><![CDATA[my_id]]></FromUserName><CreateTime>123456789876</CreateTime><MsgType><![CDATA[transfer_customer_service]]></MsgType><TransInfo><KfAccount><![CDATA[test1@test]]></KfAccount></TransInfo></xml>"#,
             msg.to_xml()?
         );
+        assert_eq!(msg, ReplyMessage::from_xml(&msg.to_xml()?)?);
+        assert!(msg.to_kf_messages()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_text() {
+        assert_eq!(vec![""], split_text("", 4));
+        assert_eq!(vec!["abcd", "ef"], split_text("abcdef", 4));
+        // 不拆开UTF-8字符
+        assert_eq!(vec!["你", "好a"], split_text("你好a", 4));
+        // 优先在换行处拆分
+        assert_eq!(vec!["ab\n", "cdef"], split_text("ab\ncdef", 4));
+        assert_eq!(vec!["你", "好"], split_text("你好", 1));
+    }
+
+    #[test]
+    fn test_reply_builder() -> Result<(), WechatError> {
+        let message = from_xml(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <FromUserName><![CDATA[fromUser]]></FromUserName>
+  <CreateTime>1348831860</CreateTime>
+  <MsgType><![CDATA[text]]></MsgType>
+  <Content><![CDATA[this is a test]]></Content>
+  <MsgId>1234567890123456</MsgId>
+</xml>"#,
+        )?;
+        let reply = message.reply().text("hello")?;
+        let xml = reply.to_xml()?;
+        assert!(!xml.contains("MsgId"));
+        match reply {
+            ReplyMessage::Text { info, content } => {
+                assert_eq!("fromUser", info.to_user_name);
+                assert_eq!("toUser", info.from_user_name);
+                assert_eq!(None, info.msg_id);
+                assert!(info.create_time > 1348831860);
+                assert_eq!("hello", content);
+            }
+            _ => panic!("should be text reply"),
+        }
+        // 回调消息的MsgId不会出现在回复中
+        let reply = ReplyMessage::Text {
+            info: message.info().clone(),
+            content: "hello".into(),
+        };
+        assert!(!reply.to_xml()?.contains("MsgId"));
+
+        // 回复无效属于服务端错误
+        match message.reply().text("") {
+            Err(e) => assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, e.http_status()),
+            Ok(_) => panic!("empty text should be rejected"),
+        }
+        let long = "好".repeat(1000);
+        assert!(message.reply().text(&long).is_err());
+        let (reply, rest) = message.reply().long_text(&long)?;
+        match reply {
+            ReplyMessage::Text { content, .. } => assert_eq!(682, content.chars().count()),
+            _ => panic!("should be text reply"),
+        }
+        assert_eq!(1, rest.len());
+
+        assert!(message.reply().image("").is_err());
+        assert!(message.reply().voice("media_id").is_ok());
+        assert!(message.reply().video("", None, None).is_err());
+        assert!(message.reply().music("", None, None, None, None).is_err());
+        let article = ReplyArticle::new("title", "description", "pic url", "url");
+        assert!(message.reply().news(vec![]).is_err());
+        assert!(message.reply().news(vec![article.clone(); 9]).is_err());
+        match message.reply().news(vec![article; 8])? {
+            ReplyMessage::News { articles, .. } => assert_eq!("title", articles[7].title),
+            _ => panic!("should be news reply"),
+        }
         Ok(())
     }
 
@@ -1477,8 +2554,10 @@ pub mod crypt {
 
     use std::io::Cursor;
 
-    use crate::core::WechatConfig;
+    use crate::core::nonce_cache::NonceCache;
+    use crate::core::{SaasContext, WechatConfig};
     use base64;
+    use std::time::Duration;
     use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
     use openssl::symm;
     use rand::thread_rng;
@@ -1490,11 +2569,21 @@ pub mod crypt {
             return Ok(None);
         }
         let config = base64::STANDARD.decode_allow_trailing_bits(true);
-        let key = base64::decode_config(key, config)?;
+        let key =
+            base64::decode_config(key, config).map_err(|_| WechatEncryptError::InvalidConfig)?;
+        if key.len() != AES_KEY_LEN {
+            return Err(WechatEncryptError::InvalidConfig);
+        }
         Ok(Some(key))
     }
 
-    #[derive(Deserialize, Debug)]
+    /// EncodingAESKey解码后的长度
+    const AES_KEY_LEN: usize = 32;
+
+    /// 回调消息体的默认最大字节数
+    pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct VerifyInfo {
         pub signature: String,
         pub timestamp: i64,
@@ -1505,6 +2594,41 @@ pub mod crypt {
         pub encrypt_type: Option<String>,
     }
 
+    /// 回调消息的加密方式
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum EncryptMode {
+        /// 明文模式
+        Plaintext,
+        /// 兼容模式, 同时包含明文字段及Encrypt
+        Compatible,
+        /// 安全模式, 只有Encrypt
+        Safe,
+    }
+
+    impl EncryptMode {
+        /// 回复消息是否需要加密, 兼容模式下按公众号是否配置了EncodingAESKey
+        pub fn reply_encrypted(self, config: &WechatConfig) -> bool {
+            match self {
+                EncryptMode::Plaintext => false,
+                EncryptMode::Compatible => config.key.is_some(),
+                EncryptMode::Safe => true,
+            }
+        }
+    }
+
+    impl VerifyInfo {
+        /// 根据请求参数及已解析的消息体判断加密方式
+        pub fn encrypt_mode(&self, envelope: &XmlNode) -> EncryptMode {
+            if self.encrypt_type.is_none() {
+                EncryptMode::Plaintext
+            } else if envelope.child("MsgType").is_some() {
+                EncryptMode::Compatible
+            } else {
+                EncryptMode::Safe
+            }
+        }
+    }
+
     #[derive(Debug, Eq, PartialEq)]
     pub(crate) struct PrpCrypto {
         key: Vec<u8>,
@@ -1524,7 +2648,15 @@ pub mod crypt {
             }
         }
 
+        fn check_key(&self) -> Result<(), WechatEncryptError> {
+            if self.key.len() != AES_KEY_LEN {
+                return Err(WechatEncryptError::InvalidConfig);
+            }
+            Ok(())
+        }
+
         pub fn encrypt(&self, plaintext: &str, app_id: &str) -> Result<String, WechatEncryptError> {
+            self.check_key()?;
             let mut wtr = PrpCrypto::get_random_string().into_bytes();
             wtr.write_u32::<NativeEndian>((plaintext.len() as u32).to_be())?;
             wtr.extend(plaintext.bytes());
@@ -1537,13 +2669,24 @@ pub mod crypt {
         }
 
         pub fn decrypt(&self, ciphertext: &str, _id: &str) -> Result<String, WechatEncryptError> {
-            let b64decoded = base64::decode(ciphertext)?;
+            self.check_key()?;
+            let b64decoded = base64::decode(ciphertext)
+                .map_err(|e| WechatEncryptError::InvalidMessage(format!("密文无效: {}", e)))?;
             let cipher = openssl::symm::Cipher::aes_256_cbc();
-            let text = symm::decrypt(cipher, &self.key, Some(&self.key[..16]), &b64decoded)?;
-            let mut rdr = Cursor::new(text[16..20].to_vec());
+            let text = symm::decrypt(cipher, &self.key, Some(&self.key[..16]), &b64decoded)
+                .map_err(|e| WechatEncryptError::InvalidMessage(format!("解密失败: {:?}", e)))?;
+            // 16字节随机串 + 4字节长度 + 内容 + AppID
+            if text.len() < 20 {
+                return Err(WechatEncryptError::InvalidMessage("长度不足".into()));
+            }
+            let mut rdr = Cursor::new(&text[16..20]);
             let content_length = u32::from_be(rdr.read_u32::<NativeEndian>()?) as usize;
-            let content = &text[20..content_length + 20];
-            let from_id = &text[content_length + 20..];
+            let content_end = content_length
+                .checked_add(20)
+                .filter(|end| *end <= text.len())
+                .ok_or_else(|| WechatEncryptError::InvalidMessage("内容长度无效".into()))?;
+            let content = &text[20..content_end];
+            let from_id = &text[content_end..];
             if from_id != _id.as_bytes() {
                 return Err(WechatEncryptError::InvalidAppId);
             }
@@ -1573,11 +2716,17 @@ pub mod crypt {
         Ok(hex::encode(signature))
     }
 
+    /// 常量时间比较签名
+    fn signature_eq(signature: &str, real_signature: &str) -> bool {
+        signature.len() == real_signature.len()
+            && openssl::memcmp::eq(signature.as_bytes(), real_signature.as_bytes())
+    }
+
     /// 校验基础的消息头
     pub fn verify_base(token: &String, verify_info: &VerifyInfo) -> Result<(), WechatEncryptError> {
         let real_signature = get_signature(token, verify_info.timestamp, &verify_info.nonce, &"")?;
 
-        if verify_info.signature != real_signature {
+        if !signature_eq(&verify_info.signature, &real_signature) {
             info!(
                 "消息头签名校验失败: acture_signature:{}, {:?}",
                 real_signature, verify_info,
@@ -1602,7 +2751,7 @@ pub mod crypt {
                 let real_signature =
                     get_signature(token, verify_info.timestamp, &verify_info.nonce, msg)?;
 
-                if signature != &real_signature {
+                if !signature_eq(signature, &real_signature) {
                     info!(
                         "消息内容签名校验失败: verify:{:?}, acture_signature:{}, {}",
                         verify_info, real_signature, msg,
@@ -1616,6 +2765,84 @@ pub mod crypt {
         }
     }
 
+    /// 严格校验模式
+    ///
+    /// + 加密模式下必须有msg_signature
+    /// + 时间戳与服务器时间的偏差不能超过timestamp_window
+    /// + 配置nonce_cache时, 拒绝重复的nonce
+    ///
+    /// 微信重试回调时timestamp及nonce不变, 未启用去重时重试会被拒绝;
+    /// 启用去重(`Wechat::set_dedup`)时重试按去重处理, 只有首次处理的消息检查nonce
+    pub struct StrictVerify {
+        /// 时间戳允许的最大偏差
+        pub timestamp_window: Duration,
+        /// nonce防重放缓存, None为不检查
+        pub nonce_cache: Option<Box<dyn NonceCache>>,
+    }
+
+    impl StrictVerify {
+        pub fn new(timestamp_window: Duration) -> Self {
+            StrictVerify {
+                timestamp_window,
+                nonce_cache: None,
+            }
+        }
+
+        /// 使用nonce_cache检查重放
+        pub fn with_nonce_cache(mut self, nonce_cache: Box<dyn NonceCache>) -> Self {
+            self.nonce_cache = Some(nonce_cache);
+            self
+        }
+
+        /// 校验消息头, now为当前的unix时间戳(秒)
+        pub fn verify_header(
+            &self,
+            verify_info: &VerifyInfo,
+            now: i64,
+        ) -> Result<(), WechatEncryptError> {
+            if verify_info.encrypt_type.is_some() && verify_info.msg_signature.is_none() {
+                return Err(WechatEncryptError::InvalidSignature(
+                    "缺少消息签名".into(),
+                ));
+            }
+            let offset = now.checked_sub(verify_info.timestamp).map(i64::unsigned_abs);
+            if !matches!(offset, Some(offset) if offset <= self.timestamp_window.as_secs()) {
+                info!("时间戳超出范围: now:{}, {:?}", now, verify_info);
+                return Err(WechatEncryptError::InvalidSignature(
+                    "时间戳超出范围".into(),
+                ));
+            }
+            Ok(())
+        }
+
+        /// 检查nonce是否重复, 应在签名校验通过后调用
+        pub async fn verify_nonce(
+            &self,
+            context: &SaasContext,
+            verify_info: &VerifyInfo,
+        ) -> Result<(), WechatError> {
+            let nonce_cache = match &self.nonce_cache {
+                Some(nonce_cache) => nonce_cache,
+                None => return Ok(()),
+            };
+            // 时间窗口前后均可能被接受, 需保留两倍窗口
+            let ttl = self.timestamp_window.checked_mul(2).unwrap_or(Duration::MAX);
+            let nonce = format!("{}:{}", verify_info.timestamp, verify_info.nonce);
+            if !nonce_cache.insert(context, &nonce, ttl).await? {
+                info!("重复的nonce: {:?}", verify_info);
+                return Err(WechatEncryptError::InvalidSignature("重复的请求".into()).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Default for StrictVerify {
+        /// 时间窗口5分钟, 不检查重放
+        fn default() -> Self {
+            Self::new(Duration::from_secs(300))
+        }
+    }
+
     pub fn decrypt_echostr(
         config: &WechatConfig,
         token: &String,
@@ -1630,13 +2857,11 @@ pub mod crypt {
         }
         verify_message(token, verify_info, echo_str)?;
         // 尝试解密
-        let key = match &config.key {
-            Some(key) => key,
-            None => return Ok(echo_str.clone()),
-        };
+        if config.key.is_none() {
+            return Ok(echo_str.clone());
+        }
 
-        let prp = PrpCrypto::new(key);
-        let msg = prp.decrypt(echo_str, &config.app_id);
+        let msg = decrypt_with_keys(config, echo_str);
         match msg {
             Ok(msg) => Ok(msg),
             Err(e) => Err(WechatEncryptError::InvalidSignature(format!(
@@ -1646,6 +2871,19 @@ pub mod crypt {
         }
     }
 
+    /// 加密后的回复消息
+    #[derive(Serialize)]
+    struct EncryptedMessage<'a> {
+        #[serde(rename = "Encrypt")]
+        encrypt: &'a str,
+        #[serde(rename = "MsgSignature")]
+        msg_signature: &'a str,
+        #[serde(rename = "TimeStamp")]
+        timestamp: i64,
+        #[serde(rename = "Nonce")]
+        nonce: &'a str,
+    }
+
     pub fn encrypt_message(
         config: &WechatConfig,
         token: &String,
@@ -1660,43 +2898,195 @@ pub mod crypt {
         let prp = PrpCrypto::new(&key);
         let encrypted_msg = prp.encrypt(msg, &config.app_id)?;
         let signature = get_signature(token, timestamp, nonce, &encrypted_msg)?;
-        let msg = XmlWriter::new()
-            .begin_tag("xml")?
-            .element("Encrypt", &encrypted_msg)?
-            .element("MsgSignature", &signature)?
-            .element("TimeStamp", &timestamp.to_string())?
-            .element("Nonce", &nonce.to_string())?
-            .end_tag()?
-            .to_string()?;
-        Ok(msg)
+        let envelope = EncryptedMessage {
+            encrypt: &encrypted_msg,
+            msg_signature: &signature,
+            timestamp,
+            nonce,
+        };
+        xml::to_string(&envelope).map_err(|e| WechatEncryptError::InvalidReply(e.to_string()))
+    }
+
+    /// 加密被动回复消息, 使用当前时间及随机nonce
+    pub fn encrypt_reply(
+        config: &WechatConfig,
+        token: &String,
+        reply_xml: &str,
+    ) -> Result<String, WechatEncryptError> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let nonce = thread_rng().gen::<u32>().to_string();
+        encrypt_message(config, token, reply_xml, timestamp, &nonce)
+    }
+
+    /// 解析回调消息体, 超过max_size字节时返回错误
+    ///
+    /// 纯函数, 不校验签名, 可直接用于fuzz
+    pub fn parse_envelope(xml: &str, max_size: usize) -> Result<XmlNode, WechatError> {
+        if xml.len() > max_size {
+            return Err(WechatError::ParseError(format!(
+                "消息体过大: {} > {}",
+                xml.len(),
+                max_size
+            )));
+        }
+        xml::parse(xml)
+    }
+
+    /// 使用解码后的EncodingAESKey解密消息, 并校验AppID
+    ///
+    /// 纯函数, 不校验签名, 可直接用于fuzz
+    pub fn decrypt(key: &[u8], app_id: &str, encrypted: &str) -> Result<String, WechatEncryptError> {
+        PrpCrypto::new(key).decrypt(encrypted, app_id)
+    }
+
+    /// 依次尝试当前及旧的EncodingAESKey解密, 返回第一个解密成功的结果
+    ///
+    /// 全部失败时返回当前key的错误
+    pub fn decrypt_with_keys(
+        config: &WechatConfig,
+        encrypted: &str,
+    ) -> Result<String, WechatEncryptError> {
+        let mut first_error = None;
+        for (index, key) in config.keys().enumerate() {
+            match decrypt(key, &config.app_id, encrypted) {
+                Ok(msg) => {
+                    info!(
+                        "使用EncodingAESKey[{}]解密成功, app_id: {}",
+                        index, config.app_id
+                    );
+                    return Ok(msg);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or(WechatEncryptError::InvalidConfig))
     }
 
+    /// 解密回调消息, 返回明文XML, 消息体超过max_size字节时返回错误
+    ///
+    /// 兼容模式下会校验明文与密文一致
     pub fn decrypt_message(
         config: &WechatConfig,
         token: &String,
         verify_info: &VerifyInfo,
         xml: &str,
-    ) -> Result<String, WechatEncryptError> {
+        max_size: usize,
+    ) -> Result<String, WechatError> {
         verify_base(token, verify_info)?;
 
-        if let None = verify_info.encrypt_type {
+        if verify_info.encrypt_type.is_none() {
             return Ok(xml.into());
         }
+        let envelope = parse_envelope(xml, max_size)?;
+        let msg = decrypt_envelope(config, token, verify_info, &envelope)?;
+        if config.key.is_some() && verify_info.encrypt_mode(&envelope) == EncryptMode::Compatible
+        {
+            check_compatible(&envelope, &xml::parse(&msg)?)?;
+        }
+        Ok(msg)
+    }
+
+    /// 已校验并解析的回调消息
+    #[derive(Debug, Clone)]
+    pub struct ParsedMessage {
+        /// 消息加密方式
+        pub mode: EncryptMode,
+        /// 明文消息XML, 明文模式及兼容模式未配置EncodingAESKey时为原始消息体
+        pub xml: String,
+        /// 明文消息的根节点
+        pub node: XmlNode,
+    }
+
+    /// 校验并解析回调消息, 返回消息的加密方式及明文
+    ///
+    /// 消息体只解析一次, 供`decrypt_message`及`from_xml`共用.
+    /// 兼容模式下:
+    /// + 配置了EncodingAESKey时解密密文, 并校验明文与密文一致
+    /// + 未配置时只校验密文签名, 使用明文部分
+    pub fn parse_message(
+        config: &WechatConfig,
+        token: &String,
+        verify_info: &VerifyInfo,
+        xml: &str,
+        max_size: usize,
+    ) -> Result<ParsedMessage, WechatError> {
+        verify_base(token, verify_info)?;
+
+        let envelope = parse_envelope(xml, max_size)?;
+        let mode = verify_info.encrypt_mode(&envelope);
+        let (xml, node) = match mode {
+            EncryptMode::Plaintext => (xml.to_string(), envelope),
+            EncryptMode::Safe => {
+                let msg = decrypt_envelope(config, token, verify_info, &envelope)?;
+                let node = xml::parse(&msg)?;
+                (msg, node)
+            }
+            EncryptMode::Compatible => {
+                let msg = decrypt_envelope(config, token, verify_info, &envelope)?;
+                if config.key.is_some() {
+                    let node = xml::parse(&msg)?;
+                    check_compatible(&envelope, &node)?;
+                    (msg, node)
+                } else {
+                    info!("兼容模式未配置EncodingAESKey, 使用明文消息");
+                    (xml.to_string(), plaintext_part(envelope))
+                }
+            }
+        };
+        Ok(ParsedMessage { mode, xml, node })
+    }
 
-        use sxd_document::parser;
-        use sxd_xpath::evaluate_xpath;
+    /// 兼容模式下的明文部分, 去掉Encrypt字段
+    fn plaintext_part(envelope: XmlNode) -> XmlNode {
+        match envelope {
+            XmlNode::Element(children) => XmlNode::Element(
+                children
+                    .into_iter()
+                    .filter(|(name, _)| name != "Encrypt")
+                    .collect(),
+            ),
+            node => node,
+        }
+    }
 
-        let package = parser::parse(xml).unwrap();
-        let doc = package.as_document();
+    /// 兼容模式下明文中的字段必须与密文解密后的一致
+    fn check_compatible(
+        envelope: &XmlNode,
+        decrypted: &XmlNode,
+    ) -> Result<(), WechatEncryptError> {
+        for (name, node) in envelope.children() {
+            if name == "Encrypt" {
+                continue;
+            }
+            match decrypted.child(name) {
+                Some(value) if value.text() == node.text() => {}
+                _ => {
+                    return Err(WechatEncryptError::InvalidMessage(format!(
+                        "兼容模式明文与密文不一致: {}",
+                        name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let encrypted_msg = evaluate_xpath(&doc, "/xml/Encrypt")?.string();
+    /// 解密已解析消息体中的Encrypt字段
+    fn decrypt_envelope(
+        config: &WechatConfig,
+        token: &String,
+        verify_info: &VerifyInfo,
+        envelope: &XmlNode,
+    ) -> Result<String, WechatEncryptError> {
+        let encrypted_msg = envelope
+            .child("Encrypt")
+            .map(XmlNode::text)
+            .unwrap_or_default();
         verify_message(token, verify_info, &encrypted_msg)?;
         match &config.key {
-            Some(key) => {
-                let prp = PrpCrypto::new(key);
-                let msg = prp.decrypt(&encrypted_msg, &config.app_id)?;
-                Ok(msg)
-            }
+            Some(_) => decrypt_with_keys(config, &encrypted_msg),
             None => Ok(encrypted_msg),
         }
     }
@@ -1711,7 +3101,7 @@ mod crypt_tests {
     #[test]
     fn test_prpcrypto_encrypt() -> Result<(), WechatEncryptError> {
         let encoding_aes_key = "kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=";
-        let key = base64::decode(encoding_aes_key)?;
+        let key = base64::decode(encoding_aes_key).unwrap();
         let prp = PrpCrypto::new(&key);
         let encrypted = prp.encrypt("test", "rust")?;
         assert_eq!("9s4gMv99m88kKTh/H8IdkNiFGeG9pd7vNWl50fGRWXY=", &encrypted);
@@ -1805,10 +3195,10 @@ mod crypt_tests {
             <CreateTime>1411525903</CreateTime>\n\
             </xml>";
         let expected = "<xml>\
-            <Encrypt>9s4gMv99m88kKTh/H8IdkOiMg6bisoy3ypwy9H4hvSPe9nsGaqyw5hhSjdYbcrKk+j3nba4HMOTzHrluLBYqxgNcBqGsL8GqxlhZgURnAtObvesEl5nZ+uBE8bviY0LWke8Zy9V/QYKxNV2FqllNXcfmstttyIkMKCCmVbCFM2JTF5wY0nFhHZSjPUL2Q1qvSUCUld+/WIXrx0oyKQmpB6o8NRrrNrsDf03oxI1p9FxUgMnwKKZeOA/uu+2IEvEBtb7muXsVbwbgX05UPPJvFurDXafG0RQyPR+mf1nDnAtQmmNOuiR5MIkdQ39xn1vWwi1O5oazPoQJz0nTYjxxEE8kv3kFxtAGVRe3ypD3WeK2XeFYFMNMpatF9XiKzHo3</Encrypt>\
-            <MsgSignature>407518b7649e86ef23978113f92d27afa9296533</MsgSignature>\
+            <Encrypt><![CDATA[9s4gMv99m88kKTh/H8IdkOiMg6bisoy3ypwy9H4hvSPe9nsGaqyw5hhSjdYbcrKk+j3nba4HMOTzHrluLBYqxgNcBqGsL8GqxlhZgURnAtObvesEl5nZ+uBE8bviY0LWke8Zy9V/QYKxNV2FqllNXcfmstttyIkMKCCmVbCFM2JTF5wY0nFhHZSjPUL2Q1qvSUCUld+/WIXrx0oyKQmpB6o8NRrrNrsDf03oxI1p9FxUgMnwKKZeOA/uu+2IEvEBtb7muXsVbwbgX05UPPJvFurDXafG0RQyPR+mf1nDnAtQmmNOuiR5MIkdQ39xn1vWwi1O5oazPoQJz0nTYjxxEE8kv3kFxtAGVRe3ypD3WeK2XeFYFMNMpatF9XiKzHo3]]></Encrypt>\
+            <MsgSignature><![CDATA[407518b7649e86ef23978113f92d27afa9296533]]></MsgSignature>\
             <TimeStamp>1411525903</TimeStamp>\
-            <Nonce>461056294</Nonce>\
+            <Nonce><![CDATA[461056294]]></Nonce>\
             </xml>";
         let config = WechatConfig::new(
             WechatConfig::decode_aes_key(&"kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=".into())
@@ -1851,8 +3241,12 @@ mod crypt_tests {
             msg_signature: Some("74d92dfeb87ba7c714f89d98870ae5eb62dff26d".into()),
             encrypt_type: Some("aes".into()),
         };
-        let decrypted = decrypt_message(&config, &"123456".into(), &verify_info, xml).unwrap();
+        let token = "123456".to_string();
+        let decrypted =
+            decrypt_message(&config, &token, &verify_info, xml, DEFAULT_MAX_BODY_SIZE).unwrap();
         assert_eq!(expected, &decrypted);
+        // 超过消息体大小限制
+        assert!(decrypt_message(&config, &token, &verify_info, xml, xml.len() - 1).is_err());
     }
 
     #[test]
@@ -1886,8 +3280,295 @@ mod crypt_tests {
             "".into(),
         );
         // openid=
+        let token = "testtoken123456".to_string();
         let decrypted =
-            decrypt_message(&config, &"testtoken123456".into(), &verify_info, xml).unwrap();
+            decrypt_message(&config, &token, &verify_info, xml, DEFAULT_MAX_BODY_SIZE).unwrap();
         assert_eq!(expected, &decrypted);
     }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let key = base64::decode("kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=").unwrap();
+        // key长度错误
+        let encrypted = "9s4gMv99m88kKTh/H8IdkNiFGeG9pd7vNWl50fGRWXY=";
+        assert!(decrypt(&key[..16], "rust", encrypted).is_err());
+        // 非base64属于消息错误
+        let invalid = decrypt(&key, "rust", "!!!").unwrap_err();
+        assert!(matches!(invalid, WechatEncryptError::InvalidMessage(_)));
+        assert_eq!(http::StatusCode::BAD_REQUEST, invalid.http_status());
+        // 填充无效属于消息错误
+        let padding = decrypt(&key, "rust", &base64::encode(&[0u8; 16]));
+        assert!(matches!(padding, Err(WechatEncryptError::InvalidMessage(_))));
+        // 解密后长度不足20字节
+        let short = base64::encode(
+            openssl::symm::encrypt(
+                openssl::symm::Cipher::aes_256_cbc(),
+                &key,
+                Some(&key[..16]),
+                b"0123456789",
+            )
+            .unwrap(),
+        );
+        assert!(decrypt(&key, "rust", &short).is_err());
+        // 内容长度超出
+        let mut plain = b"1234567890123456".to_vec();
+        plain.extend(&[0xff, 0xff, 0xff, 0xff]);
+        plain.extend(b"test");
+        let overflow = base64::encode(
+            openssl::symm::encrypt(
+                openssl::symm::Cipher::aes_256_cbc(),
+                &key,
+                Some(&key[..16]),
+                &plain,
+            )
+            .unwrap(),
+        );
+        assert!(decrypt(&key, "rust", &overflow).is_err());
+        // AppID不匹配
+        assert!(decrypt(&key, "other", encrypted).is_err());
+    }
+
+    #[test]
+    fn test_decode_aes_key_invalid() {
+        assert!(WechatConfig::decode_aes_key(&"a2V5".into()).is_err());
+        assert!(matches!(
+            WechatConfig::decode_aes_key(&"!!!".into()),
+            Err(WechatEncryptError::InvalidConfig)
+        ));
+    }
+
+    #[test]
+    fn test_parse_envelope_max_size() {
+        let xml = "<xml><Encrypt><![CDATA[abc]]></Encrypt></xml>";
+        assert!(parse_envelope(xml, xml.len()).is_ok());
+        assert!(parse_envelope(xml, xml.len() - 1).is_err());
+    }
+
+    /// 随机输入不能panic
+    #[test]
+    fn test_random_input() {
+        use rand::{Rng, SeedableRng};
+        let key = base64::decode("kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=").unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let pieces = [
+            "<xml>", "</xml>", "<a>", "</a>", "<![CDATA[", "]]>", "&amp;", "&#x", ";", "<!--",
+            "-->", "<?", "?>", "<MsgType>", "</MsgType>", "event", "<Event>", "</Event>", "1",
+            "/", " ", "<", ">", "&", "\"", "中",
+        ];
+        for _ in 0..2000 {
+            let len = rng.gen_range(0, 20);
+            let xml: String = (0..len)
+                .map(|_| pieces[rng.gen_range(0, pieces.len())])
+                .collect();
+            let _ = parse_envelope(&xml, DEFAULT_MAX_BODY_SIZE);
+            let _ = from_xml(&xml);
+
+            let bytes: Vec<u8> = (0..rng.gen_range(0, 128)).map(|_| rng.gen()).collect();
+            let _ = decrypt(&key, "rust", &base64::encode(&bytes));
+        }
+    }
+
+    fn get_verify_info(timestamp: i64, msg_signature: Option<String>) -> VerifyInfo {
+        VerifyInfo {
+            signature: "signature".into(),
+            timestamp,
+            nonce: "nonce".into(),
+            msg_signature,
+            encrypt_type: Some("aes".into()),
+        }
+    }
+
+    #[test]
+    fn test_strict_verify_header() {
+        let strict = StrictVerify::new(std::time::Duration::from_secs(300));
+        let now = 1592558813;
+        // 加密模式缺少msg_signature
+        let info = get_verify_info(now, None);
+        assert!(strict.verify_header(&info, now).is_err());
+        let info = get_verify_info(now, Some("s".into()));
+        assert!(strict.verify_header(&info, now).is_ok());
+        // 明文模式不需要msg_signature
+        let plain = VerifyInfo {
+            encrypt_type: None,
+            ..get_verify_info(now, None)
+        };
+        assert!(strict.verify_header(&plain, now).is_ok());
+        // 时间窗口
+        let cases = [
+            (now - 300, true),
+            (now + 300, true),
+            (now - 301, false),
+            (now + 301, false),
+        ];
+        for (timestamp, ok) in &cases {
+            let info = get_verify_info(*timestamp, Some("s".into()));
+            assert_eq!(*ok, strict.verify_header(&info, now).is_ok(), "{}", timestamp);
+        }
+        // 溢出
+        for (timestamp, now) in &[(i64::MIN, i64::MAX), (i64::MAX, i64::MIN), (i64::MIN, now)] {
+            let info = get_verify_info(*timestamp, Some("s".into()));
+            assert!(strict.verify_header(&info, *now).is_err(), "{}", timestamp);
+        }
+        let strict = StrictVerify::new(std::time::Duration::from_secs(u64::MAX));
+        let info = get_verify_info(i64::MIN, Some("s".into()));
+        assert!(strict.verify_header(&info, i64::MAX).is_err());
+        assert!(strict.verify_header(&info, -1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_strict_verify_nonce() -> Result<(), WechatError> {
+        use crate::core::nonce_cache::memory::MemoryNonceCache;
+        let strict = StrictVerify::default().with_nonce_cache(Box::new(MemoryNonceCache::new()));
+        let context = SaasContext::new(1);
+        let info = get_verify_info(1592558813, None);
+        strict.verify_nonce(&context, &info).await?;
+        assert!(strict.verify_nonce(&context, &info).await.is_err());
+        // 时间窗口过大时不会溢出
+        let strict = StrictVerify::new(std::time::Duration::MAX)
+            .with_nonce_cache(Box::new(MemoryNonceCache::new()));
+        strict.verify_nonce(&context, &info).await?;
+        assert!(strict.verify_nonce(&context, &info).await.is_err());
+        // 未配置缓存时不检查
+        let strict = StrictVerify::default();
+        strict.verify_nonce(&context, &info).await?;
+        strict.verify_nonce(&context, &info).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_reply() -> Result<(), WechatError> {
+        let config = WechatConfig::new(
+            WechatConfig::decode_aes_key(&"kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=".into())?,
+            "wx49f0ab532d5d035a".into(),
+            "".into(),
+        );
+        let token = "123456".to_string();
+        let reply = "<xml><Content><![CDATA[test]]></Content></xml>";
+        let encrypted = encrypt_reply(&config, &token, reply)?;
+        let envelope = crate::xml::parse(&encrypted)?;
+        let field = |name: &str| envelope.child(name).map(XmlNode::text).unwrap_or_default();
+        let timestamp: i64 = field("TimeStamp").parse().unwrap();
+        assert!((chrono::Utc::now().timestamp() - timestamp).abs() < 5);
+        assert!(!field("Nonce").is_empty());
+
+        let verify_info = VerifyInfo {
+            signature: get_signature(&token, timestamp, &field("Nonce"), "")?,
+            timestamp,
+            nonce: field("Nonce"),
+            msg_signature: Some(field("MsgSignature")),
+            encrypt_type: Some("aes".into()),
+        };
+        assert_eq!(
+            reply,
+            decrypt_message(&config, &token, &verify_info, &encrypted, 1024)?
+        );
+        // 每次回复使用新的nonce
+        let encrypted2 = encrypt_reply(&config, &token, reply)?;
+        let nonce2 = crate::xml::parse(&encrypted2)?
+            .child("Nonce")
+            .map(XmlNode::text);
+        assert_ne!(Some(field("Nonce")), nonce2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compatible_mode() -> Result<(), WechatError> {
+        let encrypt = "<Encrypt><![CDATA[YsZjOA0RLvxvjZ8Xq38yC2YZgxw20MS/UCS13eiWaznQawh8JHGyonUKFLKC9cSgpxDpP9IHQ5+Vl9exTBSMgCzI19P1z0YpByB5rLfHMQWsyvm/H5uwH16lf2BgooZZRoEyzTDLXQFqjwiUSP7Iw8IzdtMp1Ux3f9glW5D/I5H3sGmxbmxf0N/2I5DKKWAlQZSfEnzouKcpyD9DJeY8FfKcQAlJFs/FKGs7g6UdXlxHwmgK3+ZOf7+FL8nFVOQzVpCLuOfRJnMQ//+Bp8aXoTbLiaW6haYuKf7CpPihQJ9/XFTgirBRB2V3jNFisVzwL9XeJ6r/H8Pt8GyGeQ6Hdpl4RVJY4gOTYvZpNvcz0WsKtJkh04tC7zj6tO8/cR4wsJxzTvDpMtBSpukNcFuR7BQtKKTlAkYulnoj8dAfHFc=]]></Encrypt>";
+        let plaintext = |content: &str| {
+            format!(
+                "<xml><ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>\
+                <FromUserName><![CDATA[oseZYwXU64cWTJuTV4UkS-DTu9OQ]]></FromUserName>\
+                <CreateTime>1592558813</CreateTime><MsgType><![CDATA[text]]></MsgType>\
+                <Content><![CDATA[{}]]></Content><MsgId>22799962246505739</MsgId>{}</xml>",
+                content, encrypt
+            )
+        };
+        let verify_info = VerifyInfo {
+            signature: "3af8f544dbc8c4e096c492984cbd1175c86a95c1".into(),
+            timestamp: 1592558813,
+            nonce: "1681763772".into(),
+            msg_signature: Some("060c0da85ba7c4c2bf2a69716debec5858145826".into()),
+            encrypt_type: Some("aes".into()),
+        };
+        let token = "testtoken123456".to_string();
+        let config = WechatConfig::new(
+            WechatConfig::decode_aes_key(&"znpfGFxELvUSxh0Gx4rJenvVQRrAhdTsioG08XR4z3S=".into())?,
+            "wx11853b05910e1b6b".into(),
+            "".into(),
+        );
+
+        let ParsedMessage { mode, node, .. } =
+            parse_message(&config, &token, &verify_info, &plaintext("好的"), 1024)?;
+        assert_eq!(EncryptMode::Compatible, mode);
+        assert!(mode.reply_encrypted(&config));
+        assert!(node.child("Encrypt").is_none());
+        assert_eq!(Some("好的".to_string()), node.child("Content").map(XmlNode::text));
+
+        // 明文被篡改
+        assert!(parse_message(&config, &token, &verify_info, &plaintext("坏的"), 1024).is_err());
+        assert!(decrypt_message(&config, &token, &verify_info, &plaintext("坏的"), 1024).is_err());
+
+        // 未配置EncodingAESKey时使用明文部分, 回复不加密
+        let no_key = WechatConfig::new(None, "wx11853b05910e1b6b".into(), "".into());
+        let ParsedMessage { mode, node, .. } =
+            parse_message(&no_key, &token, &verify_info, &plaintext("好的"), 1024)?;
+        assert_eq!(EncryptMode::Compatible, mode);
+        assert!(!mode.reply_encrypted(&no_key));
+        assert!(node.child("Encrypt").is_none());
+        assert_eq!(Some("好的".to_string()), node.child("Content").map(XmlNode::text));
+        // 密文签名仍需校验
+        let invalid = VerifyInfo {
+            msg_signature: Some("invalid".into()),
+            ..verify_info
+        };
+        assert!(parse_message(&no_key, &token, &invalid, &plaintext("好的"), 1024).is_err());
+
+        // 只有Encrypt时为安全模式
+        let safe = format!("<xml>{}</xml>", encrypt);
+        assert_eq!(
+            EncryptMode::Safe,
+            invalid.encrypt_mode(&crate::xml::parse(&safe)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rotation() -> Result<(), WechatError> {
+        let xml = r#"<xml>
+        <ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>
+        <Encrypt><![CDATA[YsZjOA0RLvxvjZ8Xq38yC2YZgxw20MS/UCS13eiWaznQawh8JHGyonUKFLKC9cSgpxDpP9IHQ5+Vl9exTBSMgCzI19P1z0YpByB5rLfHMQWsyvm/H5uwH16lf2BgooZZRoEyzTDLXQFqjwiUSP7Iw8IzdtMp1Ux3f9glW5D/I5H3sGmxbmxf0N/2I5DKKWAlQZSfEnzouKcpyD9DJeY8FfKcQAlJFs/FKGs7g6UdXlxHwmgK3+ZOf7+FL8nFVOQzVpCLuOfRJnMQ//+Bp8aXoTbLiaW6haYuKf7CpPihQJ9/XFTgirBRB2V3jNFisVzwL9XeJ6r/H8Pt8GyGeQ6Hdpl4RVJY4gOTYvZpNvcz0WsKtJkh04tC7zj6tO8/cR4wsJxzTvDpMtBSpukNcFuR7BQtKKTlAkYulnoj8dAfHFc=]]></Encrypt>
+    </xml>"#;
+        let verify_info = VerifyInfo {
+            signature: "3af8f544dbc8c4e096c492984cbd1175c86a95c1".into(),
+            timestamp: 1592558813,
+            nonce: "1681763772".into(),
+            msg_signature: Some("060c0da85ba7c4c2bf2a69716debec5858145826".into()),
+            encrypt_type: Some("aes".into()),
+        };
+        let token = "testtoken123456".to_string();
+        let old_key =
+            WechatConfig::decode_aes_key(&"znpfGFxELvUSxh0Gx4rJenvVQRrAhdTsioG08XR4z3S=".into())?;
+        let new_key =
+            WechatConfig::decode_aes_key(&"kWxPEV2UEDyxWpmPdKC3F4dgPDmOvfKX1HGnEUDS1aQ=".into())?;
+        let app_id = "wx11853b05910e1b6b".to_string();
+
+        // 只有新key时无法解密旧key加密的消息
+        let config = WechatConfig::new(new_key.clone(), app_id.clone(), "".into());
+        assert!(decrypt_message(&config, &token, &verify_info, xml, 1024).is_err());
+
+        let config = config.with_old_keys(old_key.clone().into_iter().collect());
+        assert_eq!(2, config.keys().count());
+        let msg = decrypt_message(&config, &token, &verify_info, xml, 1024)?;
+        assert!(msg.contains("<Content><![CDATA[好的]]></Content>"));
+
+        // 回复使用新key加密
+        let reply = encrypt_reply(&config, &token, "<xml></xml>")?;
+        let encrypted = crate::xml::parse(&reply)?
+            .child("Encrypt")
+            .map(XmlNode::text)
+            .unwrap_or_default();
+        assert!(decrypt(&new_key.unwrap(), &app_id, &encrypted).is_ok());
+        assert!(decrypt(&old_key.unwrap(), &app_id, &encrypted).is_err());
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::SaasContext;
+use crate::{SaasContext, WechatConfig};
 use crate::{NoError, WechatEncryptError, WechatError, WechatToken};
 use crate::{Wechat, WechatResult};
 use lazy_static::*;
@@ -10,7 +10,7 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 
 lazy_static! {
-    static ref WECHAT_API: Url = Url::parse("https://api.weixin.qq.com/").unwrap();
+    pub(crate) static ref WECHAT_API: Url = Url::parse("https://api.weixin.qq.com/").unwrap();
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -34,6 +34,15 @@ impl<T: Sized> ApiResult<T> {
     }
 }
 
+/// 微信接口的响应格式错误
+pub(crate) fn invalid_response(e: serde_json::Error) -> WechatError {
+    WechatEncryptError::ApiRequestError {
+        msg: format!("响应格式错误: {}", e),
+        source: Box::new(e),
+    }
+    .into()
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 struct GetAccessTokenResp {
     /// 获取到的凭证
@@ -42,13 +51,22 @@ struct GetAccessTokenResp {
     pub expires_in: i32,
 }
 
+async fn request_access_token(url: Url) -> WechatResult<GetAccessTokenResp> {
+    get(url)
+        .await?
+        .json::<ApiResult<GetAccessTokenResp>>()
+        .await?
+        .get_result()
+}
+
 pub fn get_url_with_token(
+    api_base: &Url,
     url: &str,
     token: Option<String>,
     query: Option<HashMap<String, String>>,
 ) -> WechatResult<Url> {
     let mut u: Url = Url::options()
-        .base_url(Some(&WECHAT_API))
+        .base_url(Some(api_base))
         .parse(url)
         .map_err(|e| WechatError::ParseError(format!("{:?}", e)))?;
     {
@@ -64,9 +82,6 @@ pub fn get_url_with_token(
     }
     Ok(u)
 }
-pub fn get_url(url: &str, query: Option<HashMap<String, String>>) -> WechatResult<Url> {
-    get_url_with_token(url, None, query)
-}
 
 impl Wechat {
     /// 获取token
@@ -81,35 +96,47 @@ impl Wechat {
             .token_provider
             .lock_token_resolver(self, context)
             .await?;
+        let result = self.resolve_access_token(context, config).await;
+        // release lock, 获取失败时同样需要释放
+        let unlocked = self
+            .token_provider
+            .unlock_token_resolver(self, context, resolver)
+            .await;
+        let token = result?;
+        unlocked?;
+        Ok(token)
+    }
+
+    /// 持有锁时获取token, 并保存到token_provider
+    async fn resolve_access_token(
+        &self,
+        context: &SaasContext,
+        config: WechatConfig,
+    ) -> WechatResult<WechatToken> {
         // double check
         if let Some(token) = self.token_provider.get_token(self, context).await? {
             return Ok(token);
         }
-        let url = get_url(
+        let url = get_url_with_token(
+            &self.api_base,
             "cgi-bin/token",
+            None,
             Some(hashmap! {
                 "grant_type".into() => "client_credential".into(),
                 "appid".into() => config.app_id,
                 "secret".into() => config.app_secret,
             }),
         )?;
-        let resp = get(url)
-            .await?
-            .json::<ApiResult<GetAccessTokenResp>>()
-            .await?
-            .get_result()?;
+        // 无法获取token时回调返回503
+        let resp = request_access_token(url).await.map_err(|e| {
+            WechatEncryptError::TokenError(format!("获取access_token失败: {:?}", e))
+        })?;
 
         let token = WechatToken::new_relative(resp.access_token, resp.expires_in);
 
         self.token_provider
             .set_token(self, context, Some(token.clone()))
             .await?;
-
-        // release lock
-        self.token_provider
-            .unlock_token_resolver(self, context, resolver)
-            .await?;
-
         Ok(token)
     }
 
@@ -120,7 +147,7 @@ impl Wechat {
         query: Option<HashMap<String, String>>,
     ) -> WechatResult<Url> {
         let token = self.get_access_token(context).await?;
-        get_url_with_token(url, Some(token.token), query)
+        get_url_with_token(&self.api_base, url, Some(token.token), query)
     }
     pub(crate) async fn api_post<T: Serialize + ?Sized, R: DeserializeOwned>(
         &self,
@@ -131,15 +158,10 @@ impl Wechat {
     ) -> WechatResult<R> {
         let url = self.get_url(context, url, query).await?;
         let client = Client::new();
-        let result = client
-            .post(url)
-            .json(&body)
-            .send()
-            .await?
-            .json::<ApiResult<R>>()
-            .await?
-            .get_result()?;
-        Ok(result)
+        let text = client.post(url).json(&body).send().await?.text().await?;
+        serde_json::from_str::<ApiResult<R>>(&text)
+            .map_err(invalid_response)?
+            .get_result()
     }
     pub(crate) async fn api_get<R: DeserializeOwned>(
         &self,
@@ -148,12 +170,10 @@ impl Wechat {
         query: Option<HashMap<String, String>>,
     ) -> WechatResult<R> {
         let url = self.get_url(context, url, query).await?;
-        let result = reqwest::get(url)
-            .await?
-            .json::<ApiResult<R>>()
-            .await?
-            .get_result()?;
-        Ok(result)
+        let text = reqwest::get(url).await?.text().await?;
+        serde_json::from_str::<ApiResult<R>>(&text)
+            .map_err(invalid_response)?
+            .get_result()
     }
 
     // pub(crate) async fn apt_upload<R: DeserializeOwned>(
@@ -168,6 +188,69 @@ impl Wechat {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::token_provider::memory::MemoryTokenProvider;
+    use crate::token_provider::{TokenProvider, TokenResolveGard};
+    use crate::ConstSaasResolver;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    use std::sync::Arc;
+
+    /// 记录未释放的锁数量
+    struct CountLocks(MemoryTokenProvider, Arc<AtomicIsize>);
+
+    #[async_trait]
+    impl TokenProvider for CountLocks {
+        async fn get_token(
+            &self,
+            wechat: &Wechat,
+            context: &SaasContext,
+        ) -> WechatResult<Option<WechatToken>> {
+            self.0.get_token(wechat, context).await
+        }
+
+        async fn set_token(
+            &self,
+            wechat: &Wechat,
+            context: &SaasContext,
+            token: Option<WechatToken>,
+        ) -> WechatResult<()> {
+            self.0.set_token(wechat, context, token).await
+        }
+
+        async fn lock_token_resolver(
+            &self,
+            wechat: &Wechat,
+            context: &SaasContext,
+        ) -> WechatResult<TokenResolveGard> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.lock_token_resolver(wechat, context).await
+        }
+
+        async fn unlock_token_resolver(
+            &self,
+            wechat: &Wechat,
+            context: &SaasContext,
+            gard: TokenResolveGard,
+        ) -> WechatResult<()> {
+            self.1.fetch_sub(1, Ordering::SeqCst);
+            self.0.unlock_token_resolver(wechat, context, gard).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unlock_on_error() {
+        let locked = Arc::new(AtomicIsize::new(0));
+        let mut wechat = Wechat::new(
+            Box::new(ConstSaasResolver::new(WechatConfig::default())),
+            Box::new(CountLocks(MemoryTokenProvider::new(), locked.clone())),
+        );
+        wechat.set_api_base(Url::parse("http://127.0.0.1:1/").unwrap());
+        for _ in 0..2 {
+            let context = SaasContext::new(1);
+            assert!(wechat.get_access_token(&context).await.is_err());
+            assert_eq!(0, locked.load(Ordering::SeqCst));
+        }
+    }
 
     #[test]
     fn test_apiresult_serde_error() {
@@ -182,6 +265,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_invalid_response() {
+        let e = serde_json::from_str::<serde_json::Value>("<html>").unwrap_err();
+        assert_eq!(
+            http::StatusCode::BAD_GATEWAY,
+            invalid_response(e).http_status()
+        );
+    }
+
     #[test]
     fn test_apiresult_serde_ok() {
         let json = r#"{"access_token":"ACCESS_TOKEN","expires_in":7200}"#;
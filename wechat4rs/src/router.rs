@@ -0,0 +1,367 @@
+//! 声明式的回调消息路由
+//!
+//! ```ignore
+//! let router = Router::new()
+//!     .route(Route::new(Box::new(Help)).msg_type("text").text_prefix("帮助"))
+//!     .route(Route::new(Box::new(Coupon)).event("CLICK").event_key("coupon"))
+//!     .route(Route::new(Box::new(Audit)).priority(10).propagate())
+//!     .fallback(Box::new(Default));
+//! wechat.registry_callback(Box::new(router));
+//! ```
+use crate::core::errors::WechatError;
+use crate::message::CallbackMessage;
+use crate::wechat::{CallbackContext, HandlerOutcome, Wechat, WechatCallBackHandler};
+use async_trait::async_trait;
+use log::debug;
+use regex::Regex;
+
+/// 自定义匹配条件, 可通过`wechat.extensions()`读取应用数据
+pub type MatchFn = Box<dyn Fn(&Wechat, &CallbackContext, &CallbackMessage) -> bool + Send + Sync>;
+
+/// 路由匹配条件
+pub enum Matcher {
+    /// 消息类型(MsgType), 如text、event
+    MsgType(String),
+    /// 事件类型(Event), 不区分大小写
+    Event(String),
+    /// 事件KEY值完全相等, 如菜单KEY、二维码场景值
+    EventKey(String),
+    /// 文本消息内容完全相等
+    Text(String),
+    /// 文本消息内容前缀
+    TextPrefix(String),
+    /// 文本消息内容匹配正则
+    TextRegex(Regex),
+    /// 消息所属的公众号
+    Tenant(u64),
+    /// 自定义条件
+    Custom(MatchFn),
+}
+
+impl Matcher {
+    pub fn matches(
+        &self,
+        wechat: &Wechat,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> bool {
+        let text = match message {
+            CallbackMessage::Text { content, .. } => Some(content.as_str()),
+            _ => None,
+        };
+        match self {
+            Matcher::MsgType(msg_type) => message.msg_type() == msg_type,
+            Matcher::Event(event) => message
+                .event()
+                .is_some_and(|e| e.eq_ignore_ascii_case(event)),
+            Matcher::EventKey(key) => message.event_key() == Some(key.as_str()),
+            Matcher::Text(expected) => text == Some(expected.as_str()),
+            Matcher::TextPrefix(prefix) => text.is_some_and(|t| t.starts_with(prefix.as_str())),
+            Matcher::TextRegex(regex) => text.is_some_and(|t| regex.is_match(t)),
+            Matcher::Tenant(id) => context.saas_context.id == *id,
+            Matcher::Custom(f) => f(wechat, context, message),
+        }
+    }
+}
+
+/// 一条路由: 所有条件都满足时调用handler
+pub struct Route {
+    matchers: Vec<Matcher>,
+    handler: Box<dyn WechatCallBackHandler>,
+    priority: i32,
+    stop: bool,
+}
+
+impl Route {
+    /// 默认优先级为0, 匹配后不再执行后续路由
+    pub fn new(handler: Box<dyn WechatCallBackHandler>) -> Self {
+        Route {
+            matchers: vec![],
+            handler,
+            priority: 0,
+            stop: true,
+        }
+    }
+
+    pub fn matcher(mut self, matcher: Matcher) -> Self {
+        self.matchers.push(matcher);
+        self
+    }
+
+    pub fn msg_type(self, msg_type: &str) -> Self {
+        self.matcher(Matcher::MsgType(msg_type.into()))
+    }
+
+    pub fn event(self, event: &str) -> Self {
+        self.matcher(Matcher::Event(event.into()))
+    }
+
+    pub fn event_key(self, event_key: &str) -> Self {
+        self.matcher(Matcher::EventKey(event_key.into()))
+    }
+
+    pub fn text(self, text: &str) -> Self {
+        self.matcher(Matcher::Text(text.into()))
+    }
+
+    pub fn text_prefix(self, prefix: &str) -> Self {
+        self.matcher(Matcher::TextPrefix(prefix.into()))
+    }
+
+    pub fn text_regex(self, regex: Regex) -> Self {
+        self.matcher(Matcher::TextRegex(regex))
+    }
+
+    pub fn tenant(self, saas_id: u64) -> Self {
+        self.matcher(Matcher::Tenant(saas_id))
+    }
+
+    pub fn when<F>(self, f: F) -> Self
+    where
+        F: Fn(&Wechat, &CallbackContext, &CallbackMessage) -> bool + Send + Sync + 'static,
+    {
+        self.matcher(Matcher::Custom(Box::new(f)))
+    }
+
+    /// 优先级高的先执行, 相同优先级按注册顺序
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// 匹配后继续执行后续路由, 后续handler的回复覆盖之前的回复
+    pub fn propagate(mut self) -> Self {
+        self.stop = false;
+        self
+    }
+
+    pub fn matches(
+        &self,
+        wechat: &Wechat,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> bool {
+        self.matchers
+            .iter()
+            .all(|m| m.matches(wechat, context, message))
+    }
+}
+
+/// 按条件分发回调消息, 本身也是一个`WechatCallBackHandler`
+///
+/// 没有路由匹配(或匹配的路由都设置了propagate)时依次执行fallback,
+/// handler返回的结果设置了stop时不再执行后续路由和fallback
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallbacks: Vec<Box<dyn WechatCallBackHandler>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, route: Route) -> Self {
+        let index = self
+            .routes
+            .iter()
+            .position(|r| r.priority < route.priority)
+            .unwrap_or(self.routes.len());
+        self.routes.insert(index, route);
+        self
+    }
+
+    pub fn fallback(mut self, handler: Box<dyn WechatCallBackHandler>) -> Self {
+        self.fallbacks.push(handler);
+        self
+    }
+}
+
+#[async_trait]
+impl WechatCallBackHandler for Router {
+    async fn handler_callback(
+        &self,
+        wechat: &Wechat,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> Result<HandlerOutcome, WechatError> {
+        let mut outcome = HandlerOutcome::pass();
+        for (index, route) in self.routes.iter().enumerate() {
+            if !route.matches(wechat, context, message) {
+                continue;
+            }
+            debug!("route[{}] matched: {:?}", index, message.info().msg_id);
+            outcome.merge(
+                route
+                    .handler
+                    .handler_callback(wechat, context, message)
+                    .await?,
+            );
+            if route.stop || outcome.stop {
+                return Ok(outcome);
+            }
+        }
+        for handler in self.fallbacks.iter() {
+            outcome.merge(handler.handler_callback(wechat, context, message).await?);
+            if outcome.stop {
+                break;
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{crypt::EncryptMode, from_xml, MessageInfo, ReplyMessage};
+    use crate::wechat::test::get_wechat;
+    use crate::{Extensions, SaasContext};
+
+    /// 记录调用顺序, 回复已调用的handler
+    struct Append(&'static str);
+
+    #[async_trait]
+    impl WechatCallBackHandler for Append {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            let mut called = context.extensions.get::<Vec<&str>>().unwrap_or_default();
+            called.push(self.0);
+            context.extensions.insert(called.clone());
+            Ok(HandlerOutcome::reply(ReplyMessage::Text {
+                info: MessageInfo::default(),
+                content: called.join(","),
+            }))
+        }
+    }
+
+    fn get_context(saas_id: u64) -> CallbackContext {
+        CallbackContext {
+            saas_context: SaasContext::new(saas_id),
+            app_id: "appid".into(),
+            open_id: "openid".into(),
+            verify_info: crate::wechat::test::get_encrypted_verify_info(),
+            raw_xml: "".into(),
+            decrypted_xml: "".into(),
+            encrypt_mode: EncryptMode::Plaintext,
+            msg_key: "msg:1".into(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    fn text(content: &str) -> CallbackMessage {
+        from_xml(&format!(
+            "<xml><MsgType>text</MsgType><Content>{}</Content></xml>",
+            content
+        ))
+        .unwrap()
+    }
+
+    fn click(key: &str) -> CallbackMessage {
+        from_xml(&format!(
+            "<xml><MsgType>event</MsgType><Event>CLICK</Event><EventKey>{}</EventKey></xml>",
+            key
+        ))
+        .unwrap()
+    }
+
+    async fn dispatch(
+        router: &Router,
+        saas_id: u64,
+        message: &CallbackMessage,
+    ) -> Result<Option<String>, WechatError> {
+        let wechat = get_wechat().await;
+        // 应用数据: vip用户
+        wechat.extensions().insert(vec!["vip".to_string()]);
+        let result = router
+            .handler_callback(&wechat, &get_context(saas_id), message)
+            .await?;
+        Ok(result.reply.map(|reply| match reply {
+            ReplyMessage::Text { content, .. } => content,
+            _ => panic!("should be text reply"),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_matchers() -> Result<(), WechatError> {
+        let router = Router::new()
+            .route(Route::new(Box::new(Append("hello"))).text("hello"))
+            .route(Route::new(Box::new(Append("help"))).text_prefix("帮助"))
+            .route(
+                Route::new(Box::new(Append("order"))).text_regex(Regex::new(r"^订单\d+$").unwrap()),
+            )
+            .route(
+                Route::new(Box::new(Append("coupon")))
+                    .event("click")
+                    .event_key("coupon"),
+            )
+            .route(
+                Route::new(Box::new(Append("tenant2")))
+                    .msg_type("event")
+                    .tenant(2),
+            );
+        assert_eq!(
+            Some("hello".into()),
+            dispatch(&router, 1, &text("hello")).await?
+        );
+        assert_eq!(
+            Some("help".into()),
+            dispatch(&router, 1, &text("帮助 菜单")).await?
+        );
+        assert_eq!(
+            Some("order".into()),
+            dispatch(&router, 1, &text("订单123")).await?
+        );
+        assert_eq!(None, dispatch(&router, 1, &text("订单abc")).await?);
+        assert_eq!(
+            Some("coupon".into()),
+            dispatch(&router, 1, &click("coupon")).await?
+        );
+        assert_eq!(None, dispatch(&router, 1, &click("other")).await?);
+        assert_eq!(
+            Some("tenant2".into()),
+            dispatch(&router, 2, &click("other")).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_order_and_fallback() -> Result<(), WechatError> {
+        let router = Router::new()
+            .route(Route::new(Box::new(Append("text"))).msg_type("text"))
+            .route(
+                Route::new(Box::new(Append("audit")))
+                    .priority(10)
+                    .propagate(),
+            )
+            .route(
+                Route::new(Box::new(Append("custom"))).when(|wechat, _, message| {
+                    let vips = wechat.extensions().get::<Vec<String>>();
+                    vips.unwrap_or_default()
+                        .contains(&message.info().from_user_name)
+                }),
+            )
+            .fallback(Box::new(Append("fallback")));
+        // 高优先级先执行, 继续传递给后续路由
+        assert_eq!(
+            Some("audit,text".into()),
+            dispatch(&router, 1, &text("hi")).await?
+        );
+        // 匹配的路由都未停止时执行fallback
+        assert_eq!(
+            Some("audit,fallback".into()),
+            dispatch(&router, 1, &click("key")).await?
+        );
+        let vip = from_xml("<xml><FromUserName>vip</FromUserName><MsgType>image</MsgType></xml>")?;
+        assert_eq!(
+            Some("audit,custom".into()),
+            dispatch(&router, 1, &vip).await?
+        );
+        Ok(())
+    }
+}
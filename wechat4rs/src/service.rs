@@ -0,0 +1,234 @@
+//! 与web框架无关的回调服务
+//!
+//! 基于`http::Request<Bytes>`/`http::Response<Bytes>`, 接入其它web框架时只需转换请求和响应
+//!
+//! ```ignore
+//! let service = WechatService::new(wechat.into_shared());
+//! let response = service.call(request, &SaasContext::new(saas_id)).await;
+//! ```
+use crate::message::crypt::VerifyInfo;
+use crate::{EchoStrReq, SaasContext, Wechat, WechatError};
+use bytes::Bytes;
+use http::header::{HeaderValue, ALLOW, CONTENT_TYPE};
+use http::request::Parts;
+use http::{Method, Request, Response, StatusCode};
+use log::warn;
+use serde::de::DeserializeOwned;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+pub const APPLICATION_XML: &str = "application/xml; charset=utf-8";
+
+/// 微信回调服务: GET请求为接入验证(echostr), POST请求为消息回调
+#[derive(Clone)]
+pub struct WechatService {
+    wechat: Arc<Wechat>,
+}
+
+impl WechatService {
+    pub fn new(wechat: Arc<Wechat>) -> Self {
+        WechatService { wechat }
+    }
+
+    pub fn wechat(&self) -> &Arc<Wechat> {
+        &self.wechat
+    }
+
+    /// 处理回调请求, 错误时返回对应的状态码, 响应内容不包含错误详情
+    pub async fn call(&self, request: Request<Bytes>, context: &SaasContext) -> Response<Bytes> {
+        handle_request(&self.wechat, request, context).await
+    }
+}
+
+/// 处理回调请求, 供未使用Arc共享Wechat的web框架接入
+pub async fn handle_request(
+    wechat: &Wechat,
+    request: Request<Bytes>,
+    context: &SaasContext,
+) -> Response<Bytes> {
+    let result = match *request.method() {
+        Method::GET => echo(wechat, &request, context).await,
+        Method::POST => callback(wechat, &request, context).await,
+        _ => {
+            let mut response = text_response(StatusCode::METHOD_NOT_ALLOWED, "");
+            let allow = HeaderValue::from_static("GET, POST");
+            response.headers_mut().insert(ALLOW, allow);
+            return response;
+        }
+    };
+    result.unwrap_or_else(|e| error_response(&e))
+}
+
+/// 接入验证, 返回解密后的echostr
+async fn echo(
+    wechat: &Wechat,
+    request: &Request<Bytes>,
+    context: &SaasContext,
+) -> Result<Response<Bytes>, WechatError> {
+    let verify_info = verify_info(request)?;
+    let req: EchoStrReq = parse_query(request.uri().query().unwrap_or_default())?;
+    let echostr = wechat.handle_echo(&verify_info, &req, context).await?;
+    Ok(text_response(StatusCode::OK, echostr))
+}
+
+/// 消息回调, 返回被动回复的XML, 无回复时为空或"success"
+async fn callback(
+    wechat: &Wechat,
+    request: &Request<Bytes>,
+    context: &SaasContext,
+) -> Result<Response<Bytes>, WechatError> {
+    let verify_info = verify_info(request)?;
+    let body = String::from_utf8(request.body().to_vec())?;
+    let reply = wechat.handle_callback(&verify_info, &body, context).await?;
+    if reply.starts_with('<') {
+        Ok(response(StatusCode::OK, APPLICATION_XML, reply))
+    } else {
+        Ok(text_response(StatusCode::OK, reply))
+    }
+}
+
+/// 从请求的查询参数中提取签名信息
+pub fn verify_info<B>(request: &Request<B>) -> Result<VerifyInfo, WechatError> {
+    parse_query(request.uri().query().unwrap_or_default())
+}
+
+/// 从请求头部提取签名信息, 缺少参数或参数无效时为`ParseError`(400)
+impl TryFrom<&Parts> for VerifyInfo {
+    type Error = WechatError;
+
+    fn try_from(parts: &Parts) -> Result<Self, Self::Error> {
+        parse_query(parts.uri.query().unwrap_or_default())
+    }
+}
+
+/// 解析URL查询参数, 如`EchoStrReq`
+pub fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, WechatError> {
+    serde_urlencoded::from_str(query).map_err(|e| WechatError::ParseError(e.to_string()))
+}
+
+/// 错误响应, 只包含状态码的说明, 错误详情记录到日志
+pub fn error_response(error: &WechatError) -> Response<Bytes> {
+    let status = error.http_status();
+    warn!("微信回调处理失败: {} {:?}", status, error);
+    text_response(status, status.canonical_reason().unwrap_or_default())
+}
+
+pub(crate) fn text_response<T: Into<Bytes>>(status: StatusCode, body: T) -> Response<Bytes> {
+    response(status, TEXT_PLAIN, body)
+}
+
+fn response<T: Into<Bytes>>(
+    status: StatusCode,
+    content_type: &'static str,
+    body: T,
+) -> Response<Bytes> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::crypt::get_signature;
+    use crate::wechat::test::{get_plaintext_verify_info, get_wechat};
+    use crate::{CallbackContext, CallbackMessage, HandlerOutcome, WechatCallBackHandler};
+    use async_trait::async_trait;
+
+    const BODY: &str = "<xml><ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>\
+<FromUserName><![CDATA[openid]]></FromUserName><CreateTime>1592558813</CreateTime>\
+<MsgType><![CDATA[text]]></MsgType><Content><![CDATA[hi]]></Content><MsgId>1</MsgId></xml>";
+
+    struct Hello;
+
+    #[async_trait]
+    impl WechatCallBackHandler for Hello {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            Ok(HandlerOutcome::reply(message.reply().text("hello")?))
+        }
+    }
+
+    fn query(verify_info: &VerifyInfo) -> String {
+        format!(
+            "signature={}&timestamp={}&nonce={}",
+            verify_info.signature, verify_info.timestamp, verify_info.nonce
+        )
+    }
+
+    fn request(method: Method, uri: &str, body: &str) -> Request<Bytes> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Bytes::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_service() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(Hello));
+        let service = WechatService::new(wechat.into_shared());
+        let context = SaasContext::new(1);
+        let query = query(&get_plaintext_verify_info());
+
+        // 消息回调
+        let uri = format!("/wechat/1/?{}", query);
+        let response = service
+            .call(request(Method::POST, &uri, BODY), &context)
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(APPLICATION_XML, response.headers()[CONTENT_TYPE]);
+        let reply = String::from_utf8(response.body().to_vec())?;
+        assert!(reply.contains("<Content><![CDATA[hello]]></Content>"));
+
+        // 明文模式的接入验证原样返回echostr
+        let timestamp = 1592558813;
+        let signature = get_signature(&"testtoken123456".into(), timestamp, "nonce", "")?;
+        let uri = format!(
+            "/wechat/1/?signature={}&timestamp={}&nonce=nonce&echostr=echo%20str",
+            signature, timestamp
+        );
+        let response = service.call(request(Method::GET, &uri, ""), &context).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(TEXT_PLAIN, response.headers()[CONTENT_TYPE]);
+        assert_eq!("echo str", response.body());
+
+        // 缺少参数
+        let response = service
+            .call(request(Method::POST, "/wechat/1/", BODY), &context)
+            .await;
+        assert!(response.status().is_client_error());
+        assert!(!String::from_utf8(response.body().to_vec())?.contains("signature"));
+
+        let response = service.call(request(Method::PUT, &uri, ""), &context).await;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+        assert_eq!("GET, POST", response.headers()[ALLOW]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_info_from_parts() {
+        let verify_info = get_plaintext_verify_info();
+        let uri = format!("/wechat/1/?{}", query(&verify_info));
+        let (parts, _) = request(Method::POST, &uri, "").into_parts();
+        let parsed = VerifyInfo::try_from(&parts).unwrap();
+        assert_eq!(verify_info.signature, parsed.signature);
+        assert_eq!(verify_info.timestamp, parsed.timestamp);
+        assert_eq!(None, parsed.msg_signature);
+
+        for uri in &["/wechat/1/", "/wechat/1/?signature=x&timestamp=abc&nonce=n"] {
+            let (parts, _) = request(Method::POST, uri, "").into_parts();
+            let error = VerifyInfo::try_from(&parts).unwrap_err();
+            assert_eq!(StatusCode::BAD_REQUEST, error.http_status());
+        }
+    }
+}
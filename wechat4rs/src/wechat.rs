@@ -1,14 +1,24 @@
-use crate::core::errors::{WechatEncryptError, WechatError};
+use crate::core::dedup::{dedup_key, CallbackDedup, DedupState};
+use crate::core::errors::{SessionError, WechatEncryptError, WechatError};
+use crate::core::recorder::{CallbackRecord, CallbackRecorder};
+use crate::core::session::{SessionManager, UserSession};
 use crate::core::token_provider::TokenProvider;
 use crate::core::*;
+use crate::customservice::KfMessage;
 use crate::message::*;
-use log::info;
+use log::{info, warn};
+use reqwest::Url;
 use serde::Deserialize;
 
-use crate::message::crypt::VerifyInfo;
+use crate::message::crypt::{EncryptMode, StrictVerify, VerifyInfo};
 
 use async_trait::async_trait;
+use std::fmt;
+use std::future::Future;
 use std::marker::{Send, Sync};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
 
 #[allow(unused_variables)]
 #[async_trait]
@@ -17,13 +27,154 @@ pub trait WechatCallBackHandler: Send + Sync {
     async fn handler_callback(
         &self,
         wechat: &Wechat,
-        prev_result: Option<ReplyMessage>,
+        context: &CallbackContext,
         message: &CallbackMessage,
-    ) -> Result<Option<ReplyMessage>, WechatError> {
-        Ok(prev_result)
+    ) -> Result<HandlerOutcome, WechatError> {
+        Ok(HandlerOutcome::pass())
     }
 }
 
+/// 被动回复后在后台执行的任务
+pub type FollowUpTask = Pin<Box<dyn Future<Output = Result<(), WechatError>> + Send>>;
+
+/// 消息处理器的执行结果
+///
+/// 被动回复后, 依次通过客服消息接口发送deferred中的消息, 然后执行tasks;
+/// 发送客服消息需要Wechat通过`into_shared`共享, 否则丢弃deferred
+#[derive(Default)]
+pub struct HandlerOutcome {
+    /// 被动回复, None时保留之前处理器的回复
+    pub reply: Option<ReplyMessage>,
+    /// 不再执行后续处理器
+    pub stop: bool,
+    /// 被动回复后发送给用户的客服消息
+    pub deferred: Vec<KfMessage>,
+    /// 被动回复后在后台执行的任务
+    pub tasks: Vec<FollowUpTask>,
+}
+
+impl HandlerOutcome {
+    /// 不回复, 继续执行后续处理器
+    pub fn pass() -> Self {
+        Self::default()
+    }
+
+    /// 回复消息, 继续执行后续处理器, 后续处理器可以覆盖回复
+    pub fn reply(reply: ReplyMessage) -> Self {
+        HandlerOutcome {
+            reply: Some(reply),
+            ..Self::default()
+        }
+    }
+
+    /// 回复消息, 不再执行后续处理器
+    pub fn reply_and_stop(reply: ReplyMessage) -> Self {
+        Self::reply(reply).and_stop()
+    }
+
+    /// 不再执行后续处理器
+    pub fn and_stop(mut self) -> Self {
+        self.stop = true;
+        self
+    }
+
+    /// 被动回复后发送客服消息, Wechat需通过`into_shared`共享
+    pub fn defer(mut self, message: KfMessage) -> Self {
+        self.deferred.push(message);
+        self
+    }
+
+    /// 被动回复后执行task
+    pub fn then<F>(mut self, task: F) -> Self
+    where
+        F: Future<Output = Result<(), WechatError>> + Send + 'static,
+    {
+        self.tasks.push(Box::pin(task));
+        self
+    }
+
+    /// 合并后续处理器的结果
+    pub fn merge(&mut self, other: HandlerOutcome) {
+        if other.reply.is_some() {
+            self.reply = other.reply;
+        }
+        self.stop |= other.stop;
+        self.deferred.extend(other.deferred);
+        self.tasks.extend(other.tasks);
+    }
+
+    fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty() || !self.tasks.is_empty()
+    }
+}
+
+impl From<Option<ReplyMessage>> for HandlerOutcome {
+    fn from(reply: Option<ReplyMessage>) -> Self {
+        HandlerOutcome {
+            reply,
+            ..Self::default()
+        }
+    }
+}
+
+impl fmt::Debug for HandlerOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerOutcome")
+            .field("reply", &self.reply)
+            .field("stop", &self.stop)
+            .field("deferred", &self.deferred)
+            .field("tasks", &self.tasks.len())
+            .finish()
+    }
+}
+
+/// 消息处理器返回错误时的处理方式
+#[derive(Debug, Clone, Default)]
+pub enum HandlerErrorPolicy {
+    /// 停止执行并返回错误
+    #[default]
+    Abort,
+    /// 忽略错误, 继续执行下一个处理器, 上一个结果保持不变
+    Continue,
+    /// 停止执行, 回复文本消息
+    Fallback(String),
+    /// 停止执行, 不回复
+    Silent,
+}
+
+/// 消息处理器错误的回调, 用于记录日志、告警等
+#[async_trait]
+pub trait CallbackErrorHook: Send + Sync {
+    async fn on_error(
+        &self,
+        wechat: &Wechat,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+        error: &WechatError,
+    );
+}
+
+/// 回调消息的处理上下文
+#[derive(Debug)]
+pub struct CallbackContext {
+    /// 消息所属的公众号
+    pub saas_context: SaasContext,
+    /// 公众号AppID
+    pub app_id: String,
+    /// 发送方的OpenID(FromUserName)
+    pub open_id: String,
+    pub verify_info: VerifyInfo,
+    /// 原始消息体
+    pub raw_xml: String,
+    /// 明文消息XML, 明文模式下与raw_xml相同
+    pub decrypted_xml: String,
+    pub encrypt_mode: EncryptMode,
+    /// 消息的去重key, 见`dedup_key`
+    pub msg_key: String,
+    /// 本次请求内的扩展数据, 可在handler之间传递
+    pub extensions: Extensions,
+}
+
 /// Saas版公众号配置解析器
 /// Saas版本需要自定义实现从数据库或者Redis等地方加载配置的逻辑
 /// 单机版本可用ConstSaasResolver
@@ -60,11 +211,86 @@ impl WechatSaasResolver for ConstSaasResolver {
 
 pub type WechatResult<T> = Result<T, WechatError>;
 
+/// 被动回复的超时配置
+///
+/// 微信5秒内未收到回复时会重试, 并提示用户"该公众号暂时无法提供服务".
+/// 处理器超时后先返回确认, 处理完成后通过客服消息接口发送回复.
+#[derive(Debug, Clone)]
+pub struct ReplyDeadline {
+    /// 处理器的执行时间上限
+    pub timeout: Duration,
+    /// 超时时返回"success", 否则返回空字符串
+    pub ack_success: bool,
+}
+
+impl ReplyDeadline {
+    pub fn new(timeout: Duration) -> Self {
+        ReplyDeadline {
+            timeout,
+            ack_success: true,
+        }
+    }
+
+    /// 超时时返回空字符串
+    pub fn with_empty_ack(mut self) -> Self {
+        self.ack_success = false;
+        self
+    }
+
+    fn ack(&self) -> String {
+        if self.ack_success {
+            "success".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+}
+
+impl Default for ReplyDeadline {
+    /// 4.5秒, 返回"success"
+    fn default() -> Self {
+        Self::new(Duration::from_millis(4500))
+    }
+}
+
+/// 处理器的执行结果
+enum HandlerReply {
+    /// 回复消息的明文XML, 无回复时为空
+    Reply(String),
+    /// 已超时, 回复稍后通过客服消息发送
+    Timeout,
+}
+
 /// 微信公众平台SDK主类
 pub struct Wechat {
     pub saas_resolver: Box<dyn WechatSaasResolver>,
-    pub callback_handlers: Vec<Box<dyn WechatCallBackHandler>>,
+    /// 消息处理器, 共享后仍可注册
+    callback_handlers: RwLock<Vec<Arc<dyn WechatCallBackHandler>>>,
     pub token_provider: Box<dyn TokenProvider>,
+    /// 应用数据, 如数据库连接池、业务服务, 按类型存取
+    extensions: Extensions,
+    /// 回调消息体的最大字节数
+    pub max_body_size: usize,
+    /// 严格校验模式, None为不启用
+    pub strict_verify: Option<StrictVerify>,
+    /// 回调消息去重, None为不启用
+    pub dedup: Option<CallbackDedup>,
+    /// 用户会话, None为不启用
+    pub sessions: Option<SessionManager>,
+    /// 被动回复超时, None为不限制
+    pub reply_deadline: Option<ReplyDeadline>,
+    /// 消息处理器返回错误时的处理方式
+    pub error_policy: HandlerErrorPolicy,
+    /// 消息处理器错误的回调
+    pub error_hook: Option<Box<dyn CallbackErrorHook>>,
+    /// 回调审计记录, None为不记录
+    pub recorder: Option<Box<dyn CallbackRecorder>>,
+    /// 没有回复时返回"success"(默认), 否则返回空字符串, 与`ReplyDeadline`一致
+    pub ack_success: bool,
+    /// 微信接口地址, 默认为https://api.weixin.qq.com/
+    pub api_base: Url,
+    /// 通过into_shared共享后指向自身, 超时后继续执行处理器时使用
+    self_ref: Weak<Wechat>,
 }
 
 impl Wechat {
@@ -74,23 +300,233 @@ impl Wechat {
     ) -> Self {
         Wechat {
             saas_resolver,
-            callback_handlers: Vec::new(),
+            callback_handlers: RwLock::new(Vec::new()),
             token_provider,
+            extensions: Extensions::new(),
+            max_body_size: crate::message::crypt::DEFAULT_MAX_BODY_SIZE,
+            strict_verify: None,
+            dedup: None,
+            sessions: None,
+            reply_deadline: None,
+            error_policy: HandlerErrorPolicy::default(),
+            error_hook: None,
+            recorder: None,
+            ack_success: true,
+            api_base: crate::req_utils::WECHAT_API.clone(),
+            self_ref: Weak::new(),
         }
     }
 
-    /// 注册自定义消息处理回调
-    pub fn registry_callback(&mut self, callback: Box<dyn WechatCallBackHandler>) {
-        self.callback_handlers.push(callback);
+    /// 通过builder创建
+    pub fn builder(
+        saas_resolver: Box<dyn WechatSaasResolver>,
+        token_provider: Box<dyn TokenProvider>,
+    ) -> WechatBuilder {
+        WechatBuilder {
+            wechat: Wechat::new(saas_resolver, token_provider),
+        }
+    }
+
+    /// 转换为Arc共享, 被动回复超时需要在后台继续执行处理器
+    pub fn into_shared(self) -> Arc<Wechat> {
+        Arc::new_cyclic(|self_ref| Wechat {
+            self_ref: self_ref.clone(),
+            ..self
+        })
+    }
+
+    /// 设置被动回复超时, 需通过`into_shared`共享后生效
+    pub fn set_reply_deadline(&mut self, deadline: ReplyDeadline) {
+        self.reply_deadline = Some(deadline);
+    }
+
+    /// 设置消息处理器返回错误时的处理方式
+    pub fn set_error_policy(&mut self, policy: HandlerErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// 注册消息处理器错误的回调
+    pub fn set_error_hook(&mut self, hook: Box<dyn CallbackErrorHook>) {
+        self.error_hook = Some(hook);
+    }
+
+    /// 记录每次回调的消息、回复及耗时
+    pub fn set_recorder(&mut self, recorder: Box<dyn CallbackRecorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// 没有回复时返回"success", 为false时返回空字符串
+    pub fn set_ack_success(&mut self, ack_success: bool) {
+        self.ack_success = ack_success;
+    }
+
+    /// 启用回调消息去重, 微信重试时不再重复执行处理器
+    pub fn set_dedup(&mut self, dedup: CallbackDedup) {
+        self.dedup = Some(dedup);
+    }
+
+    /// 启用用户会话
+    pub fn set_sessions(&mut self, sessions: SessionManager) {
+        self.sessions = Some(sessions);
+    }
+
+    /// 发送方的会话, 未启用时返回`SessionError::Disabled`
+    ///
+    /// 会话记录最后执行update的消息, 微信重试同一消息时不会重复执行update
+    pub fn session(&self, context: &CallbackContext) -> Result<UserSession<'_>, WechatError> {
+        match &self.sessions {
+            Some(sessions) => Ok(sessions.session_for(context)),
+            None => Err(SessionError::Disabled.into()),
+        }
+    }
+
+    /// 启用严格校验模式
+    pub fn set_strict_verify(&mut self, strict_verify: StrictVerify) {
+        self.strict_verify = Some(strict_verify);
+    }
+
+    /// 严格模式下校验消息头
+    fn verify_strict_header(&self, verify_info: &VerifyInfo) -> Result<(), WechatError> {
+        if let Some(strict) = &self.strict_verify {
+            strict.verify_header(verify_info, chrono::Utc::now().timestamp())?;
+        }
+        Ok(())
+    }
+
+    /// 严格模式下检查重放, 需在签名校验通过后调用
+    async fn verify_strict_nonce(
+        &self,
+        verify_info: &VerifyInfo,
+        context: &SaasContext,
+    ) -> Result<(), WechatError> {
+        if let Some(strict) = &self.strict_verify {
+            strict.verify_nonce(context, verify_info).await?;
+        }
+        Ok(())
+    }
+
+    /// 设置微信接口地址, 如通过代理访问或测试时指向mock服务
+    pub fn set_api_base(&mut self, api_base: Url) {
+        self.api_base = api_base;
+    }
+
+    /// 设置回调消息体的最大字节数, 超过时返回错误
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// 注册自定义消息处理回调, 通过Arc共享后仍可注册
+    pub fn registry_callback(&self, callback: Box<dyn WechatCallBackHandler>) {
+        self.callback_handlers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::from(callback));
+    }
+
+    /// 已注册的消息处理器
+    pub fn callback_handlers(&self) -> Vec<Arc<dyn WechatCallBackHandler>> {
+        self.callback_handlers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// 应用数据, 如`wechat.extensions().get::<PgPool>()`
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
     }
 
     /// aes key的解码
     pub fn get_aes_key(key: String) -> Result<Vec<u8>, WechatEncryptError> {
-        let key = base64::decode(&key)?;
+        let key = base64::decode(&key).map_err(|_| WechatEncryptError::InvalidConfig)?;
         Ok(key)
     }
 }
 
+/// Wechat的构建器
+///
+/// ```ignore
+/// let wechat = Wechat::builder(Box::new(resolver), Box::new(token_provider))
+///     .extension(pg_pool)
+///     .callback(Box::new(router))
+///     .build_shared();
+/// ```
+pub struct WechatBuilder {
+    wechat: Wechat,
+}
+
+impl WechatBuilder {
+    /// 保存应用数据, 处理器中通过`wechat.extensions().get::<T>()`获取
+    pub fn extension<T: std::any::Any + Send + Sync>(self, value: T) -> Self {
+        self.wechat.extensions.insert(value);
+        self
+    }
+
+    pub fn callback(self, callback: Box<dyn WechatCallBackHandler>) -> Self {
+        self.wechat.registry_callback(callback);
+        self
+    }
+
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.wechat.set_max_body_size(max_body_size);
+        self
+    }
+
+    pub fn strict_verify(mut self, strict_verify: StrictVerify) -> Self {
+        self.wechat.set_strict_verify(strict_verify);
+        self
+    }
+
+    pub fn dedup(mut self, dedup: CallbackDedup) -> Self {
+        self.wechat.set_dedup(dedup);
+        self
+    }
+
+    pub fn sessions(mut self, sessions: SessionManager) -> Self {
+        self.wechat.set_sessions(sessions);
+        self
+    }
+
+    pub fn reply_deadline(mut self, deadline: ReplyDeadline) -> Self {
+        self.wechat.set_reply_deadline(deadline);
+        self
+    }
+
+    pub fn error_policy(mut self, policy: HandlerErrorPolicy) -> Self {
+        self.wechat.set_error_policy(policy);
+        self
+    }
+
+    pub fn error_hook(mut self, hook: Box<dyn CallbackErrorHook>) -> Self {
+        self.wechat.set_error_hook(hook);
+        self
+    }
+
+    pub fn recorder(mut self, recorder: Box<dyn CallbackRecorder>) -> Self {
+        self.wechat.set_recorder(recorder);
+        self
+    }
+
+    pub fn ack_success(mut self, ack_success: bool) -> Self {
+        self.wechat.set_ack_success(ack_success);
+        self
+    }
+
+    pub fn api_base(mut self, api_base: Url) -> Self {
+        self.wechat.set_api_base(api_base);
+        self
+    }
+
+    pub fn build(self) -> Wechat {
+        self.wechat
+    }
+
+    /// 构建并通过Arc共享
+    pub fn build_shared(self) -> Arc<Wechat> {
+        self.wechat.into_shared()
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct EchoStrReq {
     echostr: String,
@@ -108,36 +544,1259 @@ impl Wechat {
     ) -> Result<String, WechatError> {
         use crate::message::crypt::decrypt_echostr;
         info!("handler echo: {:?}", req);
+        self.verify_strict_header(verify_info)?;
         let config = self.saas_resolver.resolve_config(&self, &context).await?;
         let token = self.get_access_token(&context).await?;
         let msg = decrypt_echostr(&config, &token.token, verify_info, &req.echostr)?;
+        self.verify_strict_nonce(verify_info, context).await?;
         info!("msg:{}", msg);
         Ok(msg)
     }
 
-    /// 处理微信消息回调
+    /// 处理微信消息回调, 配置了recorder时保存回调记录
+    ///
+    /// 被动回复超时的消息在后台处理完成后再保存一条补充记录
     pub async fn handle_callback(
         &self,
         verify_info: &VerifyInfo,
-        request_body: &String,
+        request_body: &str,
+        context: &SaasContext,
+    ) -> Result<String, WechatError> {
+        if self.recorder.is_none() {
+            return self
+                .process_callback(verify_info, request_body, context, None)
+                .await;
+        }
+        let started = Instant::now();
+        let mut record = CallbackRecord::new(verify_info, request_body);
+        let result = self
+            .process_callback(verify_info, request_body, context, Some(&mut record))
+            .await;
+        record.finish(&result, started.elapsed());
+        self.record(context, &record).await;
+        result
+    }
+
+    /// 处理回调消息, record不为None时填充解密后的消息及回复
+    async fn process_callback(
+        &self,
+        verify_info: &VerifyInfo,
+        request_body: &str,
         context: &SaasContext,
+        mut record: Option<&mut CallbackRecord>,
     ) -> Result<String, WechatError> {
-        use crate::message::crypt::decrypt_message;
+        use crate::message::crypt::parse_message;
         info!("handler callback: {:?} {}", verify_info, request_body);
+        self.verify_strict_header(verify_info)?;
         let config = self.saas_resolver.resolve_config(&self, &context).await?;
         let token = self.get_access_token(&context).await?;
-        let xml = decrypt_message(&config, &token.token, verify_info, request_body)?;
-        let message = crate::message::from_xml(&xml)?;
-        let mut prev_result = None;
-        for handler in self.callback_handlers.iter() {
-            prev_result = handler
-                .handler_callback(self, prev_result, &message)
-                .await?;
+        let parsed = parse_message(
+            &config,
+            &token.token,
+            verify_info,
+            request_body,
+            self.max_body_size,
+        )?;
+        let mode = parsed.mode;
+        let message = CallbackMessage::from_node(&parsed.node)?;
+        if let Some(record) = record.as_mut() {
+            record.decrypted_xml = Some(parsed.xml.clone());
+            record.message = Some(message.clone());
+        }
+        let callback_context = CallbackContext {
+            saas_context: *context,
+            app_id: config.app_id.clone(),
+            open_id: message.info().from_user_name.clone(),
+            verify_info: verify_info.clone(),
+            raw_xml: request_body.to_string(),
+            decrypted_xml: parsed.xml,
+            encrypt_mode: mode,
+            msg_key: dedup_key(&message),
+            extensions: Extensions::new(),
+        };
+        let reply = match &self.dedup {
+            None => {
+                self.verify_strict_nonce(verify_info, context).await?;
+                self.run_with_deadline(callback_context, message).await?
+            }
+            // 微信重试时nonce不变, 先去重, 只有首次处理的消息检查nonce
+            Some(dedup) => {
+                let key = callback_context.msg_key.clone();
+                match dedup.store.begin(context, &key, dedup.ttl).await? {
+                    DedupState::New => {}
+                    DedupState::Processing => {
+                        info!("重复的回调, 首次处理未完成: {}", key);
+                        return Ok(self.ack_if_empty(String::new()));
+                    }
+                    DedupState::Done(reply) => {
+                        info!("重复的回调, 返回首次处理的回复: {}", key);
+                        if let Some(record) = record.as_mut() {
+                            record.reply_xml = Some(reply.clone()).filter(|xml| !xml.is_empty());
+                        }
+                        let xml = Self::encode_reply(&config, &token.token, mode, reply)?;
+                        return Ok(self.ack_if_empty(xml));
+                    }
+                }
+                let result = match self.verify_strict_nonce(verify_info, context).await {
+                    Ok(()) => self.run_with_deadline(callback_context, message).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(reply) => {
+                        let xml = match &reply {
+                            HandlerReply::Reply(xml) => xml.as_str(),
+                            HandlerReply::Timeout => "",
+                        };
+                        dedup.store.finish(context, &key, xml, dedup.ttl).await?;
+                        reply
+                    }
+                    Err(e) => {
+                        if let Err(err) = dedup.store.abort(context, &key).await {
+                            warn!("删除去重记录失败: {} {:?}", key, err);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        match reply {
+            HandlerReply::Reply(xml) => {
+                if let Some(record) = record.as_mut() {
+                    record.reply_xml = Some(xml.clone()).filter(|xml| !xml.is_empty());
+                }
+                let xml = Self::encode_reply(&config, &token.token, mode, xml)?;
+                Ok(self.ack_if_empty(xml))
+            }
+            HandlerReply::Timeout => Ok(self
+                .reply_deadline
+                .as_ref()
+                .map(ReplyDeadline::ack)
+                .unwrap_or_default()),
+        }
+    }
+
+    fn ack_if_empty(&self, xml: String) -> String {
+        if xml.is_empty() && self.ack_success {
+            "success".to_string()
+        } else {
+            xml
+        }
+    }
+
+    /// 依次执行消息处理器, 合并各处理器的结果
+    async fn run_handlers(
+        &self,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> Result<HandlerOutcome, WechatError> {
+        let mut outcome = HandlerOutcome::pass();
+        for handler in self.callback_handlers() {
+            let error = match handler.handler_callback(self, context, message).await {
+                Ok(result) => {
+                    outcome.merge(result);
+                    if outcome.stop {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => e,
+            };
+            warn!("消息处理器返回错误: {:?}", error);
+            if let Some(hook) = &self.error_hook {
+                hook.on_error(self, context, message, &error).await;
+            }
+            match &self.error_policy {
+                HandlerErrorPolicy::Abort => return Err(error),
+                HandlerErrorPolicy::Continue => {}
+                HandlerErrorPolicy::Fallback(content) => {
+                    outcome.reply = Some(message.reply().text(content)?);
+                    break;
+                }
+                HandlerErrorPolicy::Silent => {
+                    outcome.reply = None;
+                    break;
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// 在超时限制内执行消息处理器, 超时后在后台继续执行, 完成后通过客服消息发送回复
+    async fn run_with_deadline(
+        &self,
+        context: CallbackContext,
+        message: CallbackMessage,
+    ) -> Result<HandlerReply, WechatError> {
+        let (deadline, wechat) = match (&self.reply_deadline, self.self_ref.upgrade()) {
+            (Some(deadline), Some(wechat)) => (deadline.timeout, wechat),
+            (deadline, _) => {
+                if deadline.is_some() {
+                    warn!("Wechat未通过into_shared共享, 被动回复超时不生效");
+                }
+                let mut outcome = self.run_handlers(&context, &message).await?;
+                let xml = Self::reply_xml(outcome.reply.take())?;
+                self.spawn_deferred(&context, &message, outcome);
+                return Ok(HandlerReply::Reply(xml));
+            }
+        };
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut outcome = match wechat.run_handlers(&context, &message).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    if let Err(Err(e)) = tx.send(Err(e)) {
+                        warn!("超时的消息处理失败: {:?}", e);
+                        let record = CallbackRecord::deferred(
+                            &context,
+                            &message,
+                            Err(&e),
+                            started.elapsed(),
+                        );
+                        wechat.record(&context.saas_context, &record).await;
+                    }
+                    return;
+                }
+            };
+            if let Err(Ok(reply)) = tx.send(Ok(outcome.reply.take())) {
+                let record = CallbackRecord::deferred(
+                    &context,
+                    &message,
+                    Ok(reply.as_ref()),
+                    started.elapsed(),
+                );
+                wechat.record(&context.saas_context, &record).await;
+                if let Some(reply) = reply {
+                    wechat.send_late_reply(&context, &message, reply).await;
+                }
+            }
+            wechat.run_deferred(&context, &message, outcome).await;
+        });
+        match tokio::time::timeout(deadline, rx).await {
+            Ok(Ok(reply)) => Ok(HandlerReply::Reply(Self::reply_xml(reply?)?)),
+            Ok(Err(_)) => Err(WechatEncryptError::internal("消息处理器异常退出").into()),
+            Err(_) => {
+                info!("消息处理超时({:?}), 回复将通过客服消息发送", deadline);
+                Ok(HandlerReply::Timeout)
+            }
+        }
+    }
+
+    /// 超时的被动回复通过客服消息发送
+    async fn send_late_reply(
+        &self,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+        reply: ReplyMessage,
+    ) {
+        let touser = &message.info().from_user_name;
+        match reply.to_kf_messages() {
+            Ok(messages) => {
+                self.send_kf_messages(&context.saas_context, touser, messages)
+                    .await
+            }
+            Err(e) => warn!("超时的被动回复无法通过客服消息发送: {} {:?}", touser, e),
+        }
+    }
+
+    /// 保存回调记录, 未配置recorder时忽略
+    async fn record(&self, context: &SaasContext, record: &CallbackRecord) {
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(context, record).await {
+                warn!("保存回调记录失败: {:?}", e);
+            }
+        }
+    }
+
+    /// 被动回复后在后台发送客服消息并执行task
+    ///
+    /// 未通过into_shared共享时无法在后台发送, 丢弃客服消息
+    fn spawn_deferred(
+        &self,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+        outcome: HandlerOutcome,
+    ) {
+        if !outcome.has_deferred() {
+            return;
+        }
+        if let Some(wechat) = self.self_ref.upgrade() {
+            let saas_context = context.saas_context;
+            let touser = message.info().from_user_name.clone();
+            tokio::spawn(async move {
+                wechat
+                    .send_kf_messages(&saas_context, &touser, outcome.deferred)
+                    .await;
+                Self::run_tasks(outcome.tasks).await;
+            });
+            return;
+        }
+        if !outcome.deferred.is_empty() {
+            warn!(
+                "Wechat未通过into_shared共享, 丢弃{}条客服消息: {}",
+                outcome.deferred.len(),
+                message.info().from_user_name
+            );
+        }
+        tokio::spawn(Self::run_tasks(outcome.tasks));
+    }
+
+    /// 发送客服消息并执行task
+    async fn run_deferred(
+        &self,
+        context: &CallbackContext,
+        message: &CallbackMessage,
+        outcome: HandlerOutcome,
+    ) {
+        let touser = &message.info().from_user_name;
+        self.send_kf_messages(&context.saas_context, touser, outcome.deferred)
+            .await;
+        Self::run_tasks(outcome.tasks).await;
+    }
+
+    async fn run_tasks(tasks: Vec<FollowUpTask>) {
+        for task in tasks {
+            if let Err(e) = task.await {
+                warn!("回复后的任务执行失败: {:?}", e);
+            }
+        }
+    }
+
+    /// 通过客服消息接口发送, 失败时只记录日志
+    async fn send_kf_messages(
+        &self,
+        context: &SaasContext,
+        touser: &str,
+        messages: Vec<KfMessage>,
+    ) {
+        use crate::customservice::Customservice;
+        for msg in messages {
+            if let Err(e) = self.send_msg(context, touser.to_string(), msg, None).await {
+                warn!("客服消息发送失败: {} {:?}", touser, e);
+            }
+        }
+    }
+
+    fn reply_xml(reply: Option<ReplyMessage>) -> Result<String, WechatError> {
+        match reply {
+            Some(msg) => msg.to_xml(),
+            None => Ok("".to_string()),
+        }
+    }
+
+    /// 安全模式加密回复, 兼容模式按公众号配置决定
+    fn encode_reply(
+        config: &WechatConfig,
+        token: &String,
+        mode: EncryptMode,
+        reply: String,
+    ) -> Result<String, WechatError> {
+        use crate::message::crypt::encrypt_reply;
+        if reply.is_empty() || !mode.reply_encrypted(config) {
+            return Ok(reply);
+        }
+        Ok(encrypt_reply(config, token, &reply)?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::message::crypt::{decrypt, decrypt_message, get_signature, DEFAULT_MAX_BODY_SIZE};
+    use crate::token_provider::memory::MemoryTokenProvider;
+    use crate::xml::{self, XmlNode};
+
+    const TOKEN: &str = "testtoken123456";
+    const ENCRYPTED_TEXT: &str = r#"<xml>
+        <ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>
+        <Encrypt><![CDATA[YsZjOA0RLvxvjZ8Xq38yC2YZgxw20MS/UCS13eiWaznQawh8JHGyonUKFLKC9cSgpxDpP9IHQ5+Vl9exTBSMgCzI19P1z0YpByB5rLfHMQWsyvm/H5uwH16lf2BgooZZRoEyzTDLXQFqjwiUSP7Iw8IzdtMp1Ux3f9glW5D/I5H3sGmxbmxf0N/2I5DKKWAlQZSfEnzouKcpyD9DJeY8FfKcQAlJFs/FKGs7g6UdXlxHwmgK3+ZOf7+FL8nFVOQzVpCLuOfRJnMQ//+Bp8aXoTbLiaW6haYuKf7CpPihQJ9/XFTgirBRB2V3jNFisVzwL9XeJ6r/H8Pt8GyGeQ6Hdpl4RVJY4gOTYvZpNvcz0WsKtJkh04tC7zj6tO8/cR4wsJxzTvDpMtBSpukNcFuR7BQtKKTlAkYulnoj8dAfHFc=]]></Encrypt>
+    </xml>"#;
+
+    const PLAINTEXT_TEXT: &str = r#"<xml>
+<ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>
+<FromUserName><![CDATA[oseZYwXU64cWTJuTV4UkS-DTu9OQ]]></FromUserName>
+<CreateTime>1592558813</CreateTime>
+<MsgType><![CDATA[text]]></MsgType>
+<Content><![CDATA[好的]]></Content>
+<MsgId>22799962246505739</MsgId>
+</xml>"#;
+
+    pub(crate) fn get_config() -> WechatConfig {
+        WechatConfig::new(
+            WechatConfig::decode_aes_key(&"znpfGFxELvUSxh0Gx4rJenvVQRrAhdTsioG08XR4z3S=".into())
+                .unwrap(),
+            "wx11853b05910e1b6b".into(),
+            "".into(),
+        )
+    }
+
+    /// 已缓存token的Wechat, 不会请求微信接口
+    pub(crate) async fn get_wechat() -> Wechat {
+        let wechat = Wechat::new(
+            Box::new(ConstSaasResolver::new(get_config())),
+            Box::new(MemoryTokenProvider::new()),
+        );
+        wechat
+            .token_provider
+            .set_token(
+                &wechat,
+                &SaasContext::new(1),
+                Some(WechatToken::new_relative(TOKEN.into(), 7200)),
+            )
+            .await
+            .unwrap();
+        wechat
+    }
+
+    /// 加密模式的verify info, 对应ENCRYPTED_TEXT
+    pub(crate) fn get_encrypted_verify_info() -> VerifyInfo {
+        VerifyInfo {
+            signature: "3af8f544dbc8c4e096c492984cbd1175c86a95c1".into(),
+            timestamp: 1592558813,
+            nonce: "1681763772".into(),
+            msg_signature: Some("060c0da85ba7c4c2bf2a69716debec5858145826".into()),
+            encrypt_type: Some("aes".into()),
+        }
+    }
+
+    /// 明文模式的verify info, 对应PLAINTEXT_TEXT
+    pub(crate) fn get_plaintext_verify_info() -> VerifyInfo {
+        let timestamp = 1592558813;
+        let nonce = "1681763772";
+        VerifyInfo {
+            signature: get_signature(&TOKEN.into(), timestamp, nonce, "").unwrap(),
+            timestamp,
+            nonce: nonce.into(),
+            msg_signature: None,
+            encrypt_type: None,
+        }
+    }
+
+    struct EchoText;
+
+    #[async_trait]
+    impl WechatCallBackHandler for EchoText {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            match message {
+                CallbackMessage::Text { info, content, .. } => {
+                    Ok(HandlerOutcome::reply(ReplyMessage::Text {
+                        info: MessageInfo {
+                            from_user_name: info.to_user_name.clone(),
+                            to_user_name: info.from_user_name.clone(),
+                            create_time: info.create_time,
+                            ..Default::default()
+                        },
+                        content: format!("hello: {}", content),
+                    }))
+                }
+                _ => Ok(HandlerOutcome::pass()),
+            }
         }
-        let xml = match prev_result {
-            None => "".to_string(),
-            Some(msg) => msg.to_xml()?,
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_reply() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        let reply = wechat
+            .handle_callback(
+                &get_encrypted_verify_info(),
+                ENCRYPTED_TEXT,
+                &SaasContext::new(1),
+            )
+            .await?;
+
+        let envelope = xml::parse(&reply)?;
+        let field = |name: &str| envelope.child(name).map(XmlNode::text).unwrap_or_default();
+        assert!(envelope.child("Content").is_none());
+        let timestamp: i64 = field("TimeStamp").parse().unwrap();
+        let verify_info = VerifyInfo {
+            signature: get_signature(&TOKEN.into(), timestamp, &field("Nonce"), "")?,
+            timestamp,
+            nonce: field("Nonce"),
+            msg_signature: Some(field("MsgSignature")),
+            encrypt_type: Some("aes".into()),
         };
-        Ok(xml)
+        let reply = decrypt_message(
+            &get_config(),
+            &TOKEN.into(),
+            &verify_info,
+            &reply,
+            DEFAULT_MAX_BODY_SIZE,
+        )?;
+        let reply = ReplyMessage::from_xml(&reply)?;
+        match reply {
+            ReplyMessage::Text { info, content } => {
+                assert_eq!("hello: 好的", content);
+                assert_eq!("oseZYwXU64cWTJuTV4UkS-DTu9OQ", info.to_user_name);
+            }
+            _ => panic!("should be text reply"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_reply() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT;
+        let reply = wechat
+            .handle_callback(&verify_info, body, &SaasContext::new(1))
+            .await?;
+        match ReplyMessage::from_xml(&reply)? {
+            ReplyMessage::Text { content, .. } => assert_eq!("hello: 好的", content),
+            _ => panic!("should be text reply"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compatible_reply() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        // 兼容模式: 明文字段与Encrypt同时存在
+        let body = ENCRYPTED_TEXT.replace(
+            "<Encrypt>",
+            "<FromUserName><![CDATA[oseZYwXU64cWTJuTV4UkS-DTu9OQ]]></FromUserName>
+<CreateTime>1592558813</CreateTime>
+<MsgType><![CDATA[text]]></MsgType>
+<Content><![CDATA[好的]]></Content>
+<MsgId>22799962246505739</MsgId>
+<Encrypt>",
+        );
+        let reply = wechat
+            .handle_callback(&get_encrypted_verify_info(), &body, &SaasContext::new(1))
+            .await?;
+        // 公众号配置了EncodingAESKey, 回复加密
+        let envelope = xml::parse(&reply)?;
+        assert!(envelope.child("Encrypt").is_some());
+        assert!(envelope.child("Content").is_none());
+
+        // 明文与密文不一致
+        let body = body.replace("好的", "坏的");
+        assert!(wechat
+            .handle_callback(&get_encrypted_verify_info(), &body, &SaasContext::new(1))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    /// 记录上下文, 供下一个handler使用
+    struct RecordContext;
+
+    #[async_trait]
+    impl WechatCallBackHandler for RecordContext {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            context.extensions.insert(format!(
+                "{}:{}:{:?}",
+                context.saas_context.id, context.app_id, context.encrypt_mode
+            ));
+            Ok(HandlerOutcome::pass())
+        }
+    }
+
+    struct ReplyContext;
+
+    #[async_trait]
+    impl WechatCallBackHandler for ReplyContext {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            assert!(context.raw_xml.contains("<Encrypt>"));
+            assert!(context
+                .decrypted_xml
+                .contains("<Content><![CDATA[好的]]></Content>"));
+            assert_eq!(
+                context.verify_info.msg_signature,
+                get_encrypted_verify_info().msg_signature
+            );
+            Ok(HandlerOutcome::reply(ReplyMessage::Text {
+                info: MessageInfo {
+                    to_user_name: message.info().from_user_name.clone(),
+                    ..Default::default()
+                },
+                content: context.extensions.get::<String>().unwrap_or_default(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_context() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(RecordContext));
+        wechat.registry_callback(Box::new(ReplyContext));
+        let reply = wechat
+            .handle_callback(
+                &get_encrypted_verify_info(),
+                ENCRYPTED_TEXT,
+                &SaasContext::new(1),
+            )
+            .await?;
+        let envelope = xml::parse(&reply)?;
+        let encrypted = envelope.child("Encrypt").map(XmlNode::text).unwrap();
+        let config = get_config();
+        let reply = decrypt(config.key.as_ref().unwrap(), &config.app_id, &encrypted)?;
+        match ReplyMessage::from_xml(&reply)? {
+            ReplyMessage::Text { content, .. } => {
+                assert_eq!("1:wx11853b05910e1b6b:Safe", content)
+            }
+            _ => panic!("should be text reply"),
+        }
+        Ok(())
+    }
+
+    /// 记录调用次数, 处理较慢
+    struct SlowCounter(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl WechatCallBackHandler for SlowCounter {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(HandlerOutcome::pass())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dedup() -> Result<(), WechatError> {
+        use crate::core::dedup::memory::MemoryDedupStore;
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.registry_callback(Box::new(SlowCounter(counter.clone())));
+        wechat.set_dedup(CallbackDedup::new(Box::new(MemoryDedupStore::new())));
+
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+        let (first, retry) = tokio::join!(
+            wechat.handle_callback(&verify_info, &body, &context),
+            wechat.handle_callback(&verify_info, &body, &context)
+        );
+        // 首次处理未完成时的重试返回success
+        assert_eq!("success", retry?);
+        let first = first?;
+        assert!(first.contains("hello: 好的"));
+        // 处理完成后的重试返回首次的回复
+        assert_eq!(
+            first,
+            wechat
+                .handle_callback(&verify_info, &body, &context)
+                .await?
+        );
+        assert_eq!(1, counter.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_strict_nonce_with_dedup() -> Result<(), WechatError> {
+        use crate::core::dedup::memory::MemoryDedupStore;
+        use crate::core::nonce_cache::memory::MemoryNonceCache;
+        let strict = || {
+            // 测试数据的时间戳较早, 放宽时间窗口
+            StrictVerify::new(Duration::from_secs(100 * 365 * 24 * 3600))
+                .with_nonce_cache(Box::new(MemoryNonceCache::new()))
+        };
+        let verify_info = get_plaintext_verify_info();
+        let context = SaasContext::new(1);
+
+        // 未启用去重时, 重试的nonce重复
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.set_strict_verify(strict());
+        wechat
+            .handle_callback(&verify_info, PLAINTEXT_TEXT, &context)
+            .await?;
+        let retry = wechat
+            .handle_callback(&verify_info, PLAINTEXT_TEXT, &context)
+            .await;
+        assert!(matches!(
+            retry,
+            Err(WechatError::EncryptError {
+                source: WechatEncryptError::InvalidSignature(_)
+            })
+        ));
+
+        // 启用去重时, 重试返回首次的回复
+        wechat.set_strict_verify(strict());
+        wechat.set_dedup(CallbackDedup::new(Box::new(MemoryDedupStore::new())));
+        let first = wechat
+            .handle_callback(&verify_info, PLAINTEXT_TEXT, &context)
+            .await?;
+        assert!(first.contains("hello: 好的"));
+        assert_eq!(
+            first,
+            wechat
+                .handle_callback(&verify_info, PLAINTEXT_TEXT, &context)
+                .await?
+        );
+        Ok(())
+    }
+
+    /// 模拟微信接口, 接收一个请求并返回(请求行, 请求体)
+    async fn mock_api() -> Result<(Url, tokio::task::JoinHandle<(String, String)>), WechatError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/", listener.local_addr()?)).unwrap();
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let (head, body) = loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                assert!(read > 0, "请求不完整");
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .filter_map(|line| {
+                            let line = line.to_ascii_lowercase();
+                            line.strip_prefix("content-length:")
+                                .map(|value| value.trim().parse::<usize>().unwrap())
+                        })
+                        .next()
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length {
+                        break (text[..end].to_string(), text[end + 4..].to_string());
+                    }
+                }
+            };
+            let response = r#"{"errcode":0,"errmsg":"ok"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            (head.lines().next().unwrap_or_default().to_string(), body)
+        });
+        Ok((url, request))
+    }
+
+    #[tokio::test]
+    async fn test_reply_deadline() -> Result<(), WechatError> {
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (api_base, request) = mock_api().await?;
+        let mut wechat = get_wechat().await;
+        wechat.set_api_base(api_base);
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.registry_callback(Box::new(SlowCounter(counter.clone())));
+        wechat.set_reply_deadline(ReplyDeadline::new(Duration::from_millis(10)));
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+
+        // 未共享时不限制
+        assert!(wechat
+            .handle_callback(&verify_info, &body, &context)
+            .await?
+            .contains("hello: 好的"));
+        assert_eq!(1, counter.load(std::sync::atomic::Ordering::SeqCst));
+
+        let wechat = wechat.into_shared();
+        assert_eq!(
+            "success",
+            wechat
+                .handle_callback(&verify_info, &body, &context)
+                .await?
+        );
+        // 处理器在后台继续执行
+        assert_eq!(1, counter.load(std::sync::atomic::Ordering::SeqCst));
+
+        // 处理完成后通过客服消息发送回复
+        let (request_line, body) = tokio::time::timeout(Duration::from_secs(5), request)
+            .await
+            .expect("客服消息未发送")
+            .unwrap();
+        assert_eq!(2, counter.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(request_line.starts_with(&format!(
+            "POST /cgi-bin/message/custom/send?access_token={}",
+            TOKEN
+        )));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!("oseZYwXU64cWTJuTV4UkS-DTu9OQ", body["touser"]);
+        assert_eq!("hello: 好的", body["text"]["content"]);
+        Ok(())
+    }
+
+    struct Panicking;
+
+    #[async_trait]
+    impl WechatCallBackHandler for Panicking {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            panic!("handler panicked")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_deadline_panic() -> Result<(), WechatError> {
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(Panicking));
+        wechat.set_reply_deadline(ReplyDeadline::new(Duration::from_secs(1)));
+        let wechat = wechat.into_shared();
+        let error = wechat
+            .handle_callback(
+                &get_plaintext_verify_info(),
+                PLAINTEXT_TEXT,
+                &SaasContext::new(1),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            WechatError::EncryptError {
+                source: WechatEncryptError::InternalError(_)
+            }
+        ));
+        assert_eq!(http::StatusCode::INTERNAL_SERVER_ERROR, error.http_status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_unavailable() -> Result<(), WechatError> {
+        let mut wechat = get_wechat().await;
+        wechat.set_api_base(Url::parse("http://127.0.0.1:1/").unwrap());
+        wechat.registry_callback(Box::new(EchoText));
+        // 未缓存token且无法从微信接口获取
+        let error = wechat
+            .handle_callback(
+                &get_plaintext_verify_info(),
+                PLAINTEXT_TEXT,
+                &SaasContext::new(2),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            WechatError::EncryptError {
+                source: WechatEncryptError::TokenError(_)
+            }
+        ));
+        assert_eq!(http::StatusCode::SERVICE_UNAVAILABLE, error.http_status());
+        Ok(())
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl WechatCallBackHandler for Failing {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            Err(WechatError::ParseError("failed".into()))
+        }
+    }
+
+    struct CountErrors(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl CallbackErrorHook for CountErrors {
+        async fn on_error(
+            &self,
+            _wechat: &Wechat,
+            context: &CallbackContext,
+            _message: &CallbackMessage,
+            _error: &WechatError,
+        ) {
+            assert_eq!(1, context.saas_context.id);
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() -> Result<(), WechatError> {
+        let errors = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.registry_callback(Box::new(Failing));
+        wechat.set_error_hook(Box::new(CountErrors(errors.clone())));
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+        let reply_content = |reply: &str| match ReplyMessage::from_xml(reply) {
+            Ok(ReplyMessage::Text { content, .. }) => content,
+            _ => panic!("should be text reply"),
+        };
+
+        // 默认返回错误
+        assert!(wechat
+            .handle_callback(&verify_info, &body, &context)
+            .await
+            .is_err());
+        // 保留上一个处理器的结果
+        wechat.set_error_policy(HandlerErrorPolicy::Continue);
+        let reply = wechat
+            .handle_callback(&verify_info, &body, &context)
+            .await?;
+        assert_eq!("hello: 好的", reply_content(&reply));
+
+        wechat.set_error_policy(HandlerErrorPolicy::Fallback("系统繁忙".into()));
+        let reply = wechat
+            .handle_callback(&verify_info, &body, &context)
+            .await?;
+        assert_eq!("系统繁忙", reply_content(&reply));
+
+        wechat.set_error_policy(HandlerErrorPolicy::Silent);
+        assert_eq!(
+            "success",
+            wechat
+                .handle_callback(&verify_info, &body, &context)
+                .await?
+        );
+        wechat.set_ack_success(false);
+        assert_eq!(
+            "",
+            wechat
+                .handle_callback(&verify_info, &body, &context)
+                .await?
+        );
+        assert_eq!(5, errors.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    /// 回复并停止, 回复后执行task
+    struct ReplyAndStop(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl WechatCallBackHandler for ReplyAndStop {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            let counter = self.0.clone();
+            let reply = ReplyMessage::Text {
+                info: MessageInfo {
+                    to_user_name: message.info().from_user_name.clone(),
+                    ..Default::default()
+                },
+                content: "stop".into(),
+            };
+            Ok(HandlerOutcome::reply_and_stop(reply).then(async move {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_outcome() -> Result<(), WechatError> {
+        let mut outcome = HandlerOutcome::reply(ReplyMessage::Text {
+            info: MessageInfo::default(),
+            content: "first".into(),
+        })
+        .defer(KfMessage::Text {
+            content: "later".into(),
+        });
+        // pass保留之前的回复
+        outcome.merge(HandlerOutcome::pass().then(async { Ok(()) }));
+        assert!(!outcome.stop);
+        assert!(
+            matches!(&outcome.reply, Some(ReplyMessage::Text { content, .. }) if content == "first")
+        );
+        assert_eq!(1, outcome.deferred.len());
+        assert_eq!(1, outcome.tasks.len());
+
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(RecordContext));
+        wechat.registry_callback(Box::new(ReplyAndStop(counter.clone())));
+        wechat.registry_callback(Box::new(EchoText));
+        let wechat = wechat.into_shared();
+        let reply = wechat
+            .handle_callback(
+                &get_plaintext_verify_info(),
+                PLAINTEXT_TEXT,
+                &SaasContext::new(1),
+            )
+            .await?;
+        // 停止后不再执行EchoText
+        match ReplyMessage::from_xml(&reply)? {
+            ReplyMessage::Text { content, .. } => assert_eq!("stop", content),
+            _ => panic!("should be text reply"),
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(1, counter.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    /// 回复后发送客服消息
+    struct ReplyAndDefer;
+
+    #[async_trait]
+    impl WechatCallBackHandler for ReplyAndDefer {
+        async fn handler_callback(
+            &self,
+            _wechat: &Wechat,
+            _context: &CallbackContext,
+            _message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            Ok(HandlerOutcome::pass().defer(KfMessage::Text {
+                content: "later".into(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_requires_shared() -> Result<(), WechatError> {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let mut wechat = get_wechat().await;
+        wechat.set_api_base(Url::parse(&format!("http://{}/", listener.local_addr()?)).unwrap());
+        wechat.registry_callback(Box::new(ReplyAndDefer));
+        wechat
+            .handle_callback(
+                &get_plaintext_verify_info(),
+                PLAINTEXT_TEXT,
+                &SaasContext::new(1),
+            )
+            .await?;
+        // 未共享时不发送客服消息
+        let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err());
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct Greeting(&'static str);
+
+    /// 使用应用数据回复
+    struct ReplyGreeting;
+
+    #[async_trait]
+    impl WechatCallBackHandler for ReplyGreeting {
+        async fn handler_callback(
+            &self,
+            wechat: &Wechat,
+            _context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            let greeting = wechat.extensions().get::<Greeting>().unwrap();
+            Ok(HandlerOutcome::reply(ReplyMessage::Text {
+                info: MessageInfo {
+                    to_user_name: message.info().from_user_name.clone(),
+                    ..Default::default()
+                },
+                content: greeting.0.into(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_extensions() -> Result<(), WechatError> {
+        let wechat = get_wechat().await;
+        let token_provider = MemoryTokenProvider::new();
+        let token = WechatToken::new_relative(TOKEN.into(), 7200);
+        token_provider
+            .set_token(&wechat, &SaasContext::new(1), Some(token))
+            .await?;
+        let wechat = Wechat::builder(
+            Box::new(ConstSaasResolver::new(get_config())),
+            Box::new(token_provider),
+        )
+        .extension(Greeting("你好"))
+        .build_shared();
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+        assert_eq!(
+            "success",
+            wechat
+                .handle_callback(&verify_info, &body, &context)
+                .await?
+        );
+
+        // 共享后注册处理器
+        wechat.registry_callback(Box::new(ReplyGreeting));
+        assert_eq!(1, wechat.callback_handlers().len());
+        let reply = wechat
+            .handle_callback(&verify_info, &body, &context)
+            .await?;
+        match ReplyMessage::from_xml(&reply)? {
+            ReplyMessage::Text { content, .. } => assert_eq!("你好", content),
+            _ => panic!("should be text reply"),
+        }
+        Ok(())
+    }
+
+    /// 回复会话中记录的消息次数
+    struct CountMessages;
+
+    #[async_trait]
+    impl WechatCallBackHandler for CountMessages {
+        async fn handler_callback(
+            &self,
+            wechat: &Wechat,
+            context: &CallbackContext,
+            message: &CallbackMessage,
+        ) -> Result<HandlerOutcome, WechatError> {
+            let session = wechat.session(context)?;
+            let count = session
+                .update(|count: Option<u32>| Some(count.unwrap_or(0) + 1))
+                .await?;
+            Ok(HandlerOutcome::reply(ReplyMessage::Text {
+                info: MessageInfo {
+                    to_user_name: message.info().from_user_name.clone(),
+                    ..Default::default()
+                },
+                content: count.unwrap_or_default().to_string(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions() -> Result<(), WechatError> {
+        use crate::core::session::memory::MemorySessionStore;
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(CountMessages));
+        let verify_info = get_plaintext_verify_info();
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+        // 未启用会话
+        assert!(matches!(
+            wechat.handle_callback(&verify_info, &body, &context).await,
+            Err(WechatError::SessionError {
+                source: SessionError::Disabled
+            })
+        ));
+
+        // 每条消息update两次
+        wechat.registry_callback(Box::new(CountMessages));
+        wechat.set_sessions(SessionManager::new(Box::new(MemorySessionStore::new())));
+        let next = body.replace("22799962246505739", "22799962246505740");
+        // 重试的消息不重复执行update
+        for (body, expected) in &[(&body, "2"), (&body, "2"), (&next, "4")] {
+            let reply = wechat.handle_callback(&verify_info, body, &context).await?;
+            match ReplyMessage::from_xml(&reply)? {
+                ReplyMessage::Text { content, .. } => assert_eq!(*expected, content),
+                _ => panic!("should be text reply"),
+            }
+        }
+        let session = wechat
+            .sessions
+            .as_ref()
+            .unwrap()
+            .session(&context, "oseZYwXU64cWTJuTV4UkS-DTu9OQ");
+        assert_eq!(Some(4), session.get::<u32>().await?);
+        Ok(())
+    }
+
+    struct Collect(Arc<std::sync::Mutex<Vec<CallbackRecord>>>);
+
+    #[async_trait]
+    impl CallbackRecorder for Collect {
+        async fn record(
+            &self,
+            _context: &SaasContext,
+            record: &CallbackRecord,
+        ) -> Result<(), WechatError> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recorder() -> Result<(), WechatError> {
+        let records = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.set_recorder(Box::new(Collect(records.clone())));
+        let body = PLAINTEXT_TEXT.to_string();
+        let context = SaasContext::new(1);
+        let reply = wechat
+            .handle_callback(&get_plaintext_verify_info(), &body, &context)
+            .await?;
+        let record = records.lock().unwrap().pop().unwrap();
+        assert_eq!(body, record.raw_body);
+        assert!(record.decrypted_xml.unwrap().contains("好的"));
+        assert!(matches!(record.message, Some(CallbackMessage::Text { .. })));
+        assert!(record.reply_xml.unwrap().contains("hello: 好的"));
+        assert_eq!(Some(reply), record.response);
+        assert!(record.error.is_none());
+
+        // 加密模式记录加密前的回复
+        let body = ENCRYPTED_TEXT.to_string();
+        let reply = wechat
+            .handle_callback(&get_encrypted_verify_info(), &body, &context)
+            .await?;
+        let record = records.lock().unwrap().pop().unwrap();
+        assert!(record.reply_xml.unwrap().contains("hello: 好的"));
+        assert!(!reply.contains("hello"));
+
+        // 签名错误也会记录
+        let mut verify_info = get_plaintext_verify_info();
+        verify_info.signature = "invalid".into();
+        assert!(wechat
+            .handle_callback(&verify_info, PLAINTEXT_TEXT, &context)
+            .await
+            .is_err());
+        let record = records.lock().unwrap().pop().unwrap();
+        assert!(record.decrypted_xml.is_none());
+        assert!(record.message.is_none());
+        assert!(record.response.is_none());
+        assert!(record.error.unwrap().contains("InvalidSignature"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deferred_record() -> Result<(), WechatError> {
+        let records = Arc::new(std::sync::Mutex::new(vec![]));
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut wechat = get_wechat().await;
+        wechat.registry_callback(Box::new(SlowCounter(counter)));
+        wechat.registry_callback(Box::new(EchoText));
+        wechat.set_reply_deadline(ReplyDeadline::new(Duration::from_millis(10)));
+        wechat.set_recorder(Box::new(Collect(records.clone())));
+        // 客服消息发送失败不影响记录
+        wechat.set_api_base(Url::parse("http://127.0.0.1:1/").unwrap());
+        let wechat = wechat.into_shared();
+        let context = SaasContext::new(1);
+        let reply = wechat
+            .handle_callback(&get_plaintext_verify_info(), PLAINTEXT_TEXT, &context)
+            .await?;
+        assert_eq!("success", reply);
+        let record = records.lock().unwrap().pop().unwrap();
+        assert!(!record.deferred);
+        assert!(record.reply_xml.is_none());
+        assert_eq!(Some(reply), record.response);
+
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let record = records.lock().unwrap().pop().unwrap();
+        assert!(record.deferred);
+        assert_eq!(PLAINTEXT_TEXT, record.raw_body);
+        assert!(matches!(record.message, Some(CallbackMessage::Text { .. })));
+        assert!(record.reply_xml.unwrap().contains("hello: 好的"));
+        assert!(record.response.is_none());
+        assert!(record.latency_ms >= 50);
+        Ok(())
     }
 }
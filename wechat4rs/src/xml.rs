@@ -0,0 +1,1426 @@
+//! 微信XML格式的序列化/反序列化
+//!
+//! 微信的消息均为`<xml>`根节点下的扁平结构:
+//! + 字符串使用`<![CDATA[...]]>`, 数字直接输出
+//! + 结构体/Map为子元素
+//! + 数组的每个元素为一个`<item>`子元素
+//! + `None`字段不输出
+//!
+//! 使用`#[serde(flatten)]`或内部标签(`#[serde(tag = "...")]`)时, serde会先将元素缓存为文本,
+//! 数字、可选字段及`<item>`数组需通过`number`、`optional`、`items`解析
+use crate::WechatError;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Impossible};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display};
+
+/// 根节点
+const ROOT: &str = "xml";
+/// 数组元素节点
+const ITEM: &str = "item";
+/// 元素最大嵌套层数
+const MAX_DEPTH: usize = 32;
+/// 标记无需CDATA的文本
+const VALUE_TOKEN: &str = "$wechat4rs::xml::Value";
+
+/// 序列化为微信XML
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, WechatError> {
+    let mut xml = String::new();
+    xml.push_str("<xml>");
+    value.serialize(ValueSerializer { out: &mut xml })?;
+    xml.push_str("</xml>");
+    Ok(xml)
+}
+
+/// 从微信XML反序列化
+pub fn from_str<T: de::DeserializeOwned>(xml: &str) -> Result<T, WechatError> {
+    let node = parse(xml)?;
+    T::deserialize(&node)
+}
+
+/// 解析微信XML, 返回`<xml>`根节点
+///
+/// 单次扫描, 不构建DOM, 支持CDATA、预定义实体、字符引用及注释, 忽略属性;
+/// 不支持DTD(`<!DOCTYPE`)及自定义实体, 嵌套超过32层时返回错误
+pub fn parse(xml: &str) -> Result<XmlNode, WechatError> {
+    let mut reader = Reader::new(xml);
+    reader.skip_misc()?;
+    let (name, node) = reader.element()?;
+    if name != ROOT {
+        return Err(reader.error(&format!("根节点应为<xml>: {}", name)));
+    }
+    reader.skip_misc()?;
+    if !reader.eof() {
+        return Err(reader.error("根节点后有多余内容"));
+    }
+    Ok(match node {
+        XmlNode::Text(_) => XmlNode::Element(Vec::new()),
+        node => node,
+    })
+}
+
+/// 单次扫描的XML读取器
+struct Reader<'a> {
+    xml: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(xml: &'a str) -> Self {
+        Reader {
+            xml,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn error(&self, msg: &str) -> WechatError {
+        WechatError::ParseError(format!("XML格式错误: {}, 位置: {}", msg, self.pos))
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.xml.len()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.xml[self.pos..]
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// 跳过start, 并返回到end之前的内容
+    fn until(&mut self, start: &str, end: &str) -> Result<&'a str, WechatError> {
+        let begin = self.pos + start.len();
+        match self.xml[begin..].find(end) {
+            Some(len) => {
+                self.pos = begin + len + end.len();
+                Ok(&self.xml[begin..begin + len])
+            }
+            None => Err(self.error(&format!("缺少{}", end))),
+        }
+    }
+
+    /// 跳过空白、XML声明、处理指令及注释
+    fn skip_misc(&mut self) -> Result<(), WechatError> {
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("<?") {
+                self.until("<?", "?>")?;
+            } else if self.starts_with("<!--") {
+                self.until("<!--", "-->")?;
+            } else if self.starts_with("<!") {
+                return Err(self.error("不支持DTD"));
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, WechatError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("缺少元素名"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// 读取一个元素, 当前位置须为`<`
+    fn element(&mut self) -> Result<(&'a str, XmlNode), WechatError> {
+        if !self.starts_with("<") {
+            return Err(self.error("应为元素"));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("嵌套层数过多"));
+        }
+        self.pos += 1;
+        let name = self.name()?;
+        // 忽略属性
+        let mut quote = None;
+        let tag_end = self.rest().char_indices().find(|&(_, c)| match quote {
+            Some(q) if c == q => {
+                quote = None;
+                false
+            }
+            Some(_) => false,
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                false
+            }
+            None => c == '>',
+        });
+        let tag_end = match tag_end {
+            Some((i, _)) => self.pos + i,
+            None => return Err(self.error("元素未闭合")),
+        };
+        let empty = self.xml[..tag_end].ends_with('/');
+        self.pos = tag_end + 1;
+        if empty {
+            return Ok((name, XmlNode::Text(String::new())));
+        }
+
+        let mut text = String::new();
+        let mut children = Vec::new();
+        loop {
+            let rest = self.rest();
+            // 普通文本
+            let len = rest.find(&['<', '&'][..]).unwrap_or(rest.len());
+            text.push_str(&rest[..len]);
+            self.pos += len;
+
+            if self.eof() {
+                return Err(self.error(&format!("元素<{}>未闭合", name)));
+            } else if self.starts_with("&") {
+                let entity = self.until("&", ";")?;
+                text.push(self.entity(entity)?);
+            } else if self.starts_with("<![CDATA[") {
+                text.push_str(self.until("<![CDATA[", "]]>")?);
+            } else if self.starts_with("<!--") {
+                self.until("<!--", "-->")?;
+            } else if self.starts_with("</") {
+                self.pos += 2;
+                let end = self.name()?;
+                if end != name {
+                    return Err(self.error(&format!("<{}>与</{}>不匹配", name, end)));
+                }
+                self.skip_whitespace();
+                if !self.starts_with(">") {
+                    return Err(self.error("结束标签未闭合"));
+                }
+                self.pos += 1;
+                break;
+            } else if self.starts_with("<!") || self.starts_with("<?") {
+                return Err(self.error("不支持的标记"));
+            } else {
+                self.depth += 1;
+                let (child, node) = self.element()?;
+                self.depth -= 1;
+                children.push((child.to_string(), node));
+            }
+        }
+        let node = if children.is_empty() {
+            XmlNode::Text(text.trim().to_string())
+        } else {
+            XmlNode::Element(children)
+        };
+        Ok((name, node))
+    }
+
+    fn entity(&self, entity: &str) -> Result<char, WechatError> {
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                .ok()
+                .and_then(std::char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        c.ok_or_else(|| self.error(&format!("未知的实体: &{};", entity)))
+    }
+}
+
+/// XML节点
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlNode {
+    /// 文本, 序列化为CDATA
+    Text(String),
+    /// 数字等无需CDATA的文本
+    Value(String),
+    /// 子元素(元素名, 节点), 保持原始顺序
+    Element(Vec<(String, XmlNode)>),
+}
+
+impl XmlNode {
+    /// 文本内容, 子元素的文本会被拼接
+    pub fn text(&self) -> String {
+        match self {
+            XmlNode::Text(text) | XmlNode::Value(text) => text.clone(),
+            XmlNode::Element(children) => children.iter().map(|(_, node)| node.text()).collect(),
+        }
+    }
+
+    /// 第一个名为name的子元素
+    pub fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.children()
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, node)| node)
+    }
+
+    /// 所有子元素
+    pub fn children(&self) -> &[(String, XmlNode)] {
+        match self {
+            XmlNode::Element(children) => children,
+            _ => &[],
+        }
+    }
+
+    /// 按路径查找, 如`SendPicsInfo/PicList/*/PicMd5Sum`, `*`匹配任意子元素
+    pub fn find(&self, path: &str) -> Vec<&XmlNode> {
+        let mut nodes = vec![self];
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            nodes = nodes
+                .into_iter()
+                .flat_map(|node| node.children().iter())
+                .filter(|(n, _)| name == "*" || n == name)
+                .map(|(_, node)| node)
+                .collect();
+        }
+        nodes
+    }
+}
+
+impl Serialize for XmlNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::SerializeMap;
+        match self {
+            XmlNode::Text(text) => serializer.serialize_str(text),
+            XmlNode::Value(value) => serializer.serialize_newtype_struct(VALUE_TOKEN, value),
+            XmlNode::Element(children) => {
+                let mut map = serializer.serialize_map(Some(children.len()))?;
+                for (name, node) in children {
+                    map.serialize_entry(name, node)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for XmlNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeVisitor;
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = XmlNode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("xml node")
+            }
+            fn visit_bool<E>(self, v: bool) -> Result<XmlNode, E> {
+                Ok(XmlNode::Value(v.to_string()))
+            }
+            fn visit_i64<E>(self, v: i64) -> Result<XmlNode, E> {
+                Ok(XmlNode::Value(v.to_string()))
+            }
+            fn visit_u64<E>(self, v: u64) -> Result<XmlNode, E> {
+                Ok(XmlNode::Value(v.to_string()))
+            }
+            fn visit_f64<E>(self, v: f64) -> Result<XmlNode, E> {
+                Ok(XmlNode::Value(v.to_string()))
+            }
+            fn visit_str<E>(self, v: &str) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(v.to_string()))
+            }
+            fn visit_string<E>(self, v: String) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(v))
+            }
+            fn visit_unit<E>(self) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(String::new()))
+            }
+            fn visit_none<E>(self) -> Result<XmlNode, E> {
+                Ok(XmlNode::Text(String::new()))
+            }
+            fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<XmlNode, D::Error> {
+                XmlNode::deserialize(d)
+            }
+            fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<XmlNode, D::Error> {
+                Ok(XmlNode::Value(String::deserialize(d)?))
+            }
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<XmlNode, A::Error> {
+                let mut children = Vec::new();
+                while let Some(node) = seq.next_element()? {
+                    children.push((ITEM.to_string(), node));
+                }
+                Ok(XmlNode::Element(children))
+            }
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<XmlNode, A::Error> {
+                let mut children = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    children.push(entry);
+                }
+                Ok(XmlNode::Element(children))
+            }
+        }
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+// ======================== 序列化 ========================
+
+fn write_cdata(out: &mut String, text: &str) {
+    out.push_str("<![CDATA[");
+    // CDATA中不能出现]]>, 需拆分为两段
+    out.push_str(&text.replace("]]>", "]]]]><![CDATA[>"));
+    out.push_str("]]>");
+}
+
+fn write_element(out: &mut String, name: &str, content: &str) {
+    out.push('<');
+    out.push_str(name);
+    out.push('>');
+    out.push_str(content);
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+/// 序列化一个值(元素内容), 返回是否有输出, None不输出
+struct ValueSerializer<'a> {
+    out: &'a mut String,
+}
+
+impl<'a> ValueSerializer<'a> {
+    fn plain<T: Display>(self, v: T) -> Result<bool, WechatError> {
+        self.out.push_str(&v.to_string());
+        Ok(true)
+    }
+}
+
+impl<'a> Serializer for ValueSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+    type SerializeSeq = SeqSerializer<'a>;
+    type SerializeTuple = SeqSerializer<'a>;
+    type SerializeTupleStruct = SeqSerializer<'a>;
+    type SerializeTupleVariant = SeqSerializer<'a>;
+    type SerializeMap = MapSerializer<'a>;
+    type SerializeStruct = MapSerializer<'a>;
+    type SerializeStructVariant = MapSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_i8(self, v: i8) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_f32(self, v: f32) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_f64(self, v: f64) -> Result<bool, WechatError> {
+        self.plain(v)
+    }
+    fn serialize_char(self, v: char) -> Result<bool, WechatError> {
+        self.serialize_str(&v.to_string())
+    }
+    fn serialize_str(self, v: &str) -> Result<bool, WechatError> {
+        write_cdata(self.out, v);
+        Ok(true)
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<bool, WechatError> {
+        self.serialize_str(&base64::encode(v))
+    }
+    fn serialize_none(self) -> Result<bool, WechatError> {
+        Ok(false)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<bool, WechatError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<bool, WechatError> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<bool, WechatError> {
+        if name == VALUE_TOKEN {
+            // 直接输出文本, 需转义
+            let text = value
+                .serialize(KeySerializer)?
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            self.out.push_str(&text);
+            return Ok(true);
+        }
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<bool, WechatError> {
+        let mut content = String::new();
+        value.serialize(ValueSerializer { out: &mut content })?;
+        write_element(self.out, variant, &content);
+        Ok(true)
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqSerializer<'a>, WechatError> {
+        Ok(SeqSerializer { out: self.out })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer<'a>, WechatError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer<'a>, WechatError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer<'a>, WechatError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer<'a>, WechatError> {
+        Ok(MapSerializer {
+            out: self.out,
+            key: None,
+        })
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer<'a>, WechatError> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer<'a>, WechatError> {
+        self.serialize_map(Some(len))
+    }
+}
+
+/// 子元素
+struct MapSerializer<'a> {
+    out: &'a mut String,
+    key: Option<String>,
+}
+
+impl<'a> MapSerializer<'a> {
+    fn element<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), WechatError> {
+        let mut content = String::new();
+        if value.serialize(ValueSerializer { out: &mut content })? {
+            write_element(self.out, name, &content);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for MapSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), WechatError> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| WechatError::ParseError("缺少元素名".into()))?;
+        self.element(&key, value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+impl<'a> ser::SerializeStruct for MapSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), WechatError> {
+        self.element(key, value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for MapSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), WechatError> {
+        self.element(key, value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+/// 数组, 每个元素输出为<item>
+struct SeqSerializer<'a> {
+    out: &'a mut String,
+}
+
+impl<'a> SeqSerializer<'a> {
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        let mut content = String::new();
+        if value.serialize(ValueSerializer { out: &mut content })? {
+            write_element(self.out, ITEM, &content);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for SeqSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        self.item(value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+impl<'a> ser::SerializeTuple for SeqSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        self.item(value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for SeqSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        self.item(value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for SeqSerializer<'a> {
+    type Ok = bool;
+    type Error = WechatError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WechatError> {
+        self.item(value)
+    }
+    fn end(self) -> Result<bool, WechatError> {
+        Ok(true)
+    }
+}
+
+/// 元素名, 只支持字符串
+struct KeySerializer;
+
+impl KeySerializer {
+    fn invalid<T>() -> Result<T, WechatError> {
+        Err(WechatError::ParseError("元素名必须为字符串".into()))
+    }
+}
+
+impl Serializer for KeySerializer {
+    type Ok = String;
+    type Error = WechatError;
+    type SerializeSeq = Impossible<String, WechatError>;
+    type SerializeTuple = Impossible<String, WechatError>;
+    type SerializeTupleStruct = Impossible<String, WechatError>;
+    type SerializeTupleVariant = Impossible<String, WechatError>;
+    type SerializeMap = Impossible<String, WechatError>;
+    type SerializeStruct = Impossible<String, WechatError>;
+    type SerializeStructVariant = Impossible<String, WechatError>;
+
+    fn serialize_str(self, v: &str) -> Result<String, WechatError> {
+        Ok(v.to_string())
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<String, WechatError> {
+        Ok(variant.to_string())
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, WechatError> {
+        value.serialize(self)
+    }
+    fn serialize_bool(self, _v: bool) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_i8(self, _v: i8) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_i16(self, _v: i16) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_i32(self, _v: i32) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_i64(self, _v: i64) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_u8(self, _v: u8) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_u16(self, _v: u16) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_u32(self, _v: u32) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_u64(self, _v: u64) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_f32(self, _v: f32) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_f64(self, _v: f64) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_char(self, v: char) -> Result<String, WechatError> {
+        Ok(v.to_string())
+    }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_none(self) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String, WechatError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, WechatError> {
+        Self::invalid()
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, WechatError> {
+        Self::invalid()
+    }
+}
+
+// ======================== 反序列化 ========================
+
+impl XmlNode {
+    fn leaf(&self) -> Result<&str, WechatError> {
+        match self {
+            XmlNode::Text(text) | XmlNode::Value(text) => Ok(text.trim()),
+            XmlNode::Element(_) => Err(de::Error::custom("应为文本, 实际为子元素")),
+        }
+    }
+
+    fn parse_leaf<T: std::str::FromStr>(&self) -> Result<T, WechatError>
+    where
+        T::Err: Display,
+    {
+        let text = self.leaf()?;
+        text.parse()
+            .map_err(|e| de::Error::custom(format!("无法解析[{}]: {}", text, e)))
+    }
+
+    /// 是否为空文本或没有子元素
+    pub fn is_empty(&self) -> bool {
+        match self {
+            XmlNode::Text(text) | XmlNode::Value(text) => text.trim().is_empty(),
+            XmlNode::Element(children) => children.is_empty(),
+        }
+    }
+}
+
+macro_rules! deserialize_number {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+                visitor.$visit(self.parse_leaf()?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for &'de XmlNode {
+    type Error = WechatError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        match self {
+            XmlNode::Text(text) | XmlNode::Value(text) => visitor.visit_borrowed_str(text.trim()),
+            XmlNode::Element(children) => visitor.visit_map(ElementAccess::new(children)),
+        }
+    }
+
+    deserialize_number! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        match self.leaf()? {
+            "1" | "true" => visitor.visit_bool(true),
+            "0" | "false" | "" => visitor.visit_bool(false),
+            text => Err(de::Error::custom(format!("无法解析[{}]为bool", text))),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        match self {
+            XmlNode::Text(text) | XmlNode::Value(text) => visitor.visit_borrowed_str(text.trim()),
+            XmlNode::Element(_) => visitor.visit_string(self.text().trim().to_string()),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        if self.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        match self {
+            XmlNode::Element(children) => visitor.visit_seq(de::value::SeqDeserializer::new(
+                children.iter().map(|(_, node)| node),
+            )),
+            _ if self.is_empty() => visitor.visit_seq(de::value::SeqDeserializer::new(
+                std::iter::empty::<&XmlNode>(),
+            )),
+            _ => visitor.visit_seq(de::value::SeqDeserializer::new(std::iter::once(self))),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        match self {
+            XmlNode::Element(children) => visitor.visit_map(ElementAccess::new(children)),
+            _ if self.is_empty() => visitor.visit_map(ElementAccess::new(&[])),
+            _ => Err(de::Error::custom("应为子元素, 实际为文本")),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        match self {
+            XmlNode::Element(children) if children.len() == 1 => {
+                visitor.visit_enum(EnumAccess(&children[0].0, &children[0].1))
+            }
+            XmlNode::Element(_) => Err(de::Error::custom("枚举应只有一个子元素")),
+            _ => visitor.visit_enum(self.leaf()?.into_deserializer()),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, WechatError> {
+        visitor.visit_unit()
+    }
+}
+
+impl<'de> IntoDeserializer<'de, WechatError> for &'de XmlNode {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// 子元素作为Map
+struct ElementAccess<'de> {
+    children: std::slice::Iter<'de, (String, XmlNode)>,
+    value: Option<&'de XmlNode>,
+}
+
+impl<'de> ElementAccess<'de> {
+    fn new(children: &'de [(String, XmlNode)]) -> Self {
+        ElementAccess {
+            children: children.iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for ElementAccess<'de> {
+    type Error = WechatError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, WechatError> {
+        match self.children.next() {
+            Some((name, node)) => {
+                self.value = Some(node);
+                let name: de::value::BorrowedStrDeserializer<WechatError> =
+                    de::value::BorrowedStrDeserializer::new(name);
+                seed.deserialize(name).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, WechatError> {
+        let node = self
+            .value
+            .take()
+            .ok_or_else(|| WechatError::ParseError("缺少元素值".into()))?;
+        seed.deserialize(node)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.children.len())
+    }
+}
+
+/// 只有一个子元素的节点作为枚举, 元素名为枚举值
+struct EnumAccess<'de>(&'de str, &'de XmlNode);
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'de> {
+    type Error = WechatError;
+    type Variant = &'de XmlNode;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, &'de XmlNode), WechatError> {
+        let name: de::value::BorrowedStrDeserializer<WechatError> =
+            de::value::BorrowedStrDeserializer::new(self.0);
+        Ok((seed.deserialize(name)?, self.1))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &'de XmlNode {
+    type Error = WechatError;
+
+    fn unit_variant(self) -> Result<(), WechatError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, WechatError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, WechatError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+// ======================== 字段解析 ========================
+
+/// 按FromStr解析文本, 同时接受数字
+struct TextVisitor<T>(std::marker::PhantomData<T>);
+
+impl<T> TextVisitor<T>
+where
+    T: std::str::FromStr,
+    T::Err: Display,
+{
+    fn parse<E: de::Error>(text: &str) -> Result<Option<T>, E> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        text.parse()
+            .map(Some)
+            .map_err(|e| E::custom(format!("无法解析[{}]: {}", text, e)))
+    }
+}
+
+impl<'de, T> Visitor<'de> for TextVisitor<T>
+where
+    T: std::str::FromStr,
+    T::Err: Display,
+{
+    type Value = Option<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("text")
+    }
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Option<T>, E> {
+        Self::parse(&v.to_string())
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Option<T>, E> {
+        Self::parse(&v.to_string())
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Option<T>, E> {
+        Self::parse(&v.to_string())
+    }
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Option<T>, E> {
+        Self::parse(&v.to_string())
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Option<T>, E> {
+        Self::parse(v)
+    }
+    fn visit_unit<E: de::Error>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+    fn visit_none<E: de::Error>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Option<T>, D::Error> {
+        d.deserialize_any(self)
+    }
+}
+
+/// 数字等通过FromStr解析的字段, 为空时返回默认值
+///
+/// `#[serde(rename = "CreateTime", deserialize_with = "xml::number")]`
+pub fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr + Default,
+    T::Err: Display,
+{
+    let value = deserializer.deserialize_any(TextVisitor(std::marker::PhantomData))?;
+    Ok(value.unwrap_or_default())
+}
+
+/// 可选字段, 不存在或为空时为None, 需同时指定`default`
+///
+/// `#[serde(rename = "MenuID", default, deserialize_with = "xml::optional")]`
+pub fn optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: Display,
+{
+    deserializer.deserialize_any(TextVisitor(std::marker::PhantomData))
+}
+
+/// `<item>`数组
+///
+/// `#[serde(rename = "PicList", default, deserialize_with = "xml::items")]`
+pub fn items<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct ItemsVisitor<T>(std::marker::PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for ItemsVisitor<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("item list")
+        }
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<T>, E> {
+            if v.trim().is_empty() {
+                Ok(Vec::new())
+            } else {
+                Err(E::custom("应为子元素, 实际为文本"))
+            }
+        }
+        fn visit_unit<E: de::Error>(self) -> Result<Vec<T>, E> {
+            Ok(Vec::new())
+        }
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+            let mut items = Vec::new();
+            while let Some(item) = seq.next_element()? {
+                items.push(item);
+            }
+            Ok(items)
+        }
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Vec<T>, A::Error> {
+            let mut items = Vec::new();
+            while let Some((_, item)) = map.next_entry::<de::IgnoredAny, T>()? {
+                items.push(item);
+            }
+            Ok(items)
+        }
+    }
+
+    deserializer.deserialize_any(ItemsVisitor(std::marker::PhantomData))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Item {
+        #[serde(rename = "PicMd5Sum")]
+        pic_md5_sum: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Msg {
+        #[serde(rename = "ToUserName")]
+        to_user_name: String,
+        #[serde(rename = "CreateTime")]
+        create_time: u64,
+        #[serde(rename = "Scale")]
+        scale: f64,
+        #[serde(rename = "Label", skip_serializing_if = "Option::is_none", default)]
+        label: Option<String>,
+        #[serde(rename = "PicList")]
+        pic_list: Vec<Item>,
+    }
+
+    #[test]
+    fn test_serde() -> Result<(), WechatError> {
+        let msg = Msg {
+            to_user_name: "to]]>user".into(),
+            create_time: 1348831860,
+            scale: 1.5,
+            label: None,
+            pic_list: vec![
+                Item {
+                    pic_md5_sum: "1b5f7c23b5bf75682a53e7b6d163e185".into(),
+                },
+                Item {
+                    pic_md5_sum: "<&>".into(),
+                },
+            ],
+        };
+        let xml = to_string(&msg)?;
+        assert_eq!(
+            r#"<xml><ToUserName><![CDATA[to]]]]><![CDATA[>user]]></ToUserName><CreateTime>1348831860</CreateTime><Scale>1.5</Scale><PicList><item><PicMd5Sum><![CDATA[1b5f7c23b5bf75682a53e7b6d163e185]]></PicMd5Sum></item><item><PicMd5Sum><![CDATA[<&>]]></PicMd5Sum></item></PicList></xml>"#,
+            xml
+        );
+        assert_eq!(msg, from_str::<Msg>(&xml)?);
+        Ok(())
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Head {
+        #[serde(rename = "CreateTime", deserialize_with = "number")]
+        create_time: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "MsgType")]
+    enum Tagged {
+        #[serde(rename = "pics")]
+        Pics {
+            #[serde(flatten)]
+            head: Head,
+            #[serde(rename = "Scale", deserialize_with = "number")]
+            scale: f64,
+            #[serde(
+                rename = "Label",
+                default,
+                deserialize_with = "optional",
+                skip_serializing_if = "Option::is_none"
+            )]
+            label: Option<String>,
+            #[serde(rename = "PicList", default, deserialize_with = "items")]
+            pic_list: Vec<Item>,
+        },
+    }
+
+    #[test]
+    fn test_serde_tagged() -> Result<(), WechatError> {
+        let msg: Tagged = from_str(
+            r#"<xml>
+  <MsgType><![CDATA[pics]]></MsgType>
+  <CreateTime> 1348831860 </CreateTime>
+  <Scale>1.5</Scale>
+  <Label><![CDATA[]]></Label>
+  <PicList><item><PicMd5Sum><![CDATA[md5]]></PicMd5Sum></item></PicList>
+</xml>"#,
+        )?;
+        let expected = Tagged::Pics {
+            head: Head {
+                create_time: 1348831860,
+            },
+            scale: 1.5,
+            label: None,
+            pic_list: vec![Item {
+                pic_md5_sum: "md5".into(),
+            }],
+        };
+        assert_eq!(expected, msg);
+        assert_eq!(msg, from_str::<Tagged>(&to_string(&msg)?)?);
+        // 空数组
+        let msg: Tagged =
+            from_str("<xml><MsgType>pics</MsgType><CreateTime>1</CreateTime><Scale>2</Scale><PicList></PicList></xml>")?;
+        match msg {
+            Tagged::Pics { pic_list, .. } => assert!(pic_list.is_empty()),
+        }
+        // 数字无效
+        assert!(
+            from_str::<Tagged>("<xml><MsgType>pics</MsgType><CreateTime>x</CreateTime></xml>")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<(), WechatError> {
+        let node = parse(
+            r#"<xml>
+  <ToUserName><![CDATA[toUser]]></ToUserName>
+  <CreateTime>1348831860</CreateTime>
+  <ScanCodeInfo><ScanType><![CDATA[qrcode]]></ScanType>
+  <ScanResult><![CDATA[1]]></ScanResult>
+  </ScanCodeInfo>
+</xml>"#,
+        )?;
+        assert_eq!(
+            Some(&XmlNode::Text("toUser".into())),
+            node.child("ToUserName")
+        );
+        assert_eq!(
+            vec![&XmlNode::Text("qrcode".into())],
+            node.find("ScanCodeInfo/ScanType")
+        );
+        assert_eq!("qrcode1", node.child("ScanCodeInfo").unwrap().text());
+        assert!(parse("<root></root>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_markup() -> Result<(), WechatError> {
+        let node = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- comment -->
+<xml a="1" b='>'>
+  <Content>1 &lt; 2 &amp;&#x4e2d;&#25991;<!-- c --><![CDATA[ <b>&amp;</b>]]></Content>
+  <Empty/>
+  <Empty2></Empty2>
+</xml>"#,
+        )?;
+        assert_eq!(
+            XmlNode::Element(vec![
+                (
+                    "Content".into(),
+                    XmlNode::Text("1 < 2 &中文 <b>&amp;</b>".into())
+                ),
+                ("Empty".into(), XmlNode::Text("".into())),
+                ("Empty2".into(), XmlNode::Text("".into())),
+            ]),
+            node
+        );
+        assert_eq!(XmlNode::Element(vec![]), parse("<xml/>")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for xml in &[
+            "",
+            "<xml>",
+            "<xml><a></b></xml>",
+            "<xml><a>&unknown;</a></xml>",
+            "<xml><a><![CDATA[x</a></xml>",
+            "<xml></xml><xml></xml>",
+            "text",
+            r#"<!DOCTYPE xml [<!ENTITY e "x">]><xml><a>&e;</a></xml>"#,
+            "<xml><a><!DOCTYPE b></a></xml>",
+        ] {
+            assert!(parse(xml).is_err(), "{}", xml);
+        }
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let xml = format!(
+            "<xml>{}{}</xml>",
+            "<a>".repeat(100_000),
+            "</a>".repeat(100_000)
+        );
+        assert!(parse(&xml).is_err());
+        let xml = format!("<xml>{}{}</xml>", "<a>".repeat(31), "</a>".repeat(31));
+        assert!(parse(&xml).is_ok());
+    }
+}
@@ -0,0 +1,220 @@
+#![cfg(feature = "actix")]
+use actix_web::http::StatusCode;
+use actix_web::{test, App};
+use async_trait::async_trait;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use wechat4rs::crypt::get_signature;
+use wechat4rs::token_provider::memory::MemoryTokenProvider;
+use wechat4rs::{
+    CallbackContext, CallbackMessage, ConstSaasResolver, HandlerOutcome, ReplyDeadline,
+    SaasContext, Wechat, WechatBuilder, WechatCallBackHandler, WechatConfig, WechatError,
+    WechatToken,
+};
+
+const TOKEN: &str = "testtoken123456";
+const TIMESTAMP: i64 = 1592558813;
+const BODY: &str = "<xml><ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>\
+<FromUserName><![CDATA[openid]]></FromUserName><CreateTime>1592558813</CreateTime>\
+<MsgType><![CDATA[text]]></MsgType><Content><![CDATA[hi]]></Content><MsgId>1</MsgId></xml>";
+
+struct Hello;
+
+#[async_trait]
+impl WechatCallBackHandler for Hello {
+    async fn handler_callback(
+        &self,
+        _wechat: &Wechat,
+        _context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> Result<HandlerOutcome, WechatError> {
+        Ok(HandlerOutcome::reply(message.reply().text("hello")?))
+    }
+}
+
+/// 处理较慢, 超过被动回复时限
+struct SlowHello;
+
+#[async_trait]
+impl WechatCallBackHandler for SlowHello {
+    async fn handler_callback(
+        &self,
+        _wechat: &Wechat,
+        _context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> Result<HandlerOutcome, WechatError> {
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        Ok(HandlerOutcome::reply(message.reply().text("slow hello")?))
+    }
+}
+
+/// 明文模式的公众号
+fn builder() -> WechatBuilder {
+    let config = WechatConfig::new(None, "wx11853b05910e1b6b".into(), "".into());
+    Wechat::builder(
+        Box::new(ConstSaasResolver::new(config)),
+        Box::new(MemoryTokenProvider::new()),
+    )
+    .max_body_size(1024)
+}
+
+/// 缓存token后共享
+async fn shared(builder: WechatBuilder) -> Arc<Wechat> {
+    let wechat = builder.build_shared();
+    let token = WechatToken::new_relative(TOKEN.into(), 7200);
+    wechat
+        .token_provider
+        .set_token(&wechat, &SaasContext::new(1), Some(token))
+        .await
+        .unwrap();
+    wechat
+}
+
+async fn get_wechat() -> Arc<Wechat> {
+    shared(builder().callback(Box::new(Hello))).await
+}
+
+/// 模拟微信接口, 返回(接口地址, 收到的请求行及请求体)
+fn mock_api() -> (reqwest::Url, mpsc::UnboundedReceiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                let header = line.to_ascii_lowercase();
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = r#"{"errcode":0,"errmsg":"ok"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            if sender
+                .send((request_line.trim_end().to_string(), body))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    (reqwest::Url::parse(&url).unwrap(), receiver)
+}
+
+fn query() -> String {
+    let signature = get_signature(&TOKEN.into(), TIMESTAMP, "nonce", "").unwrap();
+    format!(
+        "signature={}&timestamp={}&nonce=nonce",
+        signature, TIMESTAMP
+    )
+}
+
+#[actix_rt::test]
+async fn test_callback_scope() {
+    let mut app = test::init_service(App::new().service(wechat4rs::actix::callback_scope(
+        "/wechat",
+        get_wechat().await,
+    )))
+    .await;
+
+    // 接入验证
+    let uri = format!("/wechat/1/?{}&echostr=echo", query());
+    let request = test::TestRequest::get().uri(&uri).to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("echo", test::read_body(response).await);
+
+    // 消息回调
+    let uri = format!("/wechat/1/?{}", query());
+    let request = test::TestRequest::post()
+        .uri(&uri)
+        .header("content-type", "text/xml")
+        .set_payload(BODY)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "application/xml; charset=utf-8",
+        response.headers().get("content-type").unwrap()
+    );
+    let body = test::read_body(response).await;
+    assert!(String::from_utf8_lossy(&body).contains("<Content><![CDATA[hello]]></Content>"));
+
+    // 签名错误
+    let request = test::TestRequest::post()
+        .uri("/wechat/1/?signature=x&timestamp=1592558813&nonce=nonce")
+        .set_payload(BODY)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert!(response.status().is_client_error());
+
+    // 消息体超过限制
+    let request = test::TestRequest::post()
+        .uri(&uri)
+        .set_payload(vec![b'a'; 2048])
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+    let request = test::TestRequest::put().uri(&uri).to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+
+    // saas_id无效
+    let request = test::TestRequest::get().uri("/wechat/abc/").to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}
+
+#[actix_rt::test]
+async fn test_reply_deadline() {
+    let (api_base, mut requests) = mock_api();
+    let builder = builder()
+        .callback(Box::new(SlowHello))
+        .reply_deadline(ReplyDeadline::new(Duration::from_millis(20)))
+        .api_base(api_base);
+    let wechat = shared(builder).await;
+    let mut app =
+        test::init_service(App::new().service(wechat4rs::actix::callback_scope("/wechat", wechat)))
+            .await;
+
+    // 超时先返回success
+    let uri = format!("/wechat/1/?{}", query());
+    let request = test::TestRequest::post()
+        .uri(&uri)
+        .set_payload(BODY)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("success", test::read_body(response).await);
+
+    // 处理完成后通过客服消息发送回复
+    let (request_line, body) = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+        .await
+        .expect("客服消息未发送")
+        .unwrap();
+    assert!(request_line.starts_with(&format!(
+        "POST /cgi-bin/message/custom/send?access_token={}",
+        TOKEN
+    )));
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!("openid", body["touser"]);
+    assert_eq!("slow hello", body["text"]["content"]);
+}
@@ -0,0 +1,132 @@
+#![cfg(feature = "hyper")]
+use async_trait::async_trait;
+use hyper::service::Service;
+use hyper::{body, Body, Method, Request, StatusCode};
+use std::sync::Arc;
+use wechat4rs::crypt::get_signature;
+use wechat4rs::hyper::CallbackService;
+use wechat4rs::service::error_response;
+use wechat4rs::token_provider::memory::MemoryTokenProvider;
+use wechat4rs::{
+    CallbackContext, CallbackMessage, ConstSaasResolver, HandlerOutcome, SaasContext, Wechat,
+    WechatCallBackHandler, WechatConfig, WechatEncryptError, WechatError, WechatToken,
+};
+
+const TOKEN: &str = "testtoken123456";
+const TIMESTAMP: i64 = 1592558813;
+const BODY: &str = "<xml><ToUserName><![CDATA[gh_f91a47ec7ff6]]></ToUserName>\
+<FromUserName><![CDATA[openid]]></FromUserName><CreateTime>1592558813</CreateTime>\
+<MsgType><![CDATA[text]]></MsgType><Content><![CDATA[hi]]></Content><MsgId>1</MsgId></xml>";
+
+struct Hello;
+
+#[async_trait]
+impl WechatCallBackHandler for Hello {
+    async fn handler_callback(
+        &self,
+        _wechat: &Wechat,
+        _context: &CallbackContext,
+        message: &CallbackMessage,
+    ) -> Result<HandlerOutcome, WechatError> {
+        Ok(HandlerOutcome::reply(message.reply().text("hello")?))
+    }
+}
+
+/// 明文模式的公众号, 已缓存token
+async fn get_wechat() -> Arc<Wechat> {
+    let config = WechatConfig::new(None, "wx11853b05910e1b6b".into(), "".into());
+    let wechat = Wechat::builder(
+        Box::new(ConstSaasResolver::new(config)),
+        Box::new(MemoryTokenProvider::new()),
+    )
+    .callback(Box::new(Hello))
+    .max_body_size(1024)
+    .build_shared();
+    let token = WechatToken::new_relative(TOKEN.into(), 7200);
+    wechat
+        .token_provider
+        .set_token(&wechat, &SaasContext::new(1), Some(token))
+        .await
+        .unwrap();
+    wechat
+}
+
+fn query() -> String {
+    let signature = get_signature(&TOKEN.into(), TIMESTAMP, "nonce", "").unwrap();
+    format!(
+        "signature={}&timestamp={}&nonce=nonce",
+        signature, TIMESTAMP
+    )
+}
+
+fn request<T: Into<Body>>(method: Method, uri: &str, body: T) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(body.into())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_callback_service() {
+    let mut service = CallbackService::new(get_wechat().await, "/wechat/");
+
+    // 接入验证
+    let uri = format!("/wechat/1/?{}&echostr=echo", query());
+    let response = service.call(request(Method::GET, &uri, "")).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("echo", body::to_bytes(response.into_body()).await.unwrap());
+
+    // 消息回调
+    let uri = format!("/wechat/1/?{}", query());
+    let response = service.handle(request(Method::POST, &uri, BODY)).await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "application/xml; charset=utf-8",
+        response.headers()["content-type"]
+    );
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("<Content><![CDATA[hello]]></Content>"));
+
+    // 签名错误
+    let uri = "/wechat/1/?signature=x&timestamp=1592558813&nonce=nonce";
+    let response = service.handle(request(Method::POST, uri, BODY)).await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    // 缺少参数或参数无效
+    for uri in &[
+        "/wechat/1",
+        "/wechat/1/?timestamp=1592558813&nonce=nonce",
+        "/wechat/1/?signature=x&timestamp=abc&nonce=nonce",
+    ] {
+        for method in &[Method::GET, Method::POST] {
+            let response = service.handle(request(method.clone(), uri, BODY)).await;
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        }
+    }
+
+    // 消息体超过限制
+    let uri = format!("/wechat/1/?{}", query());
+    let response = service
+        .handle(request(Method::POST, &uri, vec![b'a'; 2048]))
+        .await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+    let response = service.handle(request(Method::PUT, &uri, "")).await;
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+
+    // saas_id无效或路径不匹配
+    for uri in &["/wechat/abc/", "/other/1/", "/wechat/1/x"] {
+        let response = service.handle(request(Method::GET, uri, "")).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+}
+
+#[test]
+fn test_error_response() {
+    let error: WechatError = WechatEncryptError::storage("redis://secret").into();
+    let response = error_response(&error).map(Body::from);
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    let error = WechatError::ParseError("bad xml".into());
+    assert_eq!(StatusCode::BAD_REQUEST, error_response(&error).status());
+}